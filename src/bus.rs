@@ -0,0 +1,36 @@
+/// Address space as seen by the CPU. `Mmu` is the console's bus; tests can
+/// run the CPU against anything else that implements it.
+///
+/// The CPU calls `tick` once per M-cycle: right before each memory access,
+/// and after the instruction for its remaining internal cycles.
+pub trait Bus {
+    fn rb(&mut self, a: u16) -> u8;
+
+    fn wb(&mut self, a: u16, v: u8);
+
+    /// Advances everything but the CPU by one M-cycle (4 T-cycles).
+    fn tick(&mut self);
+
+    /// Reads a byte without side effects, for debugging tools.
+    fn peek(&self, a: u16) -> u8;
+
+    /// Reads an instruction byte. `flag` is `CDL_CODE` for opcodes and
+    /// `CDL_OPERAND` for their operands.
+    fn fetch(&mut self, a: u16, _flag: u8) -> u8 {
+        self.rb(a)
+    }
+
+    /// Interrupts both requested in IF and enabled in IE.
+    fn pending_interrupts(&self) -> u8;
+
+    /// Clears interrupt `n` in IF when the CPU dispatches it.
+    fn acknowledge_interrupt(&mut self, n: u32);
+
+    /// ROM bank mapped at 0x4000-0x7FFF, for naming code addresses.
+    fn rom_bank(&self) -> usize {
+        1
+    }
+
+    /// Called with the address of each instruction before it executes.
+    fn on_instruction(&mut self, _pc: u16) {}
+}
@@ -0,0 +1,128 @@
+use std::fs;
+use std::path::Path;
+
+use log::info;
+
+use crate::rom::{ROM_BANK_END, ROM_BANK_SIZE};
+use crate::Result;
+
+pub const CDL_CODE: u8 = 0x01;
+pub const CDL_OPERAND: u8 = 0x02;
+pub const CDL_DATA: u8 = 0x04;
+pub const CDL_DMA: u8 = 0x08;
+
+/// Code/data log: one byte of `CDL_*` flags per ROM byte, across all banks.
+pub struct CodeDataLog {
+    flags: Vec<u8>,
+}
+
+/// Offset in the ROM image of a CPU address, with `bank` mapped at
+/// 0x4000-0x7FFF.
+pub fn rom_offset(address: u16, bank: usize) -> Option<usize> {
+    match address {
+        0x0000..=0x3FFF => Some(address as usize),
+        0x4000..=ROM_BANK_END => Some(bank * ROM_BANK_SIZE + (address as usize - 0x4000)),
+        _ => None,
+    }
+}
+
+impl CodeDataLog {
+    pub fn new(rom_size: usize) -> CodeDataLog {
+        CodeDataLog {
+            flags: vec![0; rom_size],
+        }
+    }
+
+    /// Loads a log saved by a previous session, or starts an empty one.
+    pub fn load_or_new(path: &Path, rom_size: usize) -> Result<CodeDataLog> {
+        let mut log = CodeDataLog::new(rom_size);
+        if path.exists() {
+            let flags = fs::read(path)?;
+            info!("Loaded code/data log from {}", path.display());
+            for (dst, src) in log.flags.iter_mut().zip(flags) {
+                *dst = src;
+            }
+        }
+        Ok(log)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, &self.flags)?;
+        Ok(())
+    }
+
+    pub fn mark(&mut self, offset: usize, flag: u8) {
+        if let Some(flags) = self.flags.get_mut(offset) {
+            *flags |= flag;
+        }
+    }
+
+    pub fn flags(&self, offset: usize) -> u8 {
+        self.flags.get(offset).copied().unwrap_or(0)
+    }
+
+    /// True if the byte was seen but never executed as an opcode.
+    pub fn is_data(&self, offset: usize) -> bool {
+        let flags = self.flags(offset);
+        flags != 0 && flags & CDL_CODE == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+
+    #[test]
+    fn offsets() {
+        assert_eq!(rom_offset(0x0150, 5), Some(0x0150));
+        assert_eq!(rom_offset(0x4000, 1), Some(0x4000));
+        assert_eq!(rom_offset(0x4123, 3), Some(0xC123));
+        assert_eq!(rom_offset(0xC000, 1), None);
+    }
+
+    #[test]
+    fn flags_accumulate() {
+        let mut log = CodeDataLog::new(0x8000);
+        log.mark(0x10, CDL_DATA);
+        assert!(log.is_data(0x10));
+        log.mark(0x10, CDL_CODE);
+        assert_eq!(log.flags(0x10), CDL_CODE | CDL_DATA);
+        assert!(!log.is_data(0x10));
+        assert!(!log.is_data(0x11));
+        log.mark(0x8000, CDL_CODE);
+        assert_eq!(log.flags(0x8000), 0);
+    }
+
+    #[test]
+    fn fetches_and_reads() {
+        let mut rom = vec![0; 0x8000];
+        // 0100: LD A,(0200); JR -2
+        rom[0x100..0x105].copy_from_slice(&[0xFA, 0x00, 0x02, 0x18, 0xFE]);
+        let cdl_path = std::env::temp_dir().join("rekop-gbc-cdl.cdl");
+        let _ = std::fs::remove_file(&cdl_path);
+        let cdl_path = cdl_path.to_str().unwrap();
+
+        let mut device = Device::from_rom_bytes(rom);
+        device.enable_cdl(cdl_path).unwrap();
+        device.do_cycle();
+        device.do_cycle();
+        device.cpu.bus.wb(0xFF46, 0x03);
+        let cdl = device.cpu.bus.cdl.as_ref().unwrap();
+        let flags: Vec<u8> = (0x100..0x106).map(|o| cdl.flags(o)).collect();
+        assert_eq!(
+            flags,
+            [CDL_CODE, CDL_OPERAND, CDL_OPERAND, CDL_CODE, CDL_OPERAND, 0]
+        );
+        assert_eq!(cdl.flags(0x200), CDL_DATA);
+        assert!(cdl.is_data(0x200));
+        assert_eq!(cdl.flags(0x39F), CDL_DMA);
+        assert_eq!(cdl.flags(0x3A0), 0);
+
+        // A later session continues the saved log.
+        device.save_cdl(cdl_path).unwrap();
+        let log = CodeDataLog::load_or_new(Path::new(cdl_path), 0x8000).unwrap();
+        assert_eq!(log.flags(0x100), CDL_CODE);
+        assert_eq!(log.flags(0x200), CDL_DATA);
+    }
+}
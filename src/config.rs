@@ -0,0 +1,494 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use winit::keyboard::KeyCode;
+
+use crate::gamepad::GamepadConfig;
+use crate::input::Macro;
+use crate::joypad::KeypadKey;
+use crate::{EmulatorError, Result};
+
+const HEADER: &str = "\
+# rekop-gbc configuration. Keys are winit key codes, named after their
+# position on a US keyboard: \"KeyA\", \"Digit1\", \"ArrowUp\", \"Enter\", \"F1\"...
+# Controller buttons are named after their position (\"South\", \"East\",
+# \"DPadUp\", \"Start\"...); [gamepad.controllers.<name>] tables replace
+# [gamepad.buttons] for controllers whose name contains <name>.
+# Macros are steps of buttons held for a number of frames:
+# [[macros]]
+# keys = [\"F1\"]
+# steps = [{ buttons = [\"Down\"], frames = 2 }, { buttons = [\"Down\", \"A\"], frames = 1 }]
+# Changes are picked up while the emulator runs.
+
+";
+
+/// How often `ConfigWatcher` looks at the file.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Hotkey {
+    Pause,
+    FrameAdvance,
+    FastForward,
+    ToggleFastForward,
+    SlowMotion,
+    SaveState,
+    LoadState,
+    Screenshot,
+    Reset,
+    Rewind,
+    RecordMacro,
+    PlayMacro,
+}
+
+/// Key bound twice: the binding dropped, then the one kept.
+type Conflict = (KeyCode, Binding, Binding);
+
+/// What a key is bound to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Binding {
+    Button(KeypadKey),
+    Hotkey(Hotkey),
+    /// Button with its turbo rate.
+    Turbo(KeypadKey, u32),
+    /// Index in `Config::macros`.
+    Macro(usize),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub keys: Keys,
+    pub hotkeys: Hotkeys,
+    pub turbo: Vec<Turbo>,
+    // Left out when empty so that `[[macros]]` can be appended.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub macros: Vec<MacroKeys>,
+    pub speed: Speed,
+    pub rewind: Rewind,
+    pub video: Video,
+    pub gamepad: GamepadConfig,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            keys: Keys::default(),
+            hotkeys: Hotkeys::default(),
+            turbo: vec![
+                Turbo {
+                    button: KeypadKey::A,
+                    keys: vec![KeyCode::KeyS],
+                    rate: 2,
+                },
+                Turbo {
+                    button: KeypadKey::B,
+                    keys: vec![KeyCode::KeyA],
+                    rate: 2,
+                },
+            ],
+            macros: Vec::new(),
+            speed: Speed::default(),
+            rewind: Rewind::default(),
+            video: Video::default(),
+            gamepad: GamepadConfig::default(),
+        }
+    }
+}
+
+/// Keys pressing `button` on and off while held.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Turbo {
+    pub button: KeypadKey,
+    pub keys: Vec<KeyCode>,
+    /// Frames pressed, then as many released.
+    pub rate: u32,
+}
+
+/// Emulation speeds relative to the Game Boy.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(default)]
+pub struct Speed {
+    /// Speed while fast-forwarding, or 0 to run as fast as possible.
+    pub fast_forward: f64,
+    pub slow_motion: f64,
+}
+
+impl Default for Speed {
+    fn default() -> Speed {
+        Speed {
+            fast_forward: 4.0,
+            slow_motion: 0.5,
+        }
+    }
+}
+
+/// Snapshots kept to rewind through while the rewind key is held.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(default)]
+pub struct Rewind {
+    /// Memory for snapshots, in MiB, or 0 to disable rewinding.
+    pub memory: usize,
+    /// Frames between two snapshots.
+    pub interval: u32,
+    /// Speed of rewinding relative to the Game Boy.
+    pub speed: f64,
+}
+
+impl Default for Rewind {
+    fn default() -> Rewind {
+        Rewind {
+            memory: 64,
+            interval: 5,
+            speed: 1.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct Video {
+    /// Draw without OpenGL, for machines without GPU acceleration. Read
+    /// when the window opens.
+    pub software: bool,
+}
+
+/// Keys playing a macro.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MacroKeys {
+    pub keys: Vec<KeyCode>,
+    pub steps: Macro,
+}
+
+/// Keys for each Game Boy button.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Keys {
+    pub right: Vec<KeyCode>,
+    pub left: Vec<KeyCode>,
+    pub up: Vec<KeyCode>,
+    pub down: Vec<KeyCode>,
+    pub a: Vec<KeyCode>,
+    pub b: Vec<KeyCode>,
+    pub select: Vec<KeyCode>,
+    pub start: Vec<KeyCode>,
+}
+
+impl Default for Keys {
+    fn default() -> Keys {
+        Keys {
+            right: vec![KeyCode::ArrowRight],
+            left: vec![KeyCode::ArrowLeft],
+            up: vec![KeyCode::ArrowUp],
+            down: vec![KeyCode::ArrowDown],
+            a: vec![KeyCode::KeyX],
+            b: vec![KeyCode::KeyZ],
+            select: vec![KeyCode::Backspace, KeyCode::ShiftRight],
+            start: vec![KeyCode::Enter],
+        }
+    }
+}
+
+/// Keys for emulator functions. Fast-forward lasts while its key is held,
+/// while the toggles switch it and slow motion on and off.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Hotkeys {
+    pub pause: Vec<KeyCode>,
+    /// Runs a single frame, pausing first if needed.
+    pub frame_advance: Vec<KeyCode>,
+    pub fast_forward: Vec<KeyCode>,
+    pub toggle_fast_forward: Vec<KeyCode>,
+    pub slow_motion: Vec<KeyCode>,
+    pub save_state: Vec<KeyCode>,
+    pub load_state: Vec<KeyCode>,
+    pub screenshot: Vec<KeyCode>,
+    pub reset: Vec<KeyCode>,
+    /// Goes back in time while held.
+    pub rewind: Vec<KeyCode>,
+    /// Starts recording a macro, then stops and keeps it for `play_macro`.
+    pub record_macro: Vec<KeyCode>,
+    pub play_macro: Vec<KeyCode>,
+}
+
+impl Default for Hotkeys {
+    fn default() -> Hotkeys {
+        Hotkeys {
+            pause: vec![KeyCode::KeyP],
+            frame_advance: vec![KeyCode::KeyN],
+            fast_forward: vec![KeyCode::Tab],
+            toggle_fast_forward: vec![KeyCode::Backquote],
+            slow_motion: vec![KeyCode::KeyL],
+            save_state: vec![KeyCode::F5],
+            load_state: vec![KeyCode::F7],
+            screenshot: vec![KeyCode::F12],
+            reset: vec![KeyCode::KeyR],
+            rewind: vec![KeyCode::KeyQ],
+            record_macro: vec![KeyCode::F9],
+            play_macro: vec![KeyCode::F10],
+        }
+    }
+}
+
+impl Config {
+    /// `rekop-gbc/config.toml` in the user's configuration directory.
+    pub fn default_path() -> PathBuf {
+        let dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .unwrap_or_default();
+        dir.join("rekop-gbc").join("config.toml")
+    }
+
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| EmulatorError::Config(format!("{}: {e}", path.display())))
+    }
+
+    /// Loads `path`, writing the default configuration there first if it
+    /// does not exist.
+    pub fn load_or_create(path: &Path) -> Result<Config> {
+        if !path.exists() {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let text = toml::to_string(&Config::default()).expect("default config serializes");
+            fs::write(path, format!("{HEADER}{text}"))?;
+            info!("Wrote default configuration to {}", path.display());
+        }
+        Config::load(path)
+    }
+
+    pub fn bindings(&self) -> HashMap<KeyCode, Binding> {
+        let (bindings, conflicts) = self.resolve_bindings();
+        for (key, previous, binding) in conflicts {
+            warn!("{key:?} is bound to both {previous:?} and {binding:?}");
+        }
+        bindings
+    }
+
+    /// Binding of each key, the last one listed winning, and the bindings
+    /// it replaced.
+    fn resolve_bindings(&self) -> (HashMap<KeyCode, Binding>, Vec<Conflict>) {
+        let k = &self.keys;
+        let h = &self.hotkeys;
+        let buttons = [
+            (&k.right, KeypadKey::Right),
+            (&k.left, KeypadKey::Left),
+            (&k.up, KeypadKey::Up),
+            (&k.down, KeypadKey::Down),
+            (&k.a, KeypadKey::A),
+            (&k.b, KeypadKey::B),
+            (&k.select, KeypadKey::Select),
+            (&k.start, KeypadKey::Start),
+        ]
+        .map(|(keys, button)| (keys, Binding::Button(button)));
+        let hotkeys = [
+            (&h.pause, Hotkey::Pause),
+            (&h.frame_advance, Hotkey::FrameAdvance),
+            (&h.fast_forward, Hotkey::FastForward),
+            (&h.toggle_fast_forward, Hotkey::ToggleFastForward),
+            (&h.slow_motion, Hotkey::SlowMotion),
+            (&h.save_state, Hotkey::SaveState),
+            (&h.load_state, Hotkey::LoadState),
+            (&h.screenshot, Hotkey::Screenshot),
+            (&h.reset, Hotkey::Reset),
+            (&h.rewind, Hotkey::Rewind),
+            (&h.record_macro, Hotkey::RecordMacro),
+            (&h.play_macro, Hotkey::PlayMacro),
+        ]
+        .map(|(keys, hotkey)| (keys, Binding::Hotkey(hotkey)));
+        let turbo = self
+            .turbo
+            .iter()
+            .map(|t| (&t.keys, Binding::Turbo(t.button, t.rate)));
+        let macros = self
+            .macros
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (&m.keys, Binding::Macro(i)));
+
+        let mut bindings = HashMap::new();
+        let mut conflicts = Vec::new();
+        let all = buttons
+            .into_iter()
+            .chain(hotkeys)
+            .chain(turbo)
+            .chain(macros);
+        for (keys, binding) in all {
+            for &key in keys {
+                if let Some(previous) = bindings.insert(key, binding) {
+                    conflicts.push((key, previous, binding));
+                }
+            }
+        }
+        (bindings, conflicts)
+    }
+}
+
+/// Reloads a configuration file when it changes on disk.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> ConfigWatcher {
+        ConfigWatcher {
+            path: path.to_path_buf(),
+            modified: modified(path),
+            checked: Instant::now(),
+        }
+    }
+
+    /// Returns the new configuration if the file changed since the last
+    /// call. A file that fails to load is reported and skipped.
+    pub fn poll(&mut self) -> Option<Config> {
+        if self.checked.elapsed() < POLL_INTERVAL {
+            return None;
+        }
+        self.checked = Instant::now();
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        match Config::load(&self.path) {
+            Ok(config) => {
+                info!("Reloaded configuration from {}", self.path.display());
+                Some(config)
+            }
+            Err(e) => {
+                warn!("Keeping the previous configuration: {e}");
+                None
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::MacroStep;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rekop-gbc-{name}"));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("config.toml")
+    }
+
+    #[test]
+    fn default_bindings() {
+        let (bindings, conflicts) = Config::default().resolve_bindings();
+        assert!(conflicts.is_empty());
+        assert_eq!(bindings[&KeyCode::KeyX], Binding::Button(KeypadKey::A));
+        assert_eq!(
+            bindings[&KeyCode::ShiftRight],
+            Binding::Button(KeypadKey::Select)
+        );
+        assert_eq!(bindings[&KeyCode::F5], Binding::Hotkey(Hotkey::SaveState));
+        assert_eq!(bindings[&KeyCode::KeyS], Binding::Turbo(KeypadKey::A, 2));
+        assert_eq!(bindings[&KeyCode::KeyA], Binding::Turbo(KeypadKey::B, 2));
+    }
+
+    #[test]
+    fn macros_and_duplicates() {
+        let mut config = Config::default();
+        let steps = vec![MacroStep {
+            buttons: vec![KeypadKey::Down],
+            frames: 2,
+        }];
+        for key in [KeyCode::F1, KeyCode::F2] {
+            config.macros.push(MacroKeys {
+                keys: vec![key],
+                steps: steps.clone(),
+            });
+        }
+        config.hotkeys.pause.push(KeyCode::KeyX);
+        config.turbo[0].keys.push(KeyCode::F2);
+
+        let (bindings, conflicts) = config.resolve_bindings();
+        assert_eq!(bindings[&KeyCode::F1], Binding::Macro(0));
+        assert_eq!(bindings[&KeyCode::F2], Binding::Macro(1));
+        assert_eq!(bindings[&KeyCode::KeyX], Binding::Hotkey(Hotkey::Pause));
+        assert_eq!(
+            conflicts,
+            [
+                (
+                    KeyCode::KeyX,
+                    Binding::Button(KeypadKey::A),
+                    Binding::Hotkey(Hotkey::Pause)
+                ),
+                (
+                    KeyCode::F2,
+                    Binding::Turbo(KeypadKey::A, 2),
+                    Binding::Macro(1)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn default_file_round_trips() {
+        let path = temp_path("config-default");
+        let config = Config::load_or_create(&path).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with(HEADER));
+        assert_eq!(config.bindings(), Config::default().bindings());
+        assert_eq!(config.speed, Speed::default());
+        assert_eq!(config.rewind, Rewind::default());
+
+        // Macros can be appended as the header shows, and an existing file
+        // is left alone.
+        let appended = "\n[[macros]]\nkeys = [\"F1\"]\n\
+            steps = [{ buttons = [\"Down\"], frames = 2 }, { buttons = [\"Down\", \"A\"], frames = 1 }]\n";
+        fs::write(&path, format!("{text}{appended}")).unwrap();
+        let config = Config::load_or_create(&path).unwrap();
+        assert_eq!(config.macros.len(), 1);
+        assert_eq!(config.macros[0].steps.len(), 2);
+        assert_eq!(config.bindings()[&KeyCode::F1], Binding::Macro(0));
+    }
+
+    #[test]
+    fn watcher_reloads_changes() {
+        let path = temp_path("config-watch");
+        Config::load_or_create(&path).unwrap();
+        let mut watcher = ConfigWatcher::new(&path);
+        let write = |text: &str, later: u64| {
+            fs::write(&path, text).unwrap();
+            let modified = SystemTime::now() + Duration::from_secs(later);
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        let poll = |watcher: &mut ConfigWatcher| {
+            watcher.checked -= POLL_INTERVAL;
+            watcher.poll()
+        };
+        assert!(poll(&mut watcher).is_none());
+
+        write("[speed]\nfast_forward = 2.0\n", 10);
+        // Not looked at again before the interval.
+        assert!(watcher.poll().is_none());
+        let config = poll(&mut watcher).unwrap();
+        assert_eq!(config.speed.fast_forward, 2.0);
+        assert!(poll(&mut watcher).is_none());
+
+        write("[speed\n", 20);
+        assert!(poll(&mut watcher).is_none());
+        assert!(poll(&mut watcher).is_none());
+    }
+}
@@ -1,47 +1,185 @@
+use crate::bus::Bus;
+use crate::cdl::{CDL_CODE, CDL_OPERAND};
 use crate::mmu::Mmu;
+use crate::profiler::Profiler;
 use crate::registers::CpuFlag::{C, H, N, Z};
 use crate::registers::Registers;
 use crate::rom::Rom;
+use crate::state::{StateReader, StateWriter};
+use crate::trace::Tracer;
+use crate::Result;
 
-pub struct CPU {
-    regs: Registers,
-    pub mmu: Mmu,
+/// SM83 core. It is generic over its bus so that tests and other systems
+/// can supply their own; the console's `Mmu` is statically dispatched.
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU<B: Bus = Mmu> {
+    pub regs: Registers,
+    pub bus: B,
     halted: bool,
     halt_bug: bool,
     ime: bool,
     setdi: u32,
     setei: u32,
+    /// M-cycles ticked so far by the current instruction.
+    ticks: u32,
+    pub tracer: Option<Tracer>,
+    pub profiler: Option<Box<Profiler>>,
 }
 
 impl CPU {
     pub fn new(rom: Rom) -> CPU {
+        CPU::with_bus(Mmu::new(rom))
+    }
+
+    pub fn reset(&mut self) {
+        self.regs = Registers::new();
+        self.halted = false;
+        self.halt_bug = false;
+        self.ime = true;
+        self.setdi = 0;
+        self.setei = 0;
+        self.bus.reset();
+    }
+
+    /// Saves the CPU and the rest of the machine. Only valid between
+    /// instructions.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        let r = &self.regs;
+        for v in [r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l] {
+            w.u8(v);
+        }
+        w.u16(r.sp);
+        w.u16(r.pc);
+        w.bool(self.halted);
+        w.bool(self.halt_bug);
+        w.bool(self.ime);
+        w.u32(self.setdi);
+        w.u32(self.setei);
+        self.bus.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        let regs = &mut self.regs;
+        for v in [
+            &mut regs.a,
+            &mut regs.f,
+            &mut regs.b,
+            &mut regs.c,
+            &mut regs.d,
+            &mut regs.e,
+            &mut regs.h,
+            &mut regs.l,
+        ] {
+            *v = r.u8()?;
+        }
+        regs.sp = r.u16()?;
+        regs.pc = r.u16()?;
+        self.halted = r.bool()?;
+        self.halt_bug = r.bool()?;
+        self.ime = r.bool()?;
+        self.setdi = r.u32()?;
+        self.setei = r.u32()?;
+        self.bus.load_state(r)
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    pub fn with_bus(bus: B) -> CPU<B> {
         CPU {
             regs: Registers::new(),
-            mmu: Mmu::new(rom),
+            bus,
             halted: false,
             halt_bug: false,
             ime: true,
             setdi: 0,
             setei: 0,
+            ticks: 0,
+            tracer: None,
+            profiler: None,
         }
     }
 
+    /// Executes one instruction or interrupt dispatch and returns its
+    /// duration in M-cycles.
+    pub fn step(&mut self) -> u32 {
+        self.cycle()
+    }
+
+    /// Same as `step`, in T-cycles.
     pub fn do_cycle(&mut self) -> u32 {
-        let ticks = self.cycle() * 4;
-        self.mmu.do_cycle(ticks)
+        self.cycle() * 4
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn set_ime(&mut self, ime: bool) {
+        self.ime = ime;
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
     }
 
     fn cycle(&mut self) -> u32 {
+        let pc = self.regs.pc;
+        let sp = self.regs.sp;
+        self.bus.on_instruction(pc);
         self.updateime();
-        match self.handle_interrupts() {
-            0 => {}
-            n => return n,
-        };
-
-        if self.halted {
+        let interrupt = self.handle_interrupts();
+        let n = if interrupt != 0 {
+            interrupt
+        } else if self.halted {
             1
         } else {
+            self.trace();
             self.call()
+        };
+        // Internal cycles that did not access memory.
+        while self.ticks < n {
+            self.tick();
+        }
+        let n = std::mem::take(&mut self.ticks);
+        self.profile(pc, sp, n, interrupt != 0);
+        n
+    }
+
+    /// Feeds the shadow call stack: calls and RSTs are recognized by their
+    /// opcode together with the 2-byte push, returns by the 2-byte pop.
+    fn profile(&mut self, pc: u16, sp: u16, cycles: u32, interrupt: bool) {
+        let Some(profiler) = &mut self.profiler else {
+            return;
+        };
+        let bank = self.bus.rom_bank();
+        profiler.record(pc, bank, cycles as u64 * 4);
+
+        let new_sp = self.regs.sp;
+        if interrupt {
+            profiler.enter(self.regs.pc, bank, pc, new_sp, true);
+            return;
+        }
+        match self.bus.peek(pc) {
+            0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC | 0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7
+            | 0xFF
+                if new_sp == sp.wrapping_sub(2) =>
+            {
+                let return_address = self.bus.peek(new_sp) as u16
+                    | (self.bus.peek(new_sp.wrapping_add(1)) as u16) << 8;
+                profiler.enter(self.regs.pc, bank, return_address, new_sp, false);
+            }
+            0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9 if new_sp == sp.wrapping_add(2) => {
+                profiler.leave(new_sp);
+            }
+            _ => {}
+        }
+    }
+
+    fn trace(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            if !tracer.log(&self.regs, &self.bus) {
+                self.tracer = None;
+            }
         }
     }
 
@@ -64,30 +202,59 @@ impl CPU {
         };
     }
 
+    /// Advances the rest of the system by one M-cycle.
+    fn tick(&mut self) {
+        self.ticks += 1;
+        self.bus.tick();
+    }
+
+    fn read(&mut self, a: u16) -> u8 {
+        self.tick();
+        self.bus.rb(a)
+    }
+
+    fn write(&mut self, a: u16, v: u8) {
+        self.tick();
+        self.bus.wb(a, v);
+    }
+
+    fn write_word(&mut self, a: u16, v: u16) {
+        self.write(a, (v & 0xFF) as u8);
+        self.write(a.wrapping_add(1), (v >> 8) as u8);
+    }
+
+    fn fetch_opcode(&mut self) -> u8 {
+        self.tick();
+        let byte = self.bus.fetch(self.regs.pc, CDL_CODE);
+        self.regs.pc = self.regs.pc.wrapping_add(1);
+        byte
+    }
+
     fn fetch_byte(&mut self) -> u8 {
-        let byte = self.mmu.rb(self.regs.pc);
+        self.tick();
+        let byte = self.bus.fetch(self.regs.pc, CDL_OPERAND);
         self.regs.pc = self.regs.pc.wrapping_add(1);
         byte
     }
 
     fn fetchword(&mut self) -> u16 {
-        let w = self.mmu.rw(self.regs.pc);
-        self.regs.pc += 2;
-        w
+        let lo = self.fetch_byte() as u16;
+        let hi = self.fetch_byte() as u16;
+        (hi << 8) | lo
     }
 
     fn handle_interrupts(&mut self) -> u32 {
-        if self.ime == false && self.halted == false {
+        if !self.ime && !self.halted {
             return 0;
         }
 
-        let triggered = self.mmu.inte & self.mmu.intf & 0x1F;
+        let triggered = self.bus.pending_interrupts();
         if triggered == 0 {
             return 0;
         }
 
         self.halted = false;
-        if self.ime == false {
+        if !self.ime {
             return 0;
         }
         self.ime = false;
@@ -96,7 +263,7 @@ impl CPU {
         if n >= 5 {
             panic!("Invalid interrupt triggered");
         }
-        self.mmu.intf &= !(1 << n);
+        self.bus.acknowledge_interrupt(n);
         let pc = self.regs.pc;
         self.pushstack(pc);
         self.regs.pc = 0x0040 | ((n as u16) << 3);
@@ -104,43 +271,26 @@ impl CPU {
         4
     }
 
+    /// Pushes the high byte first, like the hardware does.
     fn pushstack(&mut self, value: u16) {
-        self.regs.sp = self.regs.sp.wrapping_sub(2);
-        self.mmu.ww(self.regs.sp, value);
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write(self.regs.sp, (value >> 8) as u8);
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write(self.regs.sp, (value & 0xFF) as u8);
     }
 
     fn popstack(&mut self) -> u16 {
-        let res = self.mmu.rw(self.regs.sp);
-        self.regs.sp += 2;
+        let lo = self.read(self.regs.sp) as u16;
+        let hi = self.read(self.regs.sp.wrapping_add(1)) as u16;
+        let res = (hi << 8) | lo;
+        self.regs.sp = self.regs.sp.wrapping_add(2);
         res
     }
 
-    pub fn step(&mut self) -> usize {
-        self.handle_interrupts();
-        let opcode = self.fetch_byte();
-
-        match opcode {
-            0x78 => {
-                self.regs.a = self.regs.b;
-                4
-            }
-            0x79 => {
-                self.regs.a = self.regs.c;
-                4
-            }
-            _ => 0xFF,
-        }
-    }
-
     fn call(&mut self) -> u32 {
-        let opcode = self.fetch_byte();
+        let opcode = self.fetch_opcode();
 
         match opcode {
-            0xFF => {
-                let v = self.fetch_byte();
-                self.alu_cp(v);
-                2
-            }
             0x00 => 1,
             0x01 => {
                 let v = self.fetchword();
@@ -148,7 +298,7 @@ impl CPU {
                 3
             }
             0x02 => {
-                self.mmu.wb(self.regs.bc(), self.regs.a);
+                self.write(self.regs.bc(), self.regs.a);
                 2
             }
             0x03 => {
@@ -174,7 +324,7 @@ impl CPU {
             }
             0x08 => {
                 let a = self.fetchword();
-                self.mmu.ww(a, self.regs.sp);
+                self.write_word(a, self.regs.sp);
                 5
             }
             0x09 => {
@@ -182,7 +332,7 @@ impl CPU {
                 2
             }
             0x0A => {
-                self.regs.a = self.mmu.rb(self.regs.bc());
+                self.regs.a = self.read(self.regs.bc());
                 2
             }
             0x0B => {
@@ -207,7 +357,7 @@ impl CPU {
                 1
             }
             // 0x10 => {
-            //     self.mmu.switch_speed();
+            //     self.bus.switch_speed();
             //     1
             // } // STOP
             0x11 => {
@@ -216,7 +366,7 @@ impl CPU {
                 3
             }
             0x12 => {
-                self.mmu.wb(self.regs.de(), self.regs.a);
+                self.write(self.regs.de(), self.regs.a);
                 2
             }
             0x13 => {
@@ -249,7 +399,7 @@ impl CPU {
                 2
             }
             0x1A => {
-                self.regs.a = self.mmu.rb(self.regs.de());
+                self.regs.a = self.read(self.regs.de());
                 2
             }
             0x1B => {
@@ -278,7 +428,7 @@ impl CPU {
                     self.cpu_jr();
                     3
                 } else {
-                    self.regs.pc += 1;
+                    self.fetch_byte();
                     2
                 }
             }
@@ -288,7 +438,8 @@ impl CPU {
                 3
             }
             0x22 => {
-                self.mmu.wb(self.regs.hli(), self.regs.a);
+                let a = self.regs.hli();
+                self.write(a, self.regs.a);
                 2
             }
             0x23 => {
@@ -317,7 +468,7 @@ impl CPU {
                     self.cpu_jr();
                     3
                 } else {
-                    self.regs.pc += 1;
+                    self.fetch_byte();
                     2
                 }
             }
@@ -327,7 +478,8 @@ impl CPU {
                 2
             }
             0x2A => {
-                self.regs.a = self.mmu.rb(self.regs.hli());
+                let a = self.regs.hli();
+                self.regs.a = self.read(a);
                 2
             }
             0x2B => {
@@ -358,7 +510,7 @@ impl CPU {
                     self.cpu_jr();
                     3
                 } else {
-                    self.regs.pc += 1;
+                    self.fetch_byte();
                     2
                 }
             }
@@ -367,7 +519,8 @@ impl CPU {
                 3
             }
             0x32 => {
-                self.mmu.wb(self.regs.hld(), self.regs.a);
+                let a = self.regs.hld();
+                self.write(a, self.regs.a);
                 2
             }
             0x33 => {
@@ -376,21 +529,21 @@ impl CPU {
             }
             0x34 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a);
+                let v = self.read(a);
                 let v2 = self.alu_inc(v);
-                self.mmu.wb(a, v2);
+                self.write(a, v2);
                 3
             }
             0x35 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a);
+                let v = self.read(a);
                 let v2 = self.alu_dec(v);
-                self.mmu.wb(a, v2);
+                self.write(a, v2);
                 3
             }
             0x36 => {
                 let v = self.fetch_byte();
-                self.mmu.wb(self.regs.hl(), v);
+                self.write(self.regs.hl(), v);
                 3
             }
             0x37 => {
@@ -404,7 +557,7 @@ impl CPU {
                     self.cpu_jr();
                     3
                 } else {
-                    self.regs.pc += 1;
+                    self.fetch_byte();
                     2
                 }
             }
@@ -413,7 +566,8 @@ impl CPU {
                 2
             }
             0x3A => {
-                self.regs.a = self.mmu.rb(self.regs.hld());
+                let a = self.regs.hld();
+                self.regs.a = self.read(a);
                 2
             }
             0x3B => {
@@ -461,7 +615,7 @@ impl CPU {
                 1
             }
             0x46 => {
-                self.regs.b = self.mmu.rb(self.regs.hl());
+                self.regs.b = self.read(self.regs.hl());
                 2
             }
             0x47 => {
@@ -490,7 +644,7 @@ impl CPU {
                 1
             }
             0x4E => {
-                self.regs.c = self.mmu.rb(self.regs.hl());
+                self.regs.c = self.read(self.regs.hl());
                 2
             }
             0x4F => {
@@ -519,7 +673,7 @@ impl CPU {
                 1
             }
             0x56 => {
-                self.regs.d = self.mmu.rb(self.regs.hl());
+                self.regs.d = self.read(self.regs.hl());
                 2
             }
             0x57 => {
@@ -548,7 +702,7 @@ impl CPU {
                 1
             }
             0x5E => {
-                self.regs.e = self.mmu.rb(self.regs.hl());
+                self.regs.e = self.read(self.regs.hl());
                 2
             }
             0x5F => {
@@ -577,7 +731,7 @@ impl CPU {
                 1
             }
             0x66 => {
-                self.regs.h = self.mmu.rb(self.regs.hl());
+                self.regs.h = self.read(self.regs.hl());
                 2
             }
             0x67 => {
@@ -606,7 +760,7 @@ impl CPU {
             }
             0x6D => 1,
             0x6E => {
-                self.regs.l = self.mmu.rb(self.regs.hl());
+                self.regs.l = self.read(self.regs.hl());
                 2
             }
             0x6F => {
@@ -614,36 +768,36 @@ impl CPU {
                 1
             }
             0x70 => {
-                self.mmu.wb(self.regs.hl(), self.regs.b);
+                self.write(self.regs.hl(), self.regs.b);
                 2
             }
             0x71 => {
-                self.mmu.wb(self.regs.hl(), self.regs.c);
+                self.write(self.regs.hl(), self.regs.c);
                 2
             }
             0x72 => {
-                self.mmu.wb(self.regs.hl(), self.regs.d);
+                self.write(self.regs.hl(), self.regs.d);
                 2
             }
             0x73 => {
-                self.mmu.wb(self.regs.hl(), self.regs.e);
+                self.write(self.regs.hl(), self.regs.e);
                 2
             }
             0x74 => {
-                self.mmu.wb(self.regs.hl(), self.regs.h);
+                self.write(self.regs.hl(), self.regs.h);
                 2
             }
             0x75 => {
-                self.mmu.wb(self.regs.hl(), self.regs.l);
+                self.write(self.regs.hl(), self.regs.l);
                 2
             }
             0x76 => {
                 self.halted = true;
-                self.halt_bug = self.mmu.intf & self.mmu.inte & 0x1F != 0;
+                self.halt_bug = self.bus.pending_interrupts() != 0;
                 1
             }
             0x77 => {
-                self.mmu.wb(self.regs.hl(), self.regs.a);
+                self.write(self.regs.hl(), self.regs.a);
                 2
             }
             0x78 => {
@@ -671,7 +825,7 @@ impl CPU {
                 1
             }
             0x7E => {
-                self.regs.a = self.mmu.rb(self.regs.hl());
+                self.regs.a = self.read(self.regs.hl());
                 2
             }
             0x7F => 1,
@@ -700,7 +854,7 @@ impl CPU {
                 1
             }
             0x86 => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_add(v, false);
                 2
             }
@@ -733,7 +887,7 @@ impl CPU {
                 1
             }
             0x8E => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_add(v, true);
                 2
             }
@@ -766,7 +920,7 @@ impl CPU {
                 1
             }
             0x96 => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_sub(v, false);
                 2
             }
@@ -799,7 +953,7 @@ impl CPU {
                 1
             }
             0x9E => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_sub(v, true);
                 2
             }
@@ -832,7 +986,7 @@ impl CPU {
                 1
             }
             0xA6 => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_and(v);
                 2
             }
@@ -865,7 +1019,7 @@ impl CPU {
                 1
             }
             0xAE => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_xor(v);
                 2
             }
@@ -898,7 +1052,7 @@ impl CPU {
                 1
             }
             0xB6 => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_or(v);
                 2
             }
@@ -931,7 +1085,7 @@ impl CPU {
                 1
             }
             0xBE => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_cp(v);
                 2
             }
@@ -957,7 +1111,7 @@ impl CPU {
                     self.regs.pc = self.fetchword();
                     4
                 } else {
-                    self.regs.pc += 2;
+                    self.fetchword();
                     3
                 }
             }
@@ -967,11 +1121,12 @@ impl CPU {
             }
             0xC4 => {
                 if !self.regs.get_flag(Z) {
-                    self.pushstack(self.regs.pc + 2);
-                    self.regs.pc = self.fetchword();
+                    let a = self.fetchword();
+                    self.pushstack(self.regs.pc);
+                    self.regs.pc = a;
                     6
                 } else {
-                    self.regs.pc += 2;
+                    self.fetchword();
                     3
                 }
             }
@@ -1006,24 +1161,26 @@ impl CPU {
                     self.regs.pc = self.fetchword();
                     4
                 } else {
-                    self.regs.pc += 2;
+                    self.fetchword();
                     3
                 }
             }
             0xCB => self.call_cb(),
             0xCC => {
                 if self.regs.get_flag(Z) {
-                    self.pushstack(self.regs.pc + 2);
-                    self.regs.pc = self.fetchword();
+                    let a = self.fetchword();
+                    self.pushstack(self.regs.pc);
+                    self.regs.pc = a;
                     6
                 } else {
-                    self.regs.pc += 2;
+                    self.fetchword();
                     3
                 }
             }
             0xCD => {
-                self.pushstack(self.regs.pc + 2);
-                self.regs.pc = self.fetchword();
+                let a = self.fetchword();
+                self.pushstack(self.regs.pc);
+                self.regs.pc = a;
                 6
             }
             0xCE => {
@@ -1054,17 +1211,18 @@ impl CPU {
                     self.regs.pc = self.fetchword();
                     4
                 } else {
-                    self.regs.pc += 2;
+                    self.fetchword();
                     3
                 }
             }
             0xD4 => {
                 if !self.regs.get_flag(C) {
-                    self.pushstack(self.regs.pc + 2);
-                    self.regs.pc = self.fetchword();
+                    let a = self.fetchword();
+                    self.pushstack(self.regs.pc);
+                    self.regs.pc = a;
                     6
                 } else {
-                    self.regs.pc += 2;
+                    self.fetchword();
                     3
                 }
             }
@@ -1100,17 +1258,18 @@ impl CPU {
                     self.regs.pc = self.fetchword();
                     4
                 } else {
-                    self.regs.pc += 2;
+                    self.fetchword();
                     3
                 }
             }
             0xDC => {
                 if self.regs.get_flag(C) {
-                    self.pushstack(self.regs.pc + 2);
-                    self.regs.pc = self.fetchword();
+                    let a = self.fetchword();
+                    self.pushstack(self.regs.pc);
+                    self.regs.pc = a;
                     6
                 } else {
-                    self.regs.pc += 2;
+                    self.fetchword();
                     3
                 }
             }
@@ -1126,7 +1285,7 @@ impl CPU {
             }
             0xE0 => {
                 let a = 0xFF00 | self.fetch_byte() as u16;
-                self.mmu.wb(a, self.regs.a);
+                self.write(a, self.regs.a);
                 3
             }
             0xE1 => {
@@ -1135,7 +1294,7 @@ impl CPU {
                 3
             }
             0xE2 => {
-                self.mmu.wb(0xFF00 | self.regs.c as u16, self.regs.a);
+                self.write(0xFF00 | self.regs.c as u16, self.regs.a);
                 2
             }
             0xE5 => {
@@ -1162,7 +1321,7 @@ impl CPU {
             }
             0xEA => {
                 let a = self.fetchword();
-                self.mmu.wb(a, self.regs.a);
+                self.write(a, self.regs.a);
                 4
             }
             0xEE => {
@@ -1177,7 +1336,7 @@ impl CPU {
             }
             0xF0 => {
                 let a = 0xFF00 | self.fetch_byte() as u16;
-                self.regs.a = self.mmu.rb(a);
+                self.regs.a = self.read(a);
                 3
             }
             0xF1 => {
@@ -1186,7 +1345,7 @@ impl CPU {
                 3
             }
             0xF2 => {
-                self.regs.a = self.mmu.rb(0xFF00 | self.regs.c as u16);
+                self.regs.a = self.read(0xFF00 | self.regs.c as u16);
                 2
             }
             0xF3 => {
@@ -1218,7 +1377,7 @@ impl CPU {
             }
             0xFA => {
                 let a = self.fetchword();
-                self.regs.a = self.mmu.rb(a);
+                self.regs.a = self.read(a);
                 4
             }
             0xFB => {
@@ -1230,6 +1389,11 @@ impl CPU {
                 self.alu_cp(v);
                 2
             }
+            0xFF => {
+                self.pushstack(self.regs.pc);
+                self.regs.pc = 0x38;
+                4
+            }
             other => panic!("Instruction {:2X} is not implemented", other),
         }
     }
@@ -1263,9 +1427,9 @@ impl CPU {
             }
             0x06 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a);
+                let v = self.read(a);
                 let v2 = self.alu_rlc(v);
-                self.mmu.wb(a, v2);
+                self.write(a, v2);
                 4
             }
             0x07 => {
@@ -1298,9 +1462,9 @@ impl CPU {
             }
             0x0E => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a);
+                let v = self.read(a);
                 let v2 = self.alu_rrc(v);
-                self.mmu.wb(a, v2);
+                self.write(a, v2);
                 4
             }
             0x0F => {
@@ -1333,9 +1497,9 @@ impl CPU {
             }
             0x16 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a);
+                let v = self.read(a);
                 let v2 = self.alu_rl(v);
-                self.mmu.wb(a, v2);
+                self.write(a, v2);
                 4
             }
             0x17 => {
@@ -1368,9 +1532,9 @@ impl CPU {
             }
             0x1E => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a);
+                let v = self.read(a);
                 let v2 = self.alu_rr(v);
-                self.mmu.wb(a, v2);
+                self.write(a, v2);
                 4
             }
             0x1F => {
@@ -1403,9 +1567,9 @@ impl CPU {
             }
             0x26 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a);
+                let v = self.read(a);
                 let v2 = self.alu_sla(v);
-                self.mmu.wb(a, v2);
+                self.write(a, v2);
                 4
             }
             0x27 => {
@@ -1438,9 +1602,9 @@ impl CPU {
             }
             0x2E => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a);
+                let v = self.read(a);
                 let v2 = self.alu_sra(v);
-                self.mmu.wb(a, v2);
+                self.write(a, v2);
                 4
             }
             0x2F => {
@@ -1473,9 +1637,9 @@ impl CPU {
             }
             0x36 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a);
+                let v = self.read(a);
                 let v2 = self.alu_swap(v);
-                self.mmu.wb(a, v2);
+                self.write(a, v2);
                 4
             }
             0x37 => {
@@ -1508,9 +1672,9 @@ impl CPU {
             }
             0x3E => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a);
+                let v = self.read(a);
                 let v2 = self.alu_srl(v);
-                self.mmu.wb(a, v2);
+                self.write(a, v2);
                 4
             }
             0x3F => {
@@ -1542,7 +1706,7 @@ impl CPU {
                 2
             }
             0x46 => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_bit(v, 0);
                 3
             }
@@ -1575,7 +1739,7 @@ impl CPU {
                 2
             }
             0x4E => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_bit(v, 1);
                 3
             }
@@ -1608,7 +1772,7 @@ impl CPU {
                 2
             }
             0x56 => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_bit(v, 2);
                 3
             }
@@ -1641,7 +1805,7 @@ impl CPU {
                 2
             }
             0x5E => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_bit(v, 3);
                 3
             }
@@ -1674,7 +1838,7 @@ impl CPU {
                 2
             }
             0x66 => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_bit(v, 4);
                 3
             }
@@ -1707,7 +1871,7 @@ impl CPU {
                 2
             }
             0x6E => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_bit(v, 5);
                 3
             }
@@ -1740,7 +1904,7 @@ impl CPU {
                 2
             }
             0x76 => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_bit(v, 6);
                 3
             }
@@ -1773,7 +1937,7 @@ impl CPU {
                 2
             }
             0x7E => {
-                let v = self.mmu.rb(self.regs.hl());
+                let v = self.read(self.regs.hl());
                 self.alu_bit(v, 7);
                 3
             }
@@ -1782,547 +1946,547 @@ impl CPU {
                 2
             }
             0x80 => {
-                self.regs.b = self.regs.b & !(1 << 0);
+                self.regs.b &= !(1 << 0);
                 2
             }
             0x81 => {
-                self.regs.c = self.regs.c & !(1 << 0);
+                self.regs.c &= !(1 << 0);
                 2
             }
             0x82 => {
-                self.regs.d = self.regs.d & !(1 << 0);
+                self.regs.d &= !(1 << 0);
                 2
             }
             0x83 => {
-                self.regs.e = self.regs.e & !(1 << 0);
+                self.regs.e &= !(1 << 0);
                 2
             }
             0x84 => {
-                self.regs.h = self.regs.h & !(1 << 0);
+                self.regs.h &= !(1 << 0);
                 2
             }
             0x85 => {
-                self.regs.l = self.regs.l & !(1 << 0);
+                self.regs.l &= !(1 << 0);
                 2
             }
             0x86 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) & !(1 << 0);
-                self.mmu.wb(a, v);
+                let v = self.read(a) & !(1 << 0);
+                self.write(a, v);
                 4
             }
             0x87 => {
-                self.regs.a = self.regs.a & !(1 << 0);
+                self.regs.a &= !(1 << 0);
                 2
             }
             0x88 => {
-                self.regs.b = self.regs.b & !(1 << 1);
+                self.regs.b &= !(1 << 1);
                 2
             }
             0x89 => {
-                self.regs.c = self.regs.c & !(1 << 1);
+                self.regs.c &= !(1 << 1);
                 2
             }
             0x8A => {
-                self.regs.d = self.regs.d & !(1 << 1);
+                self.regs.d &= !(1 << 1);
                 2
             }
             0x8B => {
-                self.regs.e = self.regs.e & !(1 << 1);
+                self.regs.e &= !(1 << 1);
                 2
             }
             0x8C => {
-                self.regs.h = self.regs.h & !(1 << 1);
+                self.regs.h &= !(1 << 1);
                 2
             }
             0x8D => {
-                self.regs.l = self.regs.l & !(1 << 1);
+                self.regs.l &= !(1 << 1);
                 2
             }
             0x8E => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) & !(1 << 1);
-                self.mmu.wb(a, v);
+                let v = self.read(a) & !(1 << 1);
+                self.write(a, v);
                 4
             }
             0x8F => {
-                self.regs.a = self.regs.a & !(1 << 1);
+                self.regs.a &= !(1 << 1);
                 2
             }
             0x90 => {
-                self.regs.b = self.regs.b & !(1 << 2);
+                self.regs.b &= !(1 << 2);
                 2
             }
             0x91 => {
-                self.regs.c = self.regs.c & !(1 << 2);
+                self.regs.c &= !(1 << 2);
                 2
             }
             0x92 => {
-                self.regs.d = self.regs.d & !(1 << 2);
+                self.regs.d &= !(1 << 2);
                 2
             }
             0x93 => {
-                self.regs.e = self.regs.e & !(1 << 2);
+                self.regs.e &= !(1 << 2);
                 2
             }
             0x94 => {
-                self.regs.h = self.regs.h & !(1 << 2);
+                self.regs.h &= !(1 << 2);
                 2
             }
             0x95 => {
-                self.regs.l = self.regs.l & !(1 << 2);
+                self.regs.l &= !(1 << 2);
                 2
             }
             0x96 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) & !(1 << 2);
-                self.mmu.wb(a, v);
+                let v = self.read(a) & !(1 << 2);
+                self.write(a, v);
                 4
             }
             0x97 => {
-                self.regs.a = self.regs.a & !(1 << 2);
+                self.regs.a &= !(1 << 2);
                 2
             }
             0x98 => {
-                self.regs.b = self.regs.b & !(1 << 3);
+                self.regs.b &= !(1 << 3);
                 2
             }
             0x99 => {
-                self.regs.c = self.regs.c & !(1 << 3);
+                self.regs.c &= !(1 << 3);
                 2
             }
             0x9A => {
-                self.regs.d = self.regs.d & !(1 << 3);
+                self.regs.d &= !(1 << 3);
                 2
             }
             0x9B => {
-                self.regs.e = self.regs.e & !(1 << 3);
+                self.regs.e &= !(1 << 3);
                 2
             }
             0x9C => {
-                self.regs.h = self.regs.h & !(1 << 3);
+                self.regs.h &= !(1 << 3);
                 2
             }
             0x9D => {
-                self.regs.l = self.regs.l & !(1 << 3);
+                self.regs.l &= !(1 << 3);
                 2
             }
             0x9E => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) & !(1 << 3);
-                self.mmu.wb(a, v);
+                let v = self.read(a) & !(1 << 3);
+                self.write(a, v);
                 4
             }
             0x9F => {
-                self.regs.a = self.regs.a & !(1 << 3);
+                self.regs.a &= !(1 << 3);
                 2
             }
             0xA0 => {
-                self.regs.b = self.regs.b & !(1 << 4);
+                self.regs.b &= !(1 << 4);
                 2
             }
             0xA1 => {
-                self.regs.c = self.regs.c & !(1 << 4);
+                self.regs.c &= !(1 << 4);
                 2
             }
             0xA2 => {
-                self.regs.d = self.regs.d & !(1 << 4);
+                self.regs.d &= !(1 << 4);
                 2
             }
             0xA3 => {
-                self.regs.e = self.regs.e & !(1 << 4);
+                self.regs.e &= !(1 << 4);
                 2
             }
             0xA4 => {
-                self.regs.h = self.regs.h & !(1 << 4);
+                self.regs.h &= !(1 << 4);
                 2
             }
             0xA5 => {
-                self.regs.l = self.regs.l & !(1 << 4);
+                self.regs.l &= !(1 << 4);
                 2
             }
             0xA6 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) & !(1 << 4);
-                self.mmu.wb(a, v);
+                let v = self.read(a) & !(1 << 4);
+                self.write(a, v);
                 4
             }
             0xA7 => {
-                self.regs.a = self.regs.a & !(1 << 4);
+                self.regs.a &= !(1 << 4);
                 2
             }
             0xA8 => {
-                self.regs.b = self.regs.b & !(1 << 5);
+                self.regs.b &= !(1 << 5);
                 2
             }
             0xA9 => {
-                self.regs.c = self.regs.c & !(1 << 5);
+                self.regs.c &= !(1 << 5);
                 2
             }
             0xAA => {
-                self.regs.d = self.regs.d & !(1 << 5);
+                self.regs.d &= !(1 << 5);
                 2
             }
             0xAB => {
-                self.regs.e = self.regs.e & !(1 << 5);
+                self.regs.e &= !(1 << 5);
                 2
             }
             0xAC => {
-                self.regs.h = self.regs.h & !(1 << 5);
+                self.regs.h &= !(1 << 5);
                 2
             }
             0xAD => {
-                self.regs.l = self.regs.l & !(1 << 5);
+                self.regs.l &= !(1 << 5);
                 2
             }
             0xAE => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) & !(1 << 5);
-                self.mmu.wb(a, v);
+                let v = self.read(a) & !(1 << 5);
+                self.write(a, v);
                 4
             }
             0xAF => {
-                self.regs.a = self.regs.a & !(1 << 5);
+                self.regs.a &= !(1 << 5);
                 2
             }
             0xB0 => {
-                self.regs.b = self.regs.b & !(1 << 6);
+                self.regs.b &= !(1 << 6);
                 2
             }
             0xB1 => {
-                self.regs.c = self.regs.c & !(1 << 6);
+                self.regs.c &= !(1 << 6);
                 2
             }
             0xB2 => {
-                self.regs.d = self.regs.d & !(1 << 6);
+                self.regs.d &= !(1 << 6);
                 2
             }
             0xB3 => {
-                self.regs.e = self.regs.e & !(1 << 6);
+                self.regs.e &= !(1 << 6);
                 2
             }
             0xB4 => {
-                self.regs.h = self.regs.h & !(1 << 6);
+                self.regs.h &= !(1 << 6);
                 2
             }
             0xB5 => {
-                self.regs.l = self.regs.l & !(1 << 6);
+                self.regs.l &= !(1 << 6);
                 2
             }
             0xB6 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) & !(1 << 6);
-                self.mmu.wb(a, v);
+                let v = self.read(a) & !(1 << 6);
+                self.write(a, v);
                 4
             }
             0xB7 => {
-                self.regs.a = self.regs.a & !(1 << 6);
+                self.regs.a &= !(1 << 6);
                 2
             }
             0xB8 => {
-                self.regs.b = self.regs.b & !(1 << 7);
+                self.regs.b &= !(1 << 7);
                 2
             }
             0xB9 => {
-                self.regs.c = self.regs.c & !(1 << 7);
+                self.regs.c &= !(1 << 7);
                 2
             }
             0xBA => {
-                self.regs.d = self.regs.d & !(1 << 7);
+                self.regs.d &= !(1 << 7);
                 2
             }
             0xBB => {
-                self.regs.e = self.regs.e & !(1 << 7);
+                self.regs.e &= !(1 << 7);
                 2
             }
             0xBC => {
-                self.regs.h = self.regs.h & !(1 << 7);
+                self.regs.h &= !(1 << 7);
                 2
             }
             0xBD => {
-                self.regs.l = self.regs.l & !(1 << 7);
+                self.regs.l &= !(1 << 7);
                 2
             }
             0xBE => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) & !(1 << 7);
-                self.mmu.wb(a, v);
+                let v = self.read(a) & !(1 << 7);
+                self.write(a, v);
                 4
             }
             0xBF => {
-                self.regs.a = self.regs.a & !(1 << 7);
+                self.regs.a &= !(1 << 7);
                 2
             }
             0xC0 => {
-                self.regs.b = self.regs.b | (1 << 0);
+                self.regs.b |= 1 << 0;
                 2
             }
             0xC1 => {
-                self.regs.c = self.regs.c | (1 << 0);
+                self.regs.c |= 1 << 0;
                 2
             }
             0xC2 => {
-                self.regs.d = self.regs.d | (1 << 0);
+                self.regs.d |= 1 << 0;
                 2
             }
             0xC3 => {
-                self.regs.e = self.regs.e | (1 << 0);
+                self.regs.e |= 1 << 0;
                 2
             }
             0xC4 => {
-                self.regs.h = self.regs.h | (1 << 0);
+                self.regs.h |= 1 << 0;
                 2
             }
             0xC5 => {
-                self.regs.l = self.regs.l | (1 << 0);
+                self.regs.l |= 1 << 0;
                 2
             }
             0xC6 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) | (1 << 0);
-                self.mmu.wb(a, v);
+                let v = self.read(a) | (1 << 0);
+                self.write(a, v);
                 4
             }
             0xC7 => {
-                self.regs.a = self.regs.a | (1 << 0);
+                self.regs.a |= 1 << 0;
                 2
             }
             0xC8 => {
-                self.regs.b = self.regs.b | (1 << 1);
+                self.regs.b |= 1 << 1;
                 2
             }
             0xC9 => {
-                self.regs.c = self.regs.c | (1 << 1);
+                self.regs.c |= 1 << 1;
                 2
             }
             0xCA => {
-                self.regs.d = self.regs.d | (1 << 1);
+                self.regs.d |= 1 << 1;
                 2
             }
             0xCB => {
-                self.regs.e = self.regs.e | (1 << 1);
+                self.regs.e |= 1 << 1;
                 2
             }
             0xCC => {
-                self.regs.h = self.regs.h | (1 << 1);
+                self.regs.h |= 1 << 1;
                 2
             }
             0xCD => {
-                self.regs.l = self.regs.l | (1 << 1);
+                self.regs.l |= 1 << 1;
                 2
             }
             0xCE => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) | (1 << 1);
-                self.mmu.wb(a, v);
+                let v = self.read(a) | (1 << 1);
+                self.write(a, v);
                 4
             }
             0xCF => {
-                self.regs.a = self.regs.a | (1 << 1);
+                self.regs.a |= 1 << 1;
                 2
             }
             0xD0 => {
-                self.regs.b = self.regs.b | (1 << 2);
+                self.regs.b |= 1 << 2;
                 2
             }
             0xD1 => {
-                self.regs.c = self.regs.c | (1 << 2);
+                self.regs.c |= 1 << 2;
                 2
             }
             0xD2 => {
-                self.regs.d = self.regs.d | (1 << 2);
+                self.regs.d |= 1 << 2;
                 2
             }
             0xD3 => {
-                self.regs.e = self.regs.e | (1 << 2);
+                self.regs.e |= 1 << 2;
                 2
             }
             0xD4 => {
-                self.regs.h = self.regs.h | (1 << 2);
+                self.regs.h |= 1 << 2;
                 2
             }
             0xD5 => {
-                self.regs.l = self.regs.l | (1 << 2);
+                self.regs.l |= 1 << 2;
                 2
             }
             0xD6 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) | (1 << 2);
-                self.mmu.wb(a, v);
+                let v = self.read(a) | (1 << 2);
+                self.write(a, v);
                 4
             }
             0xD7 => {
-                self.regs.a = self.regs.a | (1 << 2);
+                self.regs.a |= 1 << 2;
                 2
             }
             0xD8 => {
-                self.regs.b = self.regs.b | (1 << 3);
+                self.regs.b |= 1 << 3;
                 2
             }
             0xD9 => {
-                self.regs.c = self.regs.c | (1 << 3);
+                self.regs.c |= 1 << 3;
                 2
             }
             0xDA => {
-                self.regs.d = self.regs.d | (1 << 3);
+                self.regs.d |= 1 << 3;
                 2
             }
             0xDB => {
-                self.regs.e = self.regs.e | (1 << 3);
+                self.regs.e |= 1 << 3;
                 2
             }
             0xDC => {
-                self.regs.h = self.regs.h | (1 << 3);
+                self.regs.h |= 1 << 3;
                 2
             }
             0xDD => {
-                self.regs.l = self.regs.l | (1 << 3);
+                self.regs.l |= 1 << 3;
                 2
             }
             0xDE => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) | (1 << 3);
-                self.mmu.wb(a, v);
+                let v = self.read(a) | (1 << 3);
+                self.write(a, v);
                 4
             }
             0xDF => {
-                self.regs.a = self.regs.a | (1 << 3);
+                self.regs.a |= 1 << 3;
                 2
             }
             0xE0 => {
-                self.regs.b = self.regs.b | (1 << 4);
+                self.regs.b |= 1 << 4;
                 2
             }
             0xE1 => {
-                self.regs.c = self.regs.c | (1 << 4);
+                self.regs.c |= 1 << 4;
                 2
             }
             0xE2 => {
-                self.regs.d = self.regs.d | (1 << 4);
+                self.regs.d |= 1 << 4;
                 2
             }
             0xE3 => {
-                self.regs.e = self.regs.e | (1 << 4);
+                self.regs.e |= 1 << 4;
                 2
             }
             0xE4 => {
-                self.regs.h = self.regs.h | (1 << 4);
+                self.regs.h |= 1 << 4;
                 2
             }
             0xE5 => {
-                self.regs.l = self.regs.l | (1 << 4);
+                self.regs.l |= 1 << 4;
                 2
             }
             0xE6 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) | (1 << 4);
-                self.mmu.wb(a, v);
+                let v = self.read(a) | (1 << 4);
+                self.write(a, v);
                 4
             }
             0xE7 => {
-                self.regs.a = self.regs.a | (1 << 4);
+                self.regs.a |= 1 << 4;
                 2
             }
             0xE8 => {
-                self.regs.b = self.regs.b | (1 << 5);
+                self.regs.b |= 1 << 5;
                 2
             }
             0xE9 => {
-                self.regs.c = self.regs.c | (1 << 5);
+                self.regs.c |= 1 << 5;
                 2
             }
             0xEA => {
-                self.regs.d = self.regs.d | (1 << 5);
+                self.regs.d |= 1 << 5;
                 2
             }
             0xEB => {
-                self.regs.e = self.regs.e | (1 << 5);
+                self.regs.e |= 1 << 5;
                 2
             }
             0xEC => {
-                self.regs.h = self.regs.h | (1 << 5);
+                self.regs.h |= 1 << 5;
                 2
             }
             0xED => {
-                self.regs.l = self.regs.l | (1 << 5);
+                self.regs.l |= 1 << 5;
                 2
             }
             0xEE => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) | (1 << 5);
-                self.mmu.wb(a, v);
+                let v = self.read(a) | (1 << 5);
+                self.write(a, v);
                 4
             }
             0xEF => {
-                self.regs.a = self.regs.a | (1 << 5);
+                self.regs.a |= 1 << 5;
                 2
             }
             0xF0 => {
-                self.regs.b = self.regs.b | (1 << 6);
+                self.regs.b |= 1 << 6;
                 2
             }
             0xF1 => {
-                self.regs.c = self.regs.c | (1 << 6);
+                self.regs.c |= 1 << 6;
                 2
             }
             0xF2 => {
-                self.regs.d = self.regs.d | (1 << 6);
+                self.regs.d |= 1 << 6;
                 2
             }
             0xF3 => {
-                self.regs.e = self.regs.e | (1 << 6);
+                self.regs.e |= 1 << 6;
                 2
             }
             0xF4 => {
-                self.regs.h = self.regs.h | (1 << 6);
+                self.regs.h |= 1 << 6;
                 2
             }
             0xF5 => {
-                self.regs.l = self.regs.l | (1 << 6);
+                self.regs.l |= 1 << 6;
                 2
             }
             0xF6 => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) | (1 << 6);
-                self.mmu.wb(a, v);
+                let v = self.read(a) | (1 << 6);
+                self.write(a, v);
                 4
             }
             0xF7 => {
-                self.regs.a = self.regs.a | (1 << 6);
+                self.regs.a |= 1 << 6;
                 2
             }
             0xF8 => {
-                self.regs.b = self.regs.b | (1 << 7);
+                self.regs.b |= 1 << 7;
                 2
             }
             0xF9 => {
-                self.regs.c = self.regs.c | (1 << 7);
+                self.regs.c |= 1 << 7;
                 2
             }
             0xFA => {
-                self.regs.d = self.regs.d | (1 << 7);
+                self.regs.d |= 1 << 7;
                 2
             }
             0xFB => {
-                self.regs.e = self.regs.e | (1 << 7);
+                self.regs.e |= 1 << 7;
                 2
             }
             0xFC => {
-                self.regs.h = self.regs.h | (1 << 7);
+                self.regs.h |= 1 << 7;
                 2
             }
             0xFD => {
-                self.regs.l = self.regs.l | (1 << 7);
+                self.regs.l |= 1 << 7;
                 2
             }
             0xFE => {
                 let a = self.regs.hl();
-                let v = self.mmu.rb(a) | (1 << 7);
-                self.mmu.wb(a, v);
+                let v = self.read(a) | (1 << 7);
+                self.write(a, v);
                 4
             }
             0xFF => {
-                self.regs.a = self.regs.a | (1 << 7);
+                self.regs.a |= 1 << 7;
                 2
             }
         }
@@ -0,0 +1,607 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+use log::warn;
+
+use crate::device::{Device, CYCLES_PER_FRAME};
+use crate::disasm;
+use crate::registers::CpuFlag::{C, H, N, Z};
+use crate::symbols::Symbols;
+use crate::watch::{Access, WatchKind, Watchpoint};
+
+const HELP: &str = "\
+Commands:
+  s, step [N]             execute N instructions (default 1)
+  c, continue             run until a breakpoint is hit or Ctrl-C
+  f, frame                run until the end of the current frame
+  b, break [BB:]ADDR|LABEL
+                          set a breakpoint, optionally bank-qualified
+  d, delete [BB:]ADDR|LABEL|all
+                          clear a breakpoint, or all of them
+  bl, breakpoints         list breakpoints
+  wa, watch KIND START[-END] [VAL]
+                          set a read/write/access/change watchpoint,
+                          optionally only when the value equals VAL
+  uw, unwatch N|all       clear watchpoints
+  wl, watchpoints         list watchpoints
+  r, regs                 print registers and flags
+  bt, backtrace           print the shadow call stack
+  x, mem ADDR [LEN]       hexdump memory (default 64 bytes)
+  w, write ADDR VAL...    write bytes to memory
+  l, dis [ADDR] [N]       disassemble N instructions (default around PC)
+  q, quit                 exit the emulator
+Ctrl-C stops a running `continue` or `frame` and returns here.
+Addresses and banks are hexadecimal; counts and values are decimal unless
+prefixed with 0x or $. An empty line repeats the previous command.";
+
+/// Drives the device while a debugger is attached, in place of the normal
+/// emulation loop.
+pub trait DebugInterface: Send {
+    /// Runs the device for at most `budget` T-cycles, or blocks waiting for
+    /// commands while paused. Returns false when the session asked to quit.
+    fn update(&mut self, device: &mut Device, budget: u64) -> bool;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub bank: Option<usize>,
+    pub address: u16,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.address),
+            None => write!(f, "{:04X}", self.address),
+        }
+    }
+}
+
+/// Set by Ctrl-C, to break into a running session.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Turns Ctrl-C into a break-in request rather than killing the process,
+/// from the first time the debugger lets the device run.
+fn catch_interrupts() {
+    static HANDLER: Once = Once::new();
+    HANDLER.call_once(|| {
+        if let Err(e) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed)) {
+            warn!("Cannot catch Ctrl-C, only breakpoints will stop execution: {e}");
+        }
+    });
+}
+
+enum State {
+    Paused,
+    Running,
+    RunningFrame(u64),
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    state: State,
+    last_command: String,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            state: State::Paused,
+            last_command: String::new(),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    fn run(&mut self, state: State) {
+        catch_interrupts();
+        INTERRUPTED.store(false, Ordering::Relaxed);
+        self.state = state;
+    }
+
+    fn pause(&mut self, device: &Device) {
+        self.state = State::Paused;
+        print_registers(device);
+        println!("{}", disassemble_at(device, device.cpu.regs.pc, 1));
+    }
+
+    /// Reports any breakpoint at PC or watchpoint triggered by the last
+    /// instruction. Returns true if execution should stop.
+    fn hit_breakpoint(&self, device: &mut Device) -> bool {
+        let mut hit = false;
+        if let Some(watch) = &mut device.cpu.bus.watch {
+            for h in watch.take_hits() {
+                let access = match h.access {
+                    Access::Read => format!("read {:02X}", h.value),
+                    Access::Write => format!("write {:02X} -> {:02X}", h.old, h.value),
+                };
+                let instruction = disassemble_at(device, h.pc, 1);
+                println!(
+                    "Watchpoint ({}) hit: {} at {} by\n{}",
+                    h.watchpoint,
+                    access,
+                    device.describe_address(h.address),
+                    instruction.trim_start_matches("=>")
+                );
+                hit = true;
+            }
+        }
+
+        let pc = device.cpu.regs.pc;
+        let bank = disasm::bank_of(pc, device.cpu.bus.rom.bank());
+        if let Some(breakpoint) = self
+            .breakpoints
+            .iter()
+            .find(|b| b.address == pc && b.bank.is_none_or(|b| b == bank))
+        {
+            println!(
+                "Breakpoint {} hit",
+                describe_breakpoint(device, *breakpoint)
+            );
+            hit = true;
+        }
+        hit
+    }
+
+    fn repl(&mut self, device: &mut Device) -> bool {
+        let stdin = io::stdin();
+        loop {
+            print!("(gbc) ");
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return false,
+                Ok(_) => {}
+            }
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                line = self.last_command.clone();
+            } else {
+                self.last_command = line.clone();
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let Some((&command, args)) = words.split_first() else {
+                continue;
+            };
+
+            match self.execute(device, command, args) {
+                Ok(true) => {}
+                Ok(false) => return !matches!(command, "q" | "quit"),
+                Err(e) => println!("{e}"),
+            }
+        }
+    }
+
+    /// Executes one REPL command. Returns Ok(false) when the REPL should hand
+    /// control back to the emulation loop.
+    fn execute(
+        &mut self,
+        device: &mut Device,
+        command: &str,
+        args: &[&str],
+    ) -> Result<bool, String> {
+        match command {
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(n) => parse_number(n)?,
+                    None => 1,
+                };
+                for _ in 0..count {
+                    device.do_cycle();
+                    if self.hit_breakpoint(device) {
+                        break;
+                    }
+                }
+                print_registers(device);
+                println!("{}", disassemble_at(device, device.cpu.regs.pc, 1));
+            }
+            "c" | "continue" => {
+                self.run(State::Running);
+                return Ok(false);
+            }
+            "f" | "frame" => {
+                let frame_end = (device.cycles() / CYCLES_PER_FRAME + 1) * CYCLES_PER_FRAME;
+                self.run(State::RunningFrame(frame_end));
+                return Ok(false);
+            }
+            "b" | "break" => {
+                let breakpoint =
+                    parse_breakpoint(args.first().ok_or("Missing address")?, device.symbols())?;
+                self.add_breakpoint(breakpoint);
+                println!(
+                    "Breakpoint set at {}",
+                    describe_breakpoint(device, breakpoint)
+                );
+            }
+            "d" | "delete" => match args.first() {
+                Some(&"all") => self.breakpoints.clear(),
+                None => return Err("Missing address, or 'all'".to_string()),
+                Some(arg) => {
+                    let breakpoint = parse_breakpoint(arg, device.symbols())?;
+                    self.breakpoints.retain(|b| *b != breakpoint);
+                }
+            },
+            "bl" | "breakpoints" => {
+                for breakpoint in &self.breakpoints {
+                    println!("  {}", describe_breakpoint(device, *breakpoint));
+                }
+            }
+            "wa" | "watch" => {
+                let watchpoint = parse_watchpoint(args)?;
+                device.watchpoints().add(watchpoint);
+                println!("Watchpoint set: {}", watchpoint);
+            }
+            "uw" | "unwatch" => {
+                if let Some(watch) = &mut device.cpu.bus.watch {
+                    match args.first() {
+                        Some(&"all") => watch.clear(),
+                        None => return Err("Missing watchpoint number, or 'all'".to_string()),
+                        Some(n) => {
+                            let n = parse_number(n)? as usize;
+                            watch.remove(n).ok_or(format!("No watchpoint #{n}"))?;
+                        }
+                    }
+                    if watch.is_empty() {
+                        device.cpu.bus.watch = None;
+                    }
+                }
+            }
+            "wl" | "watchpoints" => {
+                if let Some(watch) = &device.cpu.bus.watch {
+                    for (i, watchpoint) in watch.list().iter().enumerate() {
+                        println!("  #{}: {}", i, watchpoint);
+                    }
+                }
+            }
+            "r" | "regs" => print_registers(device),
+            "bt" | "backtrace" => print_backtrace(device),
+            "x" | "mem" => {
+                let address = parse_hex(args.first().ok_or("Missing address")?)? as u16;
+                let len = match args.get(1) {
+                    Some(n) => parse_number(n)?,
+                    None => 64,
+                };
+                hexdump(device, address, len);
+            }
+            "w" | "write" => {
+                let address = parse_hex(args.first().ok_or("Missing address")?)? as u16;
+                if args.len() < 2 {
+                    return Err("Missing value".to_string());
+                }
+                for (i, value) in args[1..].iter().enumerate() {
+                    let value = parse_number(value)? as u8;
+                    device.cpu.bus.wb(address.wrapping_add(i as u16), value);
+                }
+            }
+            "l" | "dis" => {
+                let count = match args.get(1) {
+                    Some(n) => parse_number(n)? as usize,
+                    None => 10,
+                };
+                match args.first() {
+                    Some(address) => {
+                        let address = parse_hex(address)? as u16;
+                        println!("{}", disassemble_at(device, address, count));
+                    }
+                    None => println!("{}", disassemble_around_pc(device, count)),
+                }
+            }
+            "q" | "quit" => return Ok(false),
+            "h" | "help" => println!("{HELP}"),
+            other => return Err(format!("Unknown command '{other}', try 'help'")),
+        }
+        Ok(true)
+    }
+}
+
+impl DebugInterface for Debugger {
+    fn update(&mut self, device: &mut Device, budget: u64) -> bool {
+        if let State::Paused = self.state {
+            return self.repl(device);
+        }
+
+        let end = device.cycles() + budget;
+        while device.cycles() < end {
+            device.do_cycle();
+            if let State::RunningFrame(frame_end) = self.state {
+                if device.cycles() >= frame_end {
+                    println!("End of frame {}", device.cycles() / CYCLES_PER_FRAME);
+                    self.pause(device);
+                    break;
+                }
+            }
+            if self.hit_breakpoint(device) {
+                self.pause(device);
+                break;
+            }
+            if INTERRUPTED.swap(false, Ordering::Relaxed) {
+                println!("Interrupted");
+                self.pause(device);
+                break;
+            }
+        }
+        true
+    }
+}
+
+pub fn parse_number(s: &str) -> Result<u32, String> {
+    let res = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix('$')) {
+        u32::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    res.map_err(|e| format!("Invalid number '{s}': {e}"))
+}
+
+/// Parses a label, `ADDR` or `BB:ADDR`, where both parts are hexadecimal.
+/// Labels in switchable ROM banks are bank-qualified.
+pub fn parse_breakpoint(s: &str, symbols: Option<&Symbols>) -> Result<Breakpoint, String> {
+    if let Some((bank, address)) = symbols.and_then(|symbols| symbols.lookup(s)) {
+        let bank = (0x4000..=0x7FFF).contains(&address).then_some(bank);
+        return Ok(Breakpoint { bank, address });
+    }
+    match s.split_once(':') {
+        Some((bank, address)) => Ok(Breakpoint {
+            bank: Some(parse_hex(bank)? as usize),
+            address: parse_hex(address)? as u16,
+        }),
+        None => Ok(Breakpoint {
+            bank: None,
+            address: parse_hex(s)? as u16,
+        }),
+    }
+}
+
+/// Parses `KIND START[-END] [VALUE]`, with hexadecimal addresses.
+pub fn parse_watchpoint(args: &[&str]) -> Result<Watchpoint, String> {
+    let kind = match args.first() {
+        Some(&"r") | Some(&"read") => WatchKind::Read,
+        Some(&"w") | Some(&"write") => WatchKind::Write,
+        Some(&"rw") | Some(&"access") => WatchKind::Access,
+        Some(&"c") | Some(&"change") => WatchKind::Change,
+        Some(other) => return Err(format!("Unknown watchpoint kind '{other}'")),
+        None => return Err("Missing watchpoint kind".to_string()),
+    };
+    let range = args.get(1).ok_or("Missing address")?;
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_hex(start)?, parse_hex(end)?),
+        None => (parse_hex(range)?, parse_hex(range)?),
+    };
+    if end < start {
+        return Err(format!("Invalid range '{range}'"));
+    }
+    let value = match args.get(2) {
+        Some(v) => Some(parse_number(v)? as u8),
+        None => None,
+    };
+    Ok(Watchpoint {
+        kind,
+        start: start as u16,
+        end: end as u16,
+        value,
+    })
+}
+
+fn parse_hex(s: &str) -> Result<u32, String> {
+    let s = s.trim_start_matches("0x").trim_start_matches('$');
+    u32::from_str_radix(s, 16).map_err(|e| format!("Invalid address '{s}': {e}"))
+}
+
+fn describe_breakpoint(device: &Device, breakpoint: Breakpoint) -> String {
+    match breakpoint.bank {
+        Some(bank) => format!(
+            "{:02X}:{}",
+            bank,
+            device.describe_address(breakpoint.address)
+        ),
+        None => device.describe_address(breakpoint.address),
+    }
+}
+
+fn print_registers(device: &Device) {
+    let cpu = &device.cpu;
+    let regs = &cpu.regs;
+    let flag = |f, c| if regs.get_flag(f) { c } else { '-' };
+    println!(
+        "A:{:02X} F:{:02X} [{}{}{}{}] B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{} IME:{} HALT:{} cycles:{}",
+        regs.a,
+        regs.f,
+        flag(Z, 'Z'),
+        flag(N, 'N'),
+        flag(H, 'H'),
+        flag(C, 'C'),
+        regs.b,
+        regs.c,
+        regs.d,
+        regs.e,
+        regs.h,
+        regs.l,
+        regs.sp,
+        device.describe_address(regs.pc),
+        cpu.ime() as u8,
+        cpu.halted() as u8,
+        device.cycles(),
+    );
+}
+
+fn print_backtrace(device: &Device) {
+    let Some(profiler) = device.profiler() else {
+        println!("Call stack tracking is disabled");
+        return;
+    };
+    let symbols = device.symbols();
+    for (i, frame) in profiler.stack().iter().rev().enumerate() {
+        let kind = if frame.interrupt { " [interrupt]" } else { "" };
+        if i + 1 == profiler.stack().len() {
+            println!("#{:<3} {}{}", i, frame.function.name(symbols), kind);
+        } else {
+            println!(
+                "#{:<3} {}{}, returns to {}",
+                i,
+                frame.function.name(symbols),
+                kind,
+                device.describe_address(frame.return_address)
+            );
+        }
+    }
+}
+
+fn hexdump(device: &Device, address: u16, len: u32) {
+    let mmu = &device.cpu.bus;
+    let mut offset = 0;
+    while offset < len {
+        let row = address.wrapping_add(offset as u16);
+        let count = (len - offset).min(16);
+        let bytes: Vec<u8> = (0..count)
+            .map(|i| mmu.peek(row.wrapping_add(i as u16)))
+            .collect();
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let ascii: String = bytes
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+            .collect();
+        println!("{:04X}: {:<48} {}", row, hex.join(" "), ascii);
+        offset += count;
+    }
+}
+
+fn disassemble_at(device: &Device, address: u16, count: usize) -> String {
+    let pc = device.cpu.regs.pc;
+    let mmu = &device.cpu.bus;
+    let mut read = |a| mmu.peek(a);
+    let bank = mmu.rom.bank();
+    let name = |a| {
+        device
+            .symbols()
+            .and_then(|s| s.name(a, bank))
+            .map(str::to_string)
+    };
+    let mut lines = Vec::new();
+    let mut address = address;
+    for _ in 0..count {
+        let instruction = disasm::decode(&mut read, address);
+        if let Some(label) = name(address) {
+            lines.push(format!("{}:", label));
+        }
+        let marker = if address == pc { "=>" } else { "  " };
+        lines.push(format!(
+            "{} {:04X}: {}",
+            marker,
+            address,
+            instruction.render(name)
+        ));
+        address = address.wrapping_add(instruction.len());
+    }
+    lines.join("\n")
+}
+
+/// Disassembles starting a few instructions before PC. Since instructions
+/// have variable length, the start is the furthest address whose decoding
+/// lands exactly on PC.
+fn disassemble_around_pc(device: &Device, count: usize) -> String {
+    let pc = device.cpu.regs.pc;
+    let mmu = &device.cpu.bus;
+    let mut read = |a| mmu.peek(a);
+    let start = (1..=12u16)
+        .rev()
+        .map(|back| pc.wrapping_sub(back))
+        .find(|&start| {
+            let mut address = start;
+            let mut steps = 0;
+            while address != pc && steps < 4 {
+                address = address.wrapping_add(disasm::decode(&mut read, address).len());
+                steps += 1;
+            }
+            address == pc
+        })
+        .unwrap_or(pc);
+    disassemble_at(device, start, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("10"), Ok(10));
+        assert_eq!(parse_number("0x10"), Ok(16));
+        assert_eq!(parse_number("$ff"), Ok(255));
+        assert!(parse_number("ten").is_err());
+    }
+
+    #[test]
+    fn breakpoints() {
+        let plain = parse_breakpoint("150", None).unwrap();
+        assert_eq!((plain.bank, plain.address), (None, 0x0150));
+        let banked = parse_breakpoint("02:4a00", None).unwrap();
+        assert_eq!((banked.bank, banked.address), (Some(2), 0x4A00));
+        assert!(parse_breakpoint("02:", None).is_err());
+
+        let symbols = Symbols::parse("00:0150 Start\n03:4000 Music\n");
+        let start = parse_breakpoint("Start", Some(&symbols)).unwrap();
+        assert_eq!((start.bank, start.address), (None, 0x0150));
+        let music = parse_breakpoint("Music", Some(&symbols)).unwrap();
+        assert_eq!((music.bank, music.address), (Some(3), 0x4000));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let watchpoint = parse_watchpoint(&["w", "c000-c0ff", "0x10"]).unwrap();
+        assert_eq!(
+            watchpoint,
+            Watchpoint {
+                kind: WatchKind::Write,
+                start: 0xC000,
+                end: 0xC0FF,
+                value: Some(0x10),
+            }
+        );
+        let single = parse_watchpoint(&["change", "ff40"]).unwrap();
+        assert_eq!(
+            (single.kind, single.start, single.end),
+            (WatchKind::Change, 0xFF40, 0xFF40)
+        );
+        assert!(parse_watchpoint(&["x", "c000"]).is_err());
+        assert!(parse_watchpoint(&["r"]).is_err());
+        assert!(parse_watchpoint(&["r", "c100-c000"]).is_err());
+    }
+
+    #[test]
+    fn commands() {
+        let mut device = Device::from_rom_bytes(vec![0; 0x8000]);
+        let mut debugger = Debugger::new();
+        assert!(debugger.execute(&mut device, "jump", &[]).is_err());
+        for address in ["150", "200"] {
+            assert_eq!(debugger.execute(&mut device, "b", &[address]), Ok(true));
+        }
+        assert!(debugger.execute(&mut device, "d", &[]).is_err());
+        assert_eq!(debugger.breakpoints.len(), 2);
+        assert_eq!(debugger.execute(&mut device, "d", &["150"]), Ok(true));
+        assert_eq!(debugger.breakpoints.len(), 1);
+        assert_eq!(debugger.execute(&mut device, "delete", &["all"]), Ok(true));
+        assert!(debugger.breakpoints.is_empty());
+
+        // Ctrl-C stops a `continue` at the next instruction.
+        debugger.state = State::Running;
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        assert!(debugger.update(&mut device, CYCLES_PER_FRAME));
+        assert!(matches!(debugger.state, State::Paused));
+        assert!(device.cycles() < 100);
+    }
+}
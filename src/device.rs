@@ -1,12 +1,40 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
 use crate::{
+    cdl::CodeDataLog,
     cpu::CPU,
+    disasm,
+    error::{MovieError, StateError},
+    image,
+    input::{Inputs, Macro},
+    joypad::KeypadKey,
+    movie::{self, Movie, MovieInput, Playback, Session},
+    ppu::{SCREEN_HEIGHT, SCREEN_WIDTH},
+    profiler::Profiler,
+    registers::Registers,
     rom::{self},
+    serial::SerialDevice,
+    state::{StateReader, StateWriter},
+    symbols::Symbols,
+    trace::Tracer,
+    watch::Watchpoints,
     Result,
 };
 
+pub const CYCLES_PER_FRAME: u64 = 70224;
+
 pub struct Device {
-    cpu: CPU,
+    pub(crate) cpu: CPU,
+    cycles: u64,
+    symbols: Option<Arc<Symbols>>,
+    rom_path: String,
     save_state: Option<String>,
+    movie: Option<Session>,
+    inputs: Inputs,
+    /// Frame the buttons were last updated for.
+    input_frame: u64,
 }
 
 impl Device {
@@ -14,15 +42,433 @@ impl Device {
         let cart = rom::load(romname)?;
         Ok(Device {
             cpu: CPU::new(cart),
+            cycles: 0,
+            symbols: Symbols::load_for_rom(romname).map(Arc::new),
+            rom_path: romname.to_string(),
             save_state,
+            movie: None,
+            inputs: Inputs::default(),
+            input_frame: 0,
         })
     }
 
+    /// Device running a ROM image built in memory, for unit tests.
+    #[cfg(test)]
+    pub(crate) fn from_rom_bytes(bytes: Vec<u8>) -> Device {
+        Device {
+            cpu: CPU::new(rom::Rom::from_bytes(bytes)),
+            cycles: 0,
+            symbols: None,
+            rom_path: String::new(),
+            save_state: None,
+            movie: None,
+            inputs: Inputs::default(),
+            input_frame: 0,
+        }
+    }
+
+    pub fn rom_path(&self) -> &str {
+        &self.rom_path
+    }
+
+    /// Save state file: the one given at creation, or ROM.state.
+    pub fn state_path(&self) -> String {
+        match &self.save_state {
+            Some(path) => path.clone(),
+            None => Path::new(&self.rom_path)
+                .with_extension("state")
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+
+    /// Snapshot of the whole machine, only loadable with the same ROM.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.u32(self.cpu.bus.rom.checksum());
+        w.u64(self.cycles);
+        self.cpu.save_state(&mut w);
+        w.finish()
+    }
+
+    /// Restores a snapshot. This is refused while a movie is recorded or
+    /// played, as the jump would not be part of it.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        if self.movie_active() {
+            return Err(StateError::Movie.into());
+        }
+        let mut r = StateReader::new(state)?;
+        if r.u32()? != self.cpu.bus.rom.checksum() {
+            return Err(StateError::RomMismatch.into());
+        }
+        // Check the size first so that a bad state leaves the machine as is.
+        if state.len() != self.save_state().len() {
+            return Err(StateError::Truncated.into());
+        }
+        // Values out of range are only found halfway through.
+        let backup = self.save_state();
+        if let Err(e) = self.restore(state) {
+            self.restore(&backup)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = StateReader::new(state)?;
+        r.u32()?;
+        self.cycles = r.u64()?;
+        self.cpu.load_state(&mut r)
+    }
+
+    pub fn save_state_file(&self, path: &str) -> Result<()> {
+        fs::write(path, self.save_state())?;
+        Ok(())
+    }
+
+    pub fn load_state_file(&mut self, path: &str) -> Result<()> {
+        let state = fs::read(path)?;
+        self.load_state(&state)
+    }
+
+    /// Power-cycles the console. Cartridge RAM, peripherals and debugging
+    /// tools carry over, and `cycles()` keeps counting.
+    pub fn reset(&mut self) {
+        self.record(MovieInput::Reset);
+        self.cpu.reset();
+    }
+
+    /// Starts recording inputs from the current state, or from a reset
+    /// without `from_state`.
+    pub fn record_movie(&mut self, from_state: bool) {
+        self.movie = None;
+        let state = if from_state {
+            Some(self.save_state())
+        } else {
+            self.cpu.reset();
+            None
+        };
+        let movie = Movie::new(self.cpu.bus.rom.checksum(), state, self.cycles);
+        self.movie = Some(Session::Recording(movie));
+    }
+
+    /// Stops recording or playing a movie, returning the one recorded.
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        match self.movie.take()? {
+            Session::Recording(mut movie) => {
+                movie.end = self.cycles;
+                Some(movie)
+            }
+            Session::Playing(_) => None,
+        }
+    }
+
+    /// Starts replaying `movie`. Buttons pressed from then on are ignored
+    /// until it ends.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<()> {
+        if movie.checksum != self.cpu.bus.rom.checksum() {
+            return Err(MovieError::RomMismatch.into());
+        }
+        self.movie = None;
+        match &movie.start_state {
+            Some(state) => self.load_state(state)?,
+            None => {
+                self.cpu.reset();
+                self.cycles = movie.start;
+            }
+        }
+        self.movie = Some(Session::Playing(Playback::new(movie)));
+        Ok(())
+    }
+
+    /// Whether a movie is being recorded or played, until `stop_movie`.
+    pub fn movie_active(&self) -> bool {
+        self.movie.is_some()
+    }
+
+    /// The movie being replayed, if any.
+    pub fn playback(&self) -> Option<&Playback> {
+        match &self.movie {
+            Some(Session::Playing(playback)) => Some(playback),
+            _ => None,
+        }
+    }
+
+    fn playing_movie(&self) -> bool {
+        self.playback().is_some_and(|p| !p.finished(self.cycles))
+    }
+
+    fn record(&mut self, input: MovieInput) {
+        if let Some(Session::Recording(movie)) = &mut self.movie {
+            movie.inputs.push((self.cycles, input));
+        }
+    }
+
+    /// Applies the movie inputs and checkpoint due now.
+    fn step_movie(&mut self) {
+        let Some(mut session) = self.movie.take() else {
+            return;
+        };
+        for input in session.inputs(self.cycles) {
+            match input {
+                MovieInput::Press(key) => self.cpu.bus.joypad.press_button(key),
+                MovieInput::Release(key) => self.cpu.bus.joypad.release_button(key),
+                MovieInput::Reset => self.cpu.reset(),
+            }
+        }
+        session.checkpoint(self.cycles, || self.state_hash());
+        self.movie = Some(session);
+    }
+
+    /// Hash of the screen and the whole machine state.
+    pub fn state_hash(&self) -> u64 {
+        movie::hash(&[self.frame(), &self.save_state()])
+    }
+
+    /// Starts tracing instructions to `path`, with LY stuck at 0x90 so that
+    /// the trace can match Gameboy Doctor's logs. With `symbols`, each line
+    /// is followed by a comment naming the PC, which Gameboy Doctor does not
+    /// expect but `trace::diff` ignores.
+    pub fn set_trace(&mut self, path: &str, symbols: bool) -> Result<()> {
+        let mut tracer = Tracer::create(path)?;
+        if symbols {
+            tracer.symbols = self.symbols.clone();
+        }
+        self.cpu.tracer = Some(tracer);
+        self.cpu.bus.doctor = true;
+        Ok(())
+    }
+
+    /// Starts tracking the shadow call stack and cycles per function.
+    pub fn enable_profiler(&mut self) {
+        if self.cpu.profiler.is_none() {
+            self.cpu.profiler = Some(Box::new(Profiler::new()));
+        }
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.cpu.profiler.as_deref()
+    }
+
+    /// Writes the flat, hierarchical and folded-stack profile reports.
+    pub fn write_profile(&self, prefix: &str) -> Result<()> {
+        if let Some(profiler) = self.profiler() {
+            profiler.write_reports(prefix, self.symbols())?;
+        }
+        Ok(())
+    }
+
+    /// Starts marking ROM bytes as code, operand, data or DMA source,
+    /// continuing the log saved at `path` by a previous session if any.
+    pub fn enable_cdl(&mut self, path: &str) -> Result<()> {
+        let size = self.cpu.bus.rom.bytes().len();
+        let cdl = CodeDataLog::load_or_new(Path::new(path), size)?;
+        self.cpu.bus.cdl = Some(Box::new(cdl));
+        Ok(())
+    }
+
+    pub fn save_cdl(&self, path: &str) -> Result<()> {
+        if let Some(cdl) = &self.cpu.bus.cdl {
+            cdl.save(Path::new(path))?;
+        }
+        Ok(())
+    }
+
+    /// Plugs `device` into the link port.
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.cpu.bus.serial.connect(device);
+    }
+
+    /// Starts recording the bytes sent over the link port.
+    pub fn capture_serial(&mut self) {
+        self.cpu.bus.serial.capture();
+    }
+
+    /// Bytes sent over the link port since `capture_serial`.
+    pub fn serial_output(&self) -> &[u8] {
+        self.cpu.bus.serial.output()
+    }
+
+    /// Writes the bytes sent over the link port since `capture_serial`.
+    pub fn save_serial_output(&self, path: &str) -> Result<()> {
+        fs::write(path, self.serial_output())?;
+        Ok(())
+    }
+
+    pub fn press_button(&mut self, button: KeypadKey) {
+        self.inputs.press(button);
+        self.update_buttons();
+    }
+
+    pub fn release_button(&mut self, button: KeypadKey) {
+        self.inputs.release(button);
+        self.update_buttons();
+    }
+
+    /// Presses `button` for `rate` frames and releases it for as many, in
+    /// turn, until `release_turbo`.
+    pub fn press_turbo(&mut self, button: KeypadKey, rate: u32) {
+        self.animate_inputs();
+        self.inputs.press_turbo(button, rate, self.frame_number());
+        self.update_buttons();
+    }
+
+    pub fn release_turbo(&mut self, button: KeypadKey) {
+        self.inputs.release_turbo(button);
+        self.update_buttons();
+    }
+
+    /// Presses the buttons of each step of `steps` in turn, starting now.
+    pub fn play_macro(&mut self, steps: Macro) {
+        self.animate_inputs();
+        self.inputs.play_macro(steps, self.frame_number());
+        self.update_buttons();
+    }
+
+    /// Starts recording the buttons pressed on each frame, from the current
+    /// one.
+    pub fn record_macro(&mut self) {
+        self.animate_inputs();
+        self.inputs.record_macro();
+    }
+
+    pub fn recording_macro(&self) -> bool {
+        self.inputs.recording_macro()
+    }
+
+    pub fn stop_macro_recording(&mut self) -> Option<Macro> {
+        self.inputs.stop_macro_recording()
+    }
+
+    /// Counts frames from now if they were not counted already.
+    fn animate_inputs(&mut self) {
+        if !self.inputs.animated() {
+            self.input_frame = self.frame_number();
+        }
+    }
+
+    fn frame_number(&self) -> u64 {
+        self.cycles / CYCLES_PER_FRAME
+    }
+
+    /// Presses and releases joypad buttons to match the host, turbo and
+    /// macros. A movie being replayed has the joypad to itself.
+    fn update_buttons(&mut self) {
+        if self.playing_movie() {
+            return;
+        }
+        let buttons = self.inputs.buttons(self.frame_number());
+        for key in KeypadKey::ALL {
+            let pressed = buttons.contains(&key);
+            if pressed == self.cpu.bus.joypad.is_pressed(key) {
+                continue;
+            }
+            if pressed {
+                self.record(MovieInput::Press(key));
+                self.cpu.bus.joypad.press_button(key);
+            } else {
+                self.record(MovieInput::Release(key));
+                self.cpu.bus.joypad.release_button(key);
+            }
+        }
+    }
+
+    /// Samples the finished frame for macro recording, then updates the
+    /// buttons for the new one.
+    fn next_input_frame(&mut self) {
+        self.input_frame = self.frame_number();
+        let joypad = &self.cpu.bus.joypad;
+        self.inputs.sample(|key| joypad.is_pressed(key));
+        self.update_buttons();
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.cpu.regs
+    }
+
+    /// Reads a byte without side effects.
+    pub fn peek(&self, address: u16) -> u8 {
+        self.cpu.bus.peek(address)
+    }
+
+    /// Watchpoints checked on every memory access, enabling them if needed.
+    pub fn watchpoints(&mut self) -> &mut Watchpoints {
+        self.cpu
+            .bus
+            .watch
+            .get_or_insert_with(|| Box::new(Watchpoints::new()))
+    }
+
+    pub fn symbols(&self) -> Option<&Symbols> {
+        self.symbols.as_deref()
+    }
+
+    /// Formats an address as `XXXX`, followed by the nearest symbol if any.
+    pub fn describe_address(&self, address: u16) -> String {
+        let bank = self.cpu.bus.rom.bank();
+        match self.symbols().and_then(|s| s.describe(address, bank)) {
+            Some(name) => format!("{:04X} ({})", address, name),
+            None => format!("{:04X}", address),
+        }
+    }
+
     pub fn do_cycle(&mut self) -> u32 {
-        self.cpu.do_cycle()
+        if let Some(session) = &self.movie {
+            if self.cycles >= session.next_event() {
+                self.step_movie();
+            }
+        }
+        if self.inputs.animated() && self.frame_number() != self.input_frame {
+            self.next_input_frame();
+        }
+        let ticks = self.cpu.do_cycle();
+        self.cycles += ticks as u64;
+        ticks
+    }
+
+    /// T-cycles executed since power-on.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Runs until `cycles()` reaches `cycles`.
+    pub fn run_until(&mut self, cycles: u64) {
+        while self.cycles < cycles {
+            self.do_cycle();
+        }
+    }
+
+    /// Runs to the end of the current frame, which is `CYCLES_PER_FRAME`
+    /// T-cycles when starting on a frame boundary. Returns the T-cycles run.
+    pub fn run_frame(&mut self) -> u64 {
+        let start = self.cycles;
+        self.run_until((self.frame_number() + 1) * CYCLES_PER_FRAME);
+        self.cycles - start
+    }
+
+    /// Last rendered screen, one shade from 0 (white) to 3 (black) per pixel.
+    pub fn frame(&self) -> &[u8] {
+        self.cpu.bus.ppu.frame()
+    }
+
+    /// Saves the last rendered screen as a grayscale PNG.
+    pub fn screenshot(&self, path: &str) -> Result<()> {
+        let pixels: Vec<u8> = self.frame().iter().map(|s| 0xFF - s * 0x55).collect();
+        image::save_grayscale_png(path, SCREEN_WIDTH, SCREEN_HEIGHT, &pixels)
+    }
+
+    /// Writes work RAM as mapped at 0xC000-0xDFFF.
+    pub fn dump_ram(&self, path: &str) -> Result<()> {
+        let ram: Vec<u8> = (0xC000..=0xDFFF).map(|a| self.cpu.bus.peek(a)).collect();
+        fs::write(path, ram)?;
+        Ok(())
     }
 
     pub fn ppu_data(&self) -> Vec<u8> {
-        self.cpu.mmu.ppu.get_vram()
+        self.cpu.bus.ppu.get_vram()
+    }
+
+    pub fn disassemble(&self, from: u16, to: u16) -> String {
+        disasm::disassemble_mmu(&self.cpu.bus, from, to, self.symbols())
     }
 }
@@ -0,0 +1,490 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::cdl::{self, CodeDataLog};
+use crate::mmu::Mmu;
+use crate::rom::{self, ROM_BANK_END};
+use crate::symbols::Symbols;
+use crate::Result;
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+const R16_MEM: [&str; 4] = ["[bc]", "[de]", "[hl+]", "[hl-]"];
+const COND: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const ROT: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const ACC: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BranchKind {
+    Jr,
+    Jp,
+    Call,
+}
+
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// Mnemonic and operands. For branches the target operand is left out and
+    /// appended by `render`, so that it can be replaced by a label.
+    pub mnemonic: String,
+    pub target: Option<(BranchKind, u16)>,
+    /// Memory address operand, written as `$xxxx` in `mnemonic`.
+    pub operand: Option<u16>,
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Formats the instruction, replacing addresses by the names `name`
+    /// returns for them.
+    pub fn render<F: Fn(u16) -> Option<String>>(&self, name: F) -> String {
+        let mut text = self.mnemonic.clone();
+        if let Some(address) = self.operand {
+            if let Some(name) = name(address) {
+                text = text.replace(&format!("${:04x}", address), &name);
+            }
+        }
+        match self.target {
+            Some((_, target)) => match name(target) {
+                Some(name) => text + &name,
+                None => format!("{}${:04x}", text, target),
+            },
+            None => text,
+        }
+    }
+}
+
+/// Decodes the instruction at `address`, reading its bytes through `read`.
+pub fn decode<F: FnMut(u16) -> u8>(read: &mut F, address: u16) -> Instruction {
+    let op = read(address);
+    let mut bytes = vec![op];
+    let mut imm8 = |bytes: &mut Vec<u8>| {
+        let v = read(address.wrapping_add(bytes.len() as u16));
+        bytes.push(v);
+        v
+    };
+
+    let x = op >> 6;
+    let y = ((op >> 3) & 0x07) as usize;
+    let z = (op & 0x07) as usize;
+    let p = y >> 1;
+    let q = y & 1;
+
+    let mut target = None;
+    let mut operand = None;
+    let mnemonic = match (x, z) {
+        (0, 0) => match y {
+            0 => "nop".to_string(),
+            1 => {
+                let a = imm16(&mut imm8, &mut bytes);
+                operand = Some(a);
+                format!("ld [${:04x}], sp", a)
+            }
+            2 => {
+                imm8(&mut bytes);
+                "stop".to_string()
+            }
+            _ => {
+                let e = imm8(&mut bytes) as i8;
+                let dest = address.wrapping_add(2).wrapping_add(e as u16);
+                target = Some((BranchKind::Jr, dest));
+                match y {
+                    3 => "jr ".to_string(),
+                    _ => format!("jr {}, ", COND[y - 4]),
+                }
+            }
+        },
+        (0, 1) if q == 0 => {
+            let v = imm16(&mut imm8, &mut bytes);
+            format!("ld {}, ${:04x}", R16[p], v)
+        }
+        (0, 1) => format!("add hl, {}", R16[p]),
+        (0, 2) if q == 0 => format!("ld {}, a", R16_MEM[p]),
+        (0, 2) => format!("ld a, {}", R16_MEM[p]),
+        (0, 3) if q == 0 => format!("inc {}", R16[p]),
+        (0, 3) => format!("dec {}", R16[p]),
+        (0, 4) => format!("inc {}", R8[y]),
+        (0, 5) => format!("dec {}", R8[y]),
+        (0, 6) => {
+            let v = imm8(&mut bytes);
+            format!("ld {}, ${:02x}", R8[y], v)
+        }
+        (0, 7) => ACC[y].to_string(),
+        (1, _) if op == 0x76 => "halt".to_string(),
+        (1, _) => format!("ld {}, {}", R8[y], R8[z]),
+        (2, _) => format!("{} a, {}", ALU[y], R8[z]),
+        (3, 0) => match y {
+            0..=3 => format!("ret {}", COND[y]),
+            4 => {
+                let v = imm8(&mut bytes);
+                operand = Some(0xFF00 | v as u16);
+                format!("ldh [$ff{:02x}], a", v)
+            }
+            5 => {
+                let e = imm8(&mut bytes) as i8;
+                format!("add sp, {}", e)
+            }
+            6 => {
+                let v = imm8(&mut bytes);
+                operand = Some(0xFF00 | v as u16);
+                format!("ldh a, [$ff{:02x}]", v)
+            }
+            _ => {
+                let e = imm8(&mut bytes) as i8;
+                match e {
+                    0.. => format!("ld hl, sp + {}", e),
+                    _ => format!("ld hl, sp - {}", e.unsigned_abs()),
+                }
+            }
+        },
+        (3, 1) if q == 0 => format!("pop {}", R16_STACK[p]),
+        (3, 1) => ["ret", "reti", "jp hl", "ld sp, hl"][p].to_string(),
+        (3, 2) => match y {
+            0..=3 => {
+                target = Some((BranchKind::Jp, imm16(&mut imm8, &mut bytes)));
+                format!("jp {}, ", COND[y])
+            }
+            4 => "ldh [c], a".to_string(),
+            5 => {
+                let a = imm16(&mut imm8, &mut bytes);
+                operand = Some(a);
+                format!("ld [${:04x}], a", a)
+            }
+            6 => "ldh a, [c]".to_string(),
+            _ => {
+                let a = imm16(&mut imm8, &mut bytes);
+                operand = Some(a);
+                format!("ld a, [${:04x}]", a)
+            }
+        },
+        (3, 3) => match y {
+            0 => {
+                target = Some((BranchKind::Jp, imm16(&mut imm8, &mut bytes)));
+                "jp ".to_string()
+            }
+            1 => {
+                let cb = imm8(&mut bytes);
+                decode_cb(cb)
+            }
+            6 => "di".to_string(),
+            7 => "ei".to_string(),
+            _ => format!("db ${:02x}", op),
+        },
+        (3, 4) if y < 4 => {
+            target = Some((BranchKind::Call, imm16(&mut imm8, &mut bytes)));
+            format!("call {}, ", COND[y])
+        }
+        (3, 5) if q == 0 => format!("push {}", R16_STACK[p]),
+        (3, 5) if p == 0 => {
+            target = Some((BranchKind::Call, imm16(&mut imm8, &mut bytes)));
+            "call ".to_string()
+        }
+        (3, 6) => {
+            let v = imm8(&mut bytes);
+            format!("{} a, ${:02x}", ALU[y], v)
+        }
+        (3, 7) => format!("rst ${:02x}", y * 8),
+        _ => format!("db ${:02x}", op),
+    };
+
+    Instruction {
+        address,
+        bytes,
+        mnemonic,
+        target,
+        operand,
+    }
+}
+
+fn imm16<F: FnMut(&mut Vec<u8>) -> u8>(imm8: &mut F, bytes: &mut Vec<u8>) -> u16 {
+    let lo = imm8(bytes) as u16;
+    let hi = imm8(bytes) as u16;
+    (hi << 8) | lo
+}
+
+fn decode_cb(op: u8) -> String {
+    let y = ((op >> 3) & 0x07) as usize;
+    let r = R8[(op & 0x07) as usize];
+    match op >> 6 {
+        0 => format!("{} {}", ROT[y], r),
+        1 => format!("bit {}, {}", y, r),
+        2 => format!("res {}, {}", y, r),
+        _ => format!("set {}, {}", y, r),
+    }
+}
+
+/// Bank an address belongs to, given the ROM bank mapped at 0x4000-0x7FFF.
+pub fn bank_of(address: u16, bank: usize) -> usize {
+    match address {
+        0x0000..=0x3FFF => 0,
+        0x4000..=ROM_BANK_END => bank,
+        _ => 0,
+    }
+}
+
+pub fn label_name(kind: BranchKind, bank: usize, address: u16) -> String {
+    let prefix = match kind {
+        BranchKind::Jr => "jr",
+        BranchKind::Jp => "Jump",
+        BranchKind::Call => "Call",
+    };
+    format!("{}_{:03x}_{:04x}", prefix, bank, address)
+}
+
+/// Bytes of `from..to` as a `db` line, or `None` if the first one is not
+/// known to be data.
+fn decode_data<F: FnMut(u16) -> u8>(
+    read: &mut F,
+    bank: usize,
+    from: u16,
+    to: u16,
+    cdl: &CodeDataLog,
+    symbols: Option<&Symbols>,
+) -> Option<Instruction> {
+    let is_data = |address| cdl::rom_offset(address, bank).is_some_and(|o| cdl.is_data(o));
+    if !is_data(from) {
+        return None;
+    }
+    let mut bytes = vec![read(from)];
+    let mut address = from as u32 + 1;
+    while address < to as u32 && bytes.len() < 8 {
+        let a = address as u16;
+        if !is_data(a) || symbols.is_some_and(|s| s.name(a, bank).is_some()) {
+            break;
+        }
+        bytes.push(read(a));
+        address += 1;
+    }
+    let values: Vec<String> = bytes.iter().map(|b| format!("${:02x}", b)).collect();
+    Some(Instruction {
+        address: from,
+        mnemonic: format!("db {}", values.join(", ")),
+        bytes,
+        target: None,
+        operand: None,
+    })
+}
+
+/// Disassembles `from..to` (end exclusive) into RGBDS source. `bank` is the
+/// ROM bank mapped at 0x4000-0x7FFF while reading. Addresses with a symbol
+/// are printed by name. With a code/data log, bytes that were accessed but
+/// never executed are emitted as `db` rather than decoded.
+pub fn disassemble<F: FnMut(u16) -> u8>(
+    mut read: F,
+    bank: usize,
+    from: u16,
+    to: u16,
+    symbols: Option<&Symbols>,
+    cdl: Option<&CodeDataLog>,
+) -> String {
+    let mut instructions = Vec::new();
+    let mut address = from as u32;
+    while address < to as u32 {
+        let instruction = cdl
+            .and_then(|cdl| decode_data(&mut read, bank, address as u16, to, cdl, symbols))
+            .unwrap_or_else(|| decode(&mut read, address as u16));
+        address += instruction.len() as u32;
+        instructions.push(instruction);
+    }
+
+    let symbol = |address| symbols.and_then(|s| s.name(address, bank));
+    let mut labels = BTreeMap::new();
+    for instruction in &instructions {
+        if let Some(name) = symbol(instruction.address) {
+            labels.insert(instruction.address, name.to_string());
+        }
+    }
+    for instruction in &instructions {
+        if let Some((kind, target)) = instruction.target {
+            if instructions.iter().any(|i| i.address == target) {
+                labels
+                    .entry(target)
+                    .or_insert_with(|| label_name(kind, bank_of(target, bank), target));
+            }
+        }
+    }
+
+    let mut out = String::new();
+    match from {
+        0x0000..=0x3FFF => {
+            let _ = writeln!(out, "SECTION \"ROM0 ${:04x}\", ROM0[${:04x}]\n", from, from);
+        }
+        0x4000..=ROM_BANK_END => {
+            let _ = writeln!(
+                out,
+                "SECTION \"ROM Bank ${:03x} ${:04x}\", ROMX[${:04x}], BANK[${:x}]\n",
+                bank, from, from, bank
+            );
+        }
+        _ => {}
+    }
+
+    for instruction in &instructions {
+        if let Some(label) = labels.get(&instruction.address) {
+            let _ = writeln!(out, "{}:", label);
+        }
+        let name = |address| {
+            labels
+                .get(&address)
+                .cloned()
+                .or_else(|| symbol(address).map(str::to_string))
+        };
+        let bytes: Vec<String> = instruction
+            .bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let _ = writeln!(
+            out,
+            "    {:<35} ; ${:04x}: {}",
+            instruction.render(name),
+            instruction.address,
+            bytes.join(" ")
+        );
+    }
+
+    out
+}
+
+/// Disassembles the current contents of the address space of a live `Mmu`.
+pub fn disassemble_mmu(mmu: &Mmu, from: u16, to: u16, symbols: Option<&Symbols>) -> String {
+    disassemble(
+        |a| mmu.peek(a),
+        mmu.rom.bank(),
+        from,
+        to,
+        symbols,
+        mmu.cdl.as_deref(),
+    )
+}
+
+/// Disassembles a ROM image with `bank` mapped at 0x4000-0x7FFF.
+pub fn disassemble_rom(
+    bytes: &[u8],
+    bank: usize,
+    from: u16,
+    to: u16,
+    symbols: Option<&Symbols>,
+    cdl: Option<&CodeDataLog>,
+) -> String {
+    let read = |a: u16| {
+        cdl::rom_offset(a, bank)
+            .and_then(|offset| bytes.get(offset).copied())
+            .unwrap_or(0xFF)
+    };
+    disassemble(read, bank, from, to, symbols, cdl)
+}
+
+/// Disassembles a ROM file, naming addresses from `rom.sym` and separating
+/// code from data with `rom.cdl` if present.
+pub fn disassemble_file(path: &str, bank: usize, from: u16, to: u16) -> Result<String> {
+    let rom = rom::load(path)?;
+    let symbols = Symbols::load_for_rom(path);
+    let cdl_path = Path::new(path).with_extension("cdl");
+    let cdl = if cdl_path.exists() {
+        Some(CodeDataLog::load_or_new(&cdl_path, rom.bytes().len())?)
+    } else {
+        None
+    };
+    Ok(disassemble_rom(
+        rom.bytes(),
+        bank,
+        from,
+        to,
+        symbols.as_ref(),
+        cdl.as_ref(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bytes(address: u16, bytes: &[u8]) -> Instruction {
+        let mut read = |a: u16| bytes.get((a - address) as usize).copied().unwrap_or(0);
+        decode(&mut read, address)
+    }
+
+    fn text(address: u16, bytes: &[u8]) -> String {
+        decode_bytes(address, bytes).render(|_| None)
+    }
+
+    #[test]
+    fn cb_prefix() {
+        assert_eq!(text(0, &[0xCB, 0x7C]), "bit 7, h");
+        assert_eq!(text(0, &[0xCB, 0x36]), "swap [hl]");
+        assert_eq!(text(0, &[0xCB, 0x87]), "res 0, a");
+        assert_eq!(text(0, &[0xCB, 0xFE]), "set 7, [hl]");
+        assert_eq!(decode_bytes(0, &[0xCB, 0x11]).len(), 2);
+    }
+
+    #[test]
+    fn immediate_operands() {
+        assert_eq!(text(0, &[0x3E, 0x42]), "ld a, $42");
+        assert_eq!(text(0, &[0xE6, 0x0F]), "and a, $0f");
+        assert_eq!(text(0, &[0x21, 0x34, 0x12]), "ld hl, $1234");
+        let load = decode_bytes(0, &[0xFA, 0x00, 0xC0]);
+        assert_eq!(load.render(|_| None), "ld a, [$c000]");
+        assert_eq!(load.operand, Some(0xC000));
+        assert_eq!(load.len(), 3);
+        assert_eq!(text(0, &[0xE0, 0x40]), "ldh [$ff40], a");
+    }
+
+    #[test]
+    fn relative_operands() {
+        let jr = decode_bytes(0x0100, &[0x20, 0x05]);
+        assert_eq!(jr.target, Some((BranchKind::Jr, 0x0107)));
+        assert_eq!(jr.render(|_| None), "jr nz, $0107");
+        let back = decode_bytes(0x0100, &[0x18, 0xFE]);
+        assert_eq!(back.target, Some((BranchKind::Jr, 0x0100)));
+        assert_eq!(text(0, &[0xE8, 0x80]), "add sp, -128");
+        assert_eq!(text(0, &[0xF8, 0xFE]), "ld hl, sp - 2");
+        assert_eq!(text(0, &[0xF8, 0x05]), "ld hl, sp + 5");
+    }
+
+    #[test]
+    fn illegal_opcodes() {
+        for op in [
+            0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+        ] {
+            let instruction = decode_bytes(0, &[op, 0x00, 0x00]);
+            assert_eq!(instruction.render(|_| None), format!("db ${:02x}", op));
+            assert_eq!(instruction.len(), 1);
+        }
+    }
+
+    #[test]
+    fn labels_for_targets_in_range() {
+        // call $0156; jr $0153; nop; ret; jp $4000
+        let code = [0xCD, 0x56, 0x01, 0x18, 0xFE, 0x00, 0xC9, 0xC3, 0x00, 0x40];
+        let read = |a: u16| code.get((a - 0x0150) as usize).copied().unwrap_or(0);
+        let text = disassemble(read, 1, 0x0150, 0x015A, None, None);
+        let lines: Vec<&str> = text
+            .lines()
+            .map(|l| l.split(';').next().unwrap().trim_end())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "SECTION \"ROM0 $0150\", ROM0[$0150]",
+                "",
+                "    call Call_000_0156",
+                "jr_000_0153:",
+                "    jr jr_000_0153",
+                "    nop",
+                "Call_000_0156:",
+                "    ret",
+                "    jp $4000",
+            ]
+        );
+    }
+}
@@ -9,6 +9,15 @@ pub enum EmulatorError {
     #[error("Cartridge error: {0}")]
     Cartridge(#[from] CartridgeError),
 
+    #[error("Configuration error: {0}")]
+    Config(String),
+
+    #[error("Save state error: {0}")]
+    State(#[from] StateError),
+
+    #[error("Movie error: {0}")]
+    Movie(#[from] MovieError),
+
     #[error("I/O error: {0}")]
     IO(#[from] std::io::Error),
 }
@@ -40,4 +49,40 @@ pub enum CartridgeError {
     InvalidRomSize,
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum StateError {
+    #[error("Not a save state")]
+    BadMagic,
+
+    #[error("Unsupported save state version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("Save state is truncated")]
+    Truncated,
+
+    #[error("Save state was made with another ROM")]
+    RomMismatch,
+
+    #[error("Save state is corrupt")]
+    Corrupt,
+
+    #[error("Cannot load a state while a movie is recorded or played")]
+    Movie,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MovieError {
+    #[error("Not a movie")]
+    BadMagic,
+
+    #[error("Unsupported movie version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("Movie is truncated or corrupt")]
+    Corrupt,
+
+    #[error("Movie was recorded with another ROM")]
+    RomMismatch,
+}
+
 pub type Result<T> = std::result::Result<T, EmulatorError>;
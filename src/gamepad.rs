@@ -0,0 +1,502 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::input::Holders;
+use crate::joypad::KeypadKey;
+
+/// Controller button, named after its position: South is the bottom face
+/// button (A on Xbox pads, B on Nintendo ones).
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftTrigger,
+    LeftTrigger2,
+    RightTrigger,
+    RightTrigger2,
+    Select,
+    Start,
+    Mode,
+    LeftThumb,
+    RightThumb,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+/// Analog input that can move the D-pad.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PadStick {
+    Left,
+    Right,
+    /// D-pads reported as a hat rather than as buttons.
+    DPad,
+}
+
+/// Axis of a `PadStick`, from -1.0 (left or down) to 1.0 (right or up).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PadAxis {
+    X(PadStick),
+    Y(PadStick),
+}
+
+pub enum PadEvent {
+    Connected { name: String },
+    Disconnected,
+    Pressed(PadButton),
+    Released(PadButton),
+    Axis(PadAxis, f32),
+}
+
+/// Controller buttons for each Game Boy button.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PadMapping {
+    pub right: Vec<PadButton>,
+    pub left: Vec<PadButton>,
+    pub up: Vec<PadButton>,
+    pub down: Vec<PadButton>,
+    pub a: Vec<PadButton>,
+    pub b: Vec<PadButton>,
+    pub select: Vec<PadButton>,
+    pub start: Vec<PadButton>,
+    /// Sticks acting as the D-pad.
+    pub sticks: Vec<PadStick>,
+}
+
+impl Default for PadMapping {
+    fn default() -> PadMapping {
+        PadMapping {
+            right: vec![PadButton::DPadRight],
+            left: vec![PadButton::DPadLeft],
+            up: vec![PadButton::DPadUp],
+            down: vec![PadButton::DPadDown],
+            a: vec![PadButton::East],
+            b: vec![PadButton::South],
+            select: vec![PadButton::Select],
+            start: vec![PadButton::Start],
+            sticks: vec![PadStick::Left, PadStick::DPad],
+        }
+    }
+}
+
+impl PadMapping {
+    fn keys(&self, button: PadButton) -> impl Iterator<Item = KeypadKey> + '_ {
+        [
+            (&self.right, KeypadKey::Right),
+            (&self.left, KeypadKey::Left),
+            (&self.up, KeypadKey::Up),
+            (&self.down, KeypadKey::Down),
+            (&self.a, KeypadKey::A),
+            (&self.b, KeypadKey::B),
+            (&self.select, KeypadKey::Select),
+            (&self.start, KeypadKey::Start),
+        ]
+        .into_iter()
+        .filter(move |(buttons, _)| buttons.contains(&button))
+        .map(|(_, key)| key)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct GamepadConfig {
+    /// Stick deflection, from 0.0 to 1.0, below which it is ignored.
+    pub deadzone: f32,
+    pub buttons: PadMapping,
+    /// Mappings for controllers whose name contains the key, replacing
+    /// `buttons`.
+    pub controllers: BTreeMap<String, PadMapping>,
+}
+
+impl Default for GamepadConfig {
+    fn default() -> GamepadConfig {
+        GamepadConfig {
+            deadzone: 0.5,
+            buttons: PadMapping::default(),
+            controllers: BTreeMap::new(),
+        }
+    }
+}
+
+impl GamepadConfig {
+    fn mapping(&self, name: &str) -> &PadMapping {
+        self.controllers
+            .iter()
+            .find(|(pattern, _)| name.contains(pattern.as_str()))
+            .map_or(&self.buttons, |(_, mapping)| mapping)
+    }
+}
+
+#[derive(Default)]
+struct Pad {
+    name: String,
+    buttons: HashSet<PadButton>,
+    axes: HashMap<PadAxis, f32>,
+    keys: HashSet<KeypadKey>,
+}
+
+/// Turns the events of any number of controllers into Game Boy button
+/// presses and releases. A button stays pressed while any input mapped to
+/// it is held, on any controller.
+pub struct GamepadMapper {
+    config: GamepadConfig,
+    pads: HashMap<usize, Pad>,
+    /// Controllers holding each button.
+    held: Holders,
+}
+
+impl GamepadMapper {
+    pub fn new(config: &GamepadConfig) -> GamepadMapper {
+        GamepadMapper {
+            config: config.clone(),
+            pads: HashMap::new(),
+            held: Holders::default(),
+        }
+    }
+
+    /// Switches to `config`, returning the resulting button changes.
+    pub fn set_config(&mut self, config: &GamepadConfig) -> Vec<(KeypadKey, bool)> {
+        self.config = config.clone();
+        let ids: Vec<usize> = self.pads.keys().copied().collect();
+        ids.into_iter().flat_map(|id| self.update(id)).collect()
+    }
+
+    /// Handles an event of controller `id`, returning each Game Boy button
+    /// that changed and whether it is now pressed.
+    pub fn handle(&mut self, id: usize, event: PadEvent) -> Vec<(KeypadKey, bool)> {
+        match event {
+            PadEvent::Connected { name } => {
+                info!("Controller {id} connected: {name}");
+                self.pads.insert(
+                    id,
+                    Pad {
+                        name,
+                        ..Pad::default()
+                    },
+                );
+                Vec::new()
+            }
+            PadEvent::Disconnected => {
+                info!("Controller {id} disconnected");
+                let pad = self.pads.remove(&id).unwrap_or_default();
+                let mut changes: Vec<_> = pad.keys.into_iter().map(|key| (key, false)).collect();
+                changes.sort_by_key(|&(key, _)| key as u8);
+                self.merge(changes)
+            }
+            PadEvent::Pressed(button) => {
+                self.pads.entry(id).or_default().buttons.insert(button);
+                self.update(id)
+            }
+            PadEvent::Released(button) => {
+                self.pads.entry(id).or_default().buttons.remove(&button);
+                self.update(id)
+            }
+            PadEvent::Axis(axis, value) => {
+                self.pads.entry(id).or_default().axes.insert(axis, value);
+                self.update(id)
+            }
+        }
+    }
+
+    fn update(&mut self, id: usize) -> Vec<(KeypadKey, bool)> {
+        let Some(pad) = self.pads.get_mut(&id) else {
+            return Vec::new();
+        };
+        let mapping = self.config.mapping(&pad.name);
+        let deadzone = self.config.deadzone;
+
+        let mut keys: HashSet<KeypadKey> =
+            pad.buttons.iter().flat_map(|&b| mapping.keys(b)).collect();
+        for (&axis, &value) in &pad.axes {
+            let (stick, negative, positive) = match axis {
+                PadAxis::X(stick) => (stick, KeypadKey::Left, KeypadKey::Right),
+                PadAxis::Y(stick) => (stick, KeypadKey::Down, KeypadKey::Up),
+            };
+            if !mapping.sticks.contains(&stick) {
+                continue;
+            }
+            if value <= -deadzone {
+                keys.insert(negative);
+            } else if value >= deadzone {
+                keys.insert(positive);
+            }
+        }
+
+        let mut changes: Vec<(KeypadKey, bool)> = pad
+            .keys
+            .difference(&keys)
+            .map(|&key| (key, false))
+            .chain(keys.difference(&pad.keys).map(|&key| (key, true)))
+            .collect();
+        changes.sort_by_key(|&(key, pressed)| (pressed, key as u8));
+        pad.keys = keys;
+        self.merge(changes)
+    }
+
+    /// Keeps the changes of one controller that the others do not hide.
+    fn merge(&mut self, mut changes: Vec<(KeypadKey, bool)>) -> Vec<(KeypadKey, bool)> {
+        changes.retain(|&(key, pressed)| {
+            if pressed {
+                self.held.press(key)
+            } else {
+                self.held.release(key)
+            }
+        });
+        changes
+    }
+}
+
+/// Controllers connected to the host, read through gilrs when built with
+/// the `gamepad` feature.
+pub struct Gamepads {
+    #[cfg(feature = "gamepad")]
+    gilrs: gilrs::Gilrs,
+    mapper: GamepadMapper,
+}
+
+impl Gamepads {
+    #[cfg(feature = "gamepad")]
+    pub fn new(config: &GamepadConfig) -> Option<Gamepads> {
+        let gilrs = match gilrs::Gilrs::new() {
+            Ok(gilrs) => gilrs,
+            Err(e) => {
+                log::warn!("Controllers unavailable: {e}");
+                return None;
+            }
+        };
+        let mut mapper = GamepadMapper::new(config);
+        for (id, gamepad) in gilrs.gamepads() {
+            let name = gamepad.name().to_string();
+            mapper.handle(id.into(), PadEvent::Connected { name });
+        }
+        Some(Gamepads { gilrs, mapper })
+    }
+
+    #[cfg(not(feature = "gamepad"))]
+    pub fn new(_config: &GamepadConfig) -> Option<Gamepads> {
+        None
+    }
+
+    pub fn set_config(&mut self, config: &GamepadConfig) -> Vec<(KeypadKey, bool)> {
+        self.mapper.set_config(config)
+    }
+
+    /// Handles the controller events received since the last call, including
+    /// controllers being plugged in and out.
+    #[cfg(feature = "gamepad")]
+    pub fn poll(&mut self) -> Vec<(KeypadKey, bool)> {
+        use gilrs::EventType;
+
+        let mut changes = Vec::new();
+        while let Some(gilrs::Event { id, event, .. }) = self.gilrs.next_event() {
+            let event = match event {
+                EventType::Connected => PadEvent::Connected {
+                    name: self.gilrs.gamepad(id).name().to_string(),
+                },
+                EventType::Disconnected => PadEvent::Disconnected,
+                EventType::ButtonPressed(button, _) => match pad_button(button) {
+                    Some(button) => PadEvent::Pressed(button),
+                    None => continue,
+                },
+                EventType::ButtonReleased(button, _) => match pad_button(button) {
+                    Some(button) => PadEvent::Released(button),
+                    None => continue,
+                },
+                EventType::AxisChanged(axis, value, _) => match pad_axis(axis) {
+                    Some(axis) => PadEvent::Axis(axis, value),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            changes.extend(self.mapper.handle(id.into(), event));
+        }
+        changes
+    }
+
+    #[cfg(not(feature = "gamepad"))]
+    pub fn poll(&mut self) -> Vec<(KeypadKey, bool)> {
+        Vec::new()
+    }
+}
+
+#[cfg(feature = "gamepad")]
+fn pad_button(button: gilrs::Button) -> Option<PadButton> {
+    use gilrs::Button;
+
+    Some(match button {
+        Button::South => PadButton::South,
+        Button::East => PadButton::East,
+        Button::North => PadButton::North,
+        Button::West => PadButton::West,
+        Button::LeftTrigger => PadButton::LeftTrigger,
+        Button::LeftTrigger2 => PadButton::LeftTrigger2,
+        Button::RightTrigger => PadButton::RightTrigger,
+        Button::RightTrigger2 => PadButton::RightTrigger2,
+        Button::Select => PadButton::Select,
+        Button::Start => PadButton::Start,
+        Button::Mode => PadButton::Mode,
+        Button::LeftThumb => PadButton::LeftThumb,
+        Button::RightThumb => PadButton::RightThumb,
+        Button::DPadUp => PadButton::DPadUp,
+        Button::DPadDown => PadButton::DPadDown,
+        Button::DPadLeft => PadButton::DPadLeft,
+        Button::DPadRight => PadButton::DPadRight,
+        _ => return None,
+    })
+}
+
+#[cfg(feature = "gamepad")]
+fn pad_axis(axis: gilrs::Axis) -> Option<PadAxis> {
+    use gilrs::Axis;
+
+    Some(match axis {
+        Axis::LeftStickX => PadAxis::X(PadStick::Left),
+        Axis::LeftStickY => PadAxis::Y(PadStick::Left),
+        Axis::RightStickX => PadAxis::X(PadStick::Right),
+        Axis::RightStickY => PadAxis::Y(PadStick::Right),
+        Axis::DPadX => PadAxis::X(PadStick::DPad),
+        Axis::DPadY => PadAxis::Y(PadStick::DPad),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Virtual controller `0` named `name`.
+    fn connected(config: &GamepadConfig, name: &str) -> GamepadMapper {
+        let mut mapper = GamepadMapper::new(config);
+        let name = name.to_string();
+        assert!(mapper.handle(0, PadEvent::Connected { name }).is_empty());
+        mapper
+    }
+
+    #[test]
+    fn buttons() {
+        let mut mapper = connected(&GamepadConfig::default(), "Pad");
+        let changes = mapper.handle(0, PadEvent::Pressed(PadButton::East));
+        assert_eq!(changes, [(KeypadKey::A, true)]);
+        let changes = mapper.handle(0, PadEvent::Pressed(PadButton::Start));
+        assert_eq!(changes, [(KeypadKey::Start, true)]);
+        let changes = mapper.handle(0, PadEvent::Released(PadButton::East));
+        assert_eq!(changes, [(KeypadKey::A, false)]);
+        assert!(mapper
+            .handle(0, PadEvent::Pressed(PadButton::North))
+            .is_empty());
+    }
+
+    #[test]
+    fn stick_deadzone() {
+        let mut mapper = connected(&GamepadConfig::default(), "Pad");
+        let left_x = PadAxis::X(PadStick::Left);
+        assert!(mapper.handle(0, PadEvent::Axis(left_x, 0.3)).is_empty());
+        let changes = mapper.handle(0, PadEvent::Axis(left_x, 0.8));
+        assert_eq!(changes, [(KeypadKey::Right, true)]);
+        let changes = mapper.handle(0, PadEvent::Axis(left_x, -0.9));
+        assert_eq!(
+            changes,
+            [(KeypadKey::Right, false), (KeypadKey::Left, true)]
+        );
+        let changes = mapper.handle(0, PadEvent::Axis(PadAxis::Y(PadStick::Left), 1.0));
+        assert_eq!(changes, [(KeypadKey::Up, true)]);
+        let changes = mapper.handle(0, PadEvent::Axis(left_x, 0.1));
+        assert_eq!(changes, [(KeypadKey::Left, false)]);
+    }
+
+    #[test]
+    fn unmapped_stick_is_ignored() {
+        let mut mapper = connected(&GamepadConfig::default(), "Pad");
+        let right_x = PadAxis::X(PadStick::Right);
+        assert!(mapper.handle(0, PadEvent::Axis(right_x, 1.0)).is_empty());
+    }
+
+    #[test]
+    fn held_by_stick_and_button() {
+        let mut mapper = connected(&GamepadConfig::default(), "Pad");
+        mapper.handle(0, PadEvent::Pressed(PadButton::DPadDown));
+        let down = PadAxis::Y(PadStick::Left);
+        assert!(mapper.handle(0, PadEvent::Axis(down, -1.0)).is_empty());
+        assert!(mapper
+            .handle(0, PadEvent::Released(PadButton::DPadDown))
+            .is_empty());
+        let changes = mapper.handle(0, PadEvent::Axis(down, 0.0));
+        assert_eq!(changes, [(KeypadKey::Down, false)]);
+    }
+
+    #[test]
+    fn disconnect_releases_held_buttons() {
+        let mut mapper = connected(&GamepadConfig::default(), "Pad");
+        mapper.handle(0, PadEvent::Pressed(PadButton::South));
+        mapper.handle(0, PadEvent::Pressed(PadButton::Select));
+        let changes = mapper.handle(0, PadEvent::Disconnected);
+        let expected = [(KeypadKey::B, false), (KeypadKey::Select, false)];
+        assert_eq!(changes, expected);
+    }
+
+    #[test]
+    fn held_on_any_controller() {
+        let mut mapper = connected(&GamepadConfig::default(), "Pad");
+        let name = "Other".to_string();
+        mapper.handle(1, PadEvent::Connected { name });
+        let changes = mapper.handle(0, PadEvent::Pressed(PadButton::East));
+        assert_eq!(changes, [(KeypadKey::A, true)]);
+        assert!(mapper
+            .handle(1, PadEvent::Pressed(PadButton::East))
+            .is_empty());
+        assert!(mapper.handle(1, PadEvent::Disconnected).is_empty());
+        let changes = mapper.handle(0, PadEvent::Released(PadButton::East));
+        assert_eq!(changes, [(KeypadKey::A, false)]);
+    }
+
+    #[test]
+    fn remapping_keeps_buttons_held_elsewhere() {
+        let mut mapper = connected(&GamepadConfig::default(), "Xbox One");
+        let name = "Pro Controller".to_string();
+        mapper.handle(1, PadEvent::Connected { name });
+        mapper.handle(0, PadEvent::Pressed(PadButton::South));
+        mapper.handle(1, PadEvent::Pressed(PadButton::East));
+        // The first pad moves from B to A, which the second already holds.
+        let changes = mapper.set_config(&xbox_config());
+        assert_eq!(changes, [(KeypadKey::B, false)]);
+        assert!(mapper
+            .handle(1, PadEvent::Released(PadButton::East))
+            .is_empty());
+    }
+
+    fn xbox_config() -> GamepadConfig {
+        let mut config = GamepadConfig::default();
+        let xbox = PadMapping {
+            a: vec![PadButton::South],
+            b: vec![PadButton::West],
+            ..PadMapping::default()
+        };
+        config.controllers.insert("Xbox".to_string(), xbox);
+        config
+    }
+
+    #[test]
+    fn per_controller_mapping() {
+        let mut mapper = connected(&xbox_config(), "Xbox Wireless Controller");
+        let changes = mapper.handle(0, PadEvent::Pressed(PadButton::South));
+        assert_eq!(changes, [(KeypadKey::A, true)]);
+
+        let mut mapper = connected(&xbox_config(), "Pro Controller");
+        let changes = mapper.handle(0, PadEvent::Pressed(PadButton::South));
+        assert_eq!(changes, [(KeypadKey::B, true)]);
+    }
+
+    #[test]
+    fn reloading_config_remaps_held_buttons() {
+        let mut mapper = connected(&GamepadConfig::default(), "Xbox One");
+        mapper.handle(0, PadEvent::Pressed(PadButton::South));
+        let changes = mapper.set_config(&xbox_config());
+        assert_eq!(changes, [(KeypadKey::B, false), (KeypadKey::A, true)]);
+    }
+}
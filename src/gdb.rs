@@ -0,0 +1,559 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use log::{info, warn};
+
+use crate::debugger::DebugInterface;
+use crate::device::Device;
+use crate::registers::Registers;
+use crate::Result;
+
+/// Register order used by `g`/`G` packets, matching `TARGET_XML`.
+const REGISTER_COUNT: usize = 10;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rekop.sm83.core">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="f" bitsize="8" type="uint8"/>
+    <reg name="b" bitsize="8" type="uint8"/>
+    <reg name="c" bitsize="8" type="uint8"/>
+    <reg name="d" bitsize="8" type="uint8"/>
+    <reg name="e" bitsize="8" type="uint8"/>
+    <reg name="h" bitsize="8" type="uint8"/>
+    <reg name="l" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Largest packet we accept or send, advertised in `qSupported`.
+const PACKET_SIZE: usize = 0x4000;
+
+/// Most bytes an `m` reply can hold: two hex digits each, plus `$`, `#` and
+/// the checksum.
+const MAX_READ: u16 = ((PACKET_SIZE - 4) / 2) as u16;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+enum State {
+    Paused,
+    Running,
+    Detached,
+}
+
+/// GDB remote serial protocol stub driving a `Device` over TCP.
+pub struct GdbStub {
+    stream: TcpStream,
+    breakpoints: Vec<u16>,
+    state: State,
+}
+
+impl GdbStub {
+    /// Waits on 127.0.0.1:`port` until a debugger connects.
+    pub fn listen(port: u16) -> Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!("Waiting for GDB connection on 127.0.0.1:{port} ...");
+        let (stream, addr) = listener.accept()?;
+        info!("GDB connected from {addr}");
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            stream,
+            breakpoints: Vec::new(),
+            state: State::Paused,
+        })
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        self.stream.write_all(encode_packet(data).as_bytes())?;
+        self.stream.flush()
+    }
+
+    fn stop_reply(&mut self, signal: u8) -> io::Result<()> {
+        self.stop(&format!("S{:02x}", signal))
+    }
+
+    fn stop(&mut self, reply: &str) -> io::Result<()> {
+        self.state = State::Paused;
+        self.send(reply)
+    }
+
+    /// Stop reply for a breakpoint or watchpoint hit, if any. Breakpoints
+    /// are reported as such, as `qSupported` advertises `swbreak+`.
+    fn hit_breakpoint(&self, device: &mut Device) -> Option<String> {
+        let watch_hit = match &mut device.cpu.bus.watch {
+            Some(watch) => !watch.take_hits().is_empty(),
+            None => false,
+        };
+        if self.breakpoints.contains(&device.cpu.regs.pc) {
+            Some(format!("T{:02x}swbreak:;", SIGTRAP))
+        } else if watch_hit {
+            Some(format!("S{:02x}", SIGTRAP))
+        } else {
+            None
+        }
+    }
+
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let res = match self.stream.peek(&mut byte) {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.stream.set_nonblocking(false)?;
+        res
+    }
+
+    /// Serves packets while paused. Returns false when the debugger killed
+    /// the target.
+    fn serve(&mut self, device: &mut Device) -> io::Result<bool> {
+        loop {
+            let packet = read_packet(&mut self.stream)?;
+            // Commands and their arguments are ASCII, which makes slicing
+            // them at any byte safe.
+            let Some(packet) = String::from_utf8(packet).ok().filter(|p| p.is_ascii()) else {
+                self.send("E01")?;
+                continue;
+            };
+            let (command, args) = packet.split_at(packet.len().min(1));
+            match command {
+                "\x03" => self.stop_reply(SIGINT)?,
+                "?" => self.send(&format!("S{:02x}", SIGTRAP))?,
+                "g" => {
+                    let data = read_registers(&device.cpu.regs);
+                    self.send(&data)?;
+                }
+                "G" => match write_registers(&mut device.cpu.regs, args) {
+                    Some(()) => self.send("OK")?,
+                    None => self.send("E01")?,
+                },
+                "p" => match usize::from_str_radix(args, 16) {
+                    Ok(n) if n < REGISTER_COUNT => {
+                        let data = read_register(&device.cpu.regs, n);
+                        self.send(&data)?;
+                    }
+                    _ => self.send("E01")?,
+                },
+                "P" => {
+                    let parsed = args.split_once('=').and_then(|(n, value)| {
+                        let n = usize::from_str_radix(n, 16).ok()?;
+                        let bytes = decode_hex(value)?;
+                        (n < REGISTER_COUNT && bytes.len() == register_width(n))
+                            .then_some((n, bytes))
+                    });
+                    match parsed {
+                        Some((n, bytes)) => {
+                            write_register(&mut device.cpu.regs, n, &bytes);
+                            self.send("OK")?;
+                        }
+                        None => self.send("E01")?,
+                    }
+                }
+                "m" => match parse_range(args) {
+                    Some((addr, len)) => {
+                        let data: String = (0..len.min(MAX_READ))
+                            .map(|i| format!("{:02x}", device.cpu.bus.peek(addr.wrapping_add(i))))
+                            .collect();
+                        self.send(&data)?;
+                    }
+                    None => self.send("E01")?,
+                },
+                "M" => {
+                    let parsed = args.split_once(':').and_then(|(range, data)| {
+                        let (addr, len) = parse_range(range)?;
+                        let bytes = decode_hex(data)?;
+                        (bytes.len() == len as usize).then_some((addr, bytes))
+                    });
+                    match parsed {
+                        Some((addr, bytes)) => {
+                            for (i, byte) in bytes.into_iter().enumerate() {
+                                device.cpu.bus.wb(addr.wrapping_add(i as u16), byte);
+                            }
+                            self.send("OK")?;
+                        }
+                        None => self.send("E01")?,
+                    }
+                }
+                "Z" | "z" => {
+                    let mut parts = args.split(',');
+                    let kind = parts.next();
+                    let addr = parts.next().and_then(|a| u64::from_str_radix(a, 16).ok());
+                    match (kind, addr) {
+                        (Some("0") | Some("1"), Some(addr)) => {
+                            let addr = addr as u16;
+                            if command == "Z" {
+                                if !self.breakpoints.contains(&addr) {
+                                    self.breakpoints.push(addr);
+                                }
+                            } else {
+                                self.breakpoints.retain(|b| *b != addr);
+                            }
+                            self.send("OK")?;
+                        }
+                        _ => self.send("")?,
+                    }
+                }
+                "s" => {
+                    if let Some(addr) = parse_hex(args) {
+                        device.cpu.regs.pc = addr;
+                    }
+                    device.do_cycle();
+                    self.stop_reply(SIGTRAP)?;
+                }
+                "c" => {
+                    if let Some(addr) = parse_hex(args) {
+                        device.cpu.regs.pc = addr;
+                    }
+                    self.state = State::Running;
+                    return Ok(true);
+                }
+                "H" => self.send("OK")?,
+                "D" => {
+                    self.send("OK")?;
+                    info!("GDB detached");
+                    self.state = State::Detached;
+                    return Ok(true);
+                }
+                "k" => return Ok(false),
+                "q" => self.query(args)?,
+                _ => self.send("")?,
+            }
+        }
+    }
+
+    fn query(&mut self, args: &str) -> io::Result<()> {
+        if args.starts_with("Supported") {
+            self.send(&format!(
+                "PacketSize={PACKET_SIZE:x};qXfer:features:read+;swbreak+"
+            ))
+        } else if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let (offset, len) = range
+                .split_once(',')
+                .and_then(|(o, l)| {
+                    Some((
+                        usize::from_str_radix(o, 16).ok()?,
+                        usize::from_str_radix(l, 16).ok()?,
+                    ))
+                })
+                .unwrap_or((0, TARGET_XML.len()));
+            let offset = offset.min(TARGET_XML.len());
+            let end = (offset + len).min(TARGET_XML.len());
+            let prefix = if end == TARGET_XML.len() { "l" } else { "m" };
+            self.send(&format!("{}{}", prefix, &TARGET_XML[offset..end]))
+        } else if args == "Attached" {
+            self.send("1")
+        } else if args == "fThreadInfo" {
+            self.send("m1")
+        } else if args == "sThreadInfo" {
+            self.send("l")
+        } else if args == "C" {
+            self.send("QC1")
+        } else {
+            self.send("")
+        }
+    }
+}
+
+impl DebugInterface for GdbStub {
+    fn update(&mut self, device: &mut Device, budget: u64) -> bool {
+        let res = match self.state {
+            State::Paused => self.serve(device),
+            State::Detached => {
+                let end = device.cycles() + budget;
+                while device.cycles() < end {
+                    device.do_cycle();
+                }
+                Ok(true)
+            }
+            State::Running => {
+                let end = device.cycles() + budget;
+                let mut res = Ok(true);
+                while device.cycles() < end {
+                    device.do_cycle();
+                    if let Some(reply) = self.hit_breakpoint(device) {
+                        res = self.stop(&reply).map(|_| true);
+                        break;
+                    }
+                }
+                // A pending 0x03 byte pauses the target; `serve` consumes it
+                // and sends the stop reply.
+                match self.interrupt_requested() {
+                    Ok(true) => self.state = State::Paused,
+                    Ok(false) => {}
+                    Err(e) => res = Err(e),
+                }
+                res
+            }
+        };
+        match res {
+            Ok(running) => running,
+            Err(e) => {
+                warn!("GDB connection lost: {e}");
+                false
+            }
+        }
+    }
+}
+
+fn register_width(n: usize) -> usize {
+    if n < 8 {
+        1
+    } else {
+        2
+    }
+}
+
+/// Register `n` in hex, little-endian for 16-bit ones.
+fn read_register(regs: &Registers, n: usize) -> String {
+    match n {
+        0 => format!("{:02x}", regs.a),
+        1 => format!("{:02x}", regs.f),
+        2 => format!("{:02x}", regs.b),
+        3 => format!("{:02x}", regs.c),
+        4 => format!("{:02x}", regs.d),
+        5 => format!("{:02x}", regs.e),
+        6 => format!("{:02x}", regs.h),
+        7 => format!("{:02x}", regs.l),
+        8 => format!("{:02x}{:02x}", regs.sp & 0xFF, regs.sp >> 8),
+        _ => format!("{:02x}{:02x}", regs.pc & 0xFF, regs.pc >> 8),
+    }
+}
+
+fn read_registers(regs: &Registers) -> String {
+    (0..REGISTER_COUNT)
+        .map(|n| read_register(regs, n))
+        .collect()
+}
+
+/// Sets register `n` from `register_width(n)` little-endian bytes.
+fn write_register(regs: &mut Registers, n: usize, bytes: &[u8]) {
+    let word = || bytes[0] as u16 | (bytes[1] as u16) << 8;
+    match n {
+        0 => regs.a = bytes[0],
+        1 => regs.f = bytes[0] & 0xF0,
+        2 => regs.b = bytes[0],
+        3 => regs.c = bytes[0],
+        4 => regs.d = bytes[0],
+        5 => regs.e = bytes[0],
+        6 => regs.h = bytes[0],
+        7 => regs.l = bytes[0],
+        8 => regs.sp = word(),
+        _ => regs.pc = word(),
+    }
+}
+
+/// Sets all registers from a `G` packet, leaving them untouched if it is
+/// malformed.
+fn write_registers(regs: &mut Registers, hex: &str) -> Option<()> {
+    let bytes = decode_hex(hex)?;
+    let widths = (0..REGISTER_COUNT).map(register_width);
+    if bytes.len() != widths.clone().sum::<usize>() {
+        return None;
+    }
+    let mut rest = &bytes[..];
+    for (n, width) in widths.enumerate() {
+        write_register(regs, n, &rest[..width]);
+        rest = &rest[width..];
+    }
+    Some(())
+}
+
+/// Decodes pairs of hex digits, failing on anything else.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn encode_packet(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum(data.as_bytes()))
+}
+
+fn read_byte(stream: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    stream.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Reads the next packet, skipping acknowledgements and asking for a
+/// retransmission of corrupt ones. An interrupt request (0x03) is returned
+/// as the packet "\x03". The checksum covers the data as sent, before
+/// unescaping.
+fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Vec<u8>> {
+    loop {
+        match read_byte(stream)? {
+            b'$' => {}
+            0x03 => return Ok(vec![0x03]),
+            _ => continue,
+        }
+        let mut data = Vec::new();
+        let mut sum = 0u8;
+        loop {
+            let byte = read_byte(stream)?;
+            sum = sum.wrapping_add(byte);
+            match byte {
+                b'#' => break,
+                b'}' => {
+                    let escaped = read_byte(stream)?;
+                    sum = sum.wrapping_add(escaped);
+                    data.push(escaped ^ 0x20);
+                }
+                b => data.push(b),
+            }
+        }
+        let sum = sum.wrapping_sub(b'#');
+        let digits = [read_byte(stream)?, read_byte(stream)?];
+        let expected = std::str::from_utf8(&digits)
+            .ok()
+            .and_then(|d| u8::from_str_radix(d, 16).ok());
+        if expected == Some(sum) {
+            stream.write_all(b"+")?;
+            return Ok(data);
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    u64::from_str_radix(s, 16).ok().map(|v| v as u16)
+}
+
+fn parse_range(s: &str) -> Option<(u16, u16)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((
+        parse_hex(addr)?,
+        u64::from_str_radix(len, 16).ok()?.min(0xFFFF) as u16,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Reads from a fixed input and records what is written back.
+    struct Loopback {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Loopback {
+        fn new(input: &[u8]) -> Loopback {
+            Loopback {
+                input: Cursor::new(input.to_vec()),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn packet_framing() {
+        assert_eq!(encode_packet("OK"), "$OK#9a");
+        assert_eq!(encode_packet(""), "$#00");
+
+        let mut stream = Loopback::new(b"+$g#67");
+        assert_eq!(read_packet(&mut stream).unwrap(), b"g");
+        assert_eq!(stream.output, b"+");
+
+        let mut stream = Loopback::new(b"\x03");
+        assert_eq!(read_packet(&mut stream).unwrap(), b"\x03");
+        assert!(stream.output.is_empty());
+    }
+
+    #[test]
+    fn escapes_and_bad_checksums() {
+        // '}' escapes the next byte, which is XORed with 0x20.
+        let mut stream = Loopback::new(b"$M0,1:}\x03#94");
+        assert_eq!(read_packet(&mut stream).unwrap(), b"M0,1:#");
+        assert_eq!(stream.output, b"+");
+
+        // A corrupt packet is nacked and the retransmission read instead of
+        // recursing.
+        let input = [&b"$g#00"[..], b"$g#zz", b"$g#67"].concat();
+        let mut stream = Loopback::new(&input);
+        assert_eq!(read_packet(&mut stream).unwrap(), b"g");
+        assert_eq!(stream.output, b"--+");
+
+        let mut stream = Loopback::new(b"$g#6");
+        assert!(read_packet(&mut stream).is_err());
+    }
+
+    #[test]
+    fn register_encoding() {
+        let mut regs = Registers::new();
+        regs.a = 0x12;
+        regs.f = 0xB0;
+        regs.b = 0x34;
+        regs.sp = 0xFFFE;
+        regs.pc = 0x0150;
+        assert_eq!(read_register(&regs, 0), "12");
+        assert_eq!(read_register(&regs, 8), "feff");
+        assert_eq!(read_register(&regs, 9), "5001");
+        assert_eq!(read_registers(&regs).len(), 2 * (8 + 2 * 2));
+
+        let mut copy = Registers::new();
+        write_registers(&mut copy, &read_registers(&regs)).unwrap();
+        assert_eq!(read_registers(&copy), read_registers(&regs));
+
+        write_register(&mut copy, 1, &[0xFF]);
+        write_register(&mut copy, 9, &[0x00, 0x40]);
+        assert_eq!(copy.f, 0xF0);
+        assert_eq!(copy.pc, 0x4000);
+    }
+
+    #[test]
+    fn malformed_hex() {
+        assert_eq!(decode_hex("00ff7A"), Some(vec![0x00, 0xFF, 0x7A]));
+        assert_eq!(decode_hex("0"), None);
+        assert_eq!(decode_hex("0g"), None);
+        assert_eq!(decode_hex("\u{FFFD}0"), None);
+
+        let mut regs = Registers::new();
+        regs.a = 0x12;
+        let short = &read_registers(&regs)[2..];
+        assert_eq!(write_registers(&mut Registers::new(), short), None);
+        let mut bad = read_registers(&regs);
+        bad.replace_range(0..2, "zz");
+        assert_eq!(write_registers(&mut regs, &bad), None);
+        assert_eq!(regs.a, 0x12);
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("c000,10"), Some((0xC000, 0x10)));
+        assert_eq!(parse_range("c000,100000"), Some((0xC000, 0xFFFF)));
+        assert_eq!(parse_range("c000"), None);
+        assert_eq!(parse_range("c000,x"), None);
+        assert!(encode_packet(&"00".repeat(MAX_READ as usize)).len() <= PACKET_SIZE);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::state::{StateReader, StateWriter};
+use crate::Result;
+
 pub const HRAM_SIZE: usize = 0x7F; // 127B
 pub const HRAM_START: u16 = 0xFF80;
 pub const HRAM_END: u16 = 0xFFFE;
@@ -13,11 +16,19 @@ impl Hram {
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.bytes);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes(&mut self.bytes)
+    }
+
     pub fn rb(&self, a: u16) -> u8 {
-        self.bytes[a as usize]
+        self.bytes[(a - HRAM_START) as usize]
     }
 
     pub fn wb(&mut self, a: u16, v: u8) {
-        self.bytes[a as usize] = v
+        self.bytes[(a - HRAM_START) as usize] = v
     }
 }
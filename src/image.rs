@@ -0,0 +1,17 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use crate::Result;
+
+/// Saves 8-bit gray levels, row by row, as a PNG.
+pub fn save_grayscale_png(path: &str, width: usize, height: usize, pixels: &[u8]) -> Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(std::io::Error::from)?;
+    writer
+        .write_image_data(pixels)
+        .map_err(std::io::Error::from)?;
+    Ok(())
+}
@@ -0,0 +1,222 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::joypad::KeypadKey;
+
+/// Buttons held for a number of frames.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct MacroStep {
+    pub buttons: Vec<KeypadKey>,
+    pub frames: u32,
+}
+
+/// Sequence of button states, one step after the other.
+pub type Macro = Vec<MacroStep>;
+
+/// Counts the host inputs holding each button, such as keys and
+/// controllers, so that a button is released only when the last of them
+/// lets go.
+#[derive(Default)]
+pub struct Holders {
+    counts: HashMap<KeypadKey, usize>,
+}
+
+impl Holders {
+    /// Returns true if nothing held `key` before.
+    pub fn press(&mut self, key: KeypadKey) -> bool {
+        let count = self.counts.entry(key).or_default();
+        *count += 1;
+        *count == 1
+    }
+
+    /// Returns true if that was the last input holding `key`.
+    pub fn release(&mut self, key: KeypadKey) -> bool {
+        match self.counts.get_mut(&key) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => {
+                self.counts.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Buttons pressed on the host, by turbo and by macros, combined into the
+/// buttons the joypad should have on a given frame.
+#[derive(Default)]
+pub(crate) struct Inputs {
+    held: HashSet<KeypadKey>,
+    /// Turbo buttons held, with their rate and the frame they started at.
+    turbo: Vec<(KeypadKey, u32, u64)>,
+    playing: Option<(Macro, u64)>,
+    recording: Option<Macro>,
+}
+
+impl Inputs {
+    pub(crate) fn press(&mut self, key: KeypadKey) {
+        self.held.insert(key);
+    }
+
+    pub(crate) fn release(&mut self, key: KeypadKey) {
+        self.held.remove(&key);
+    }
+
+    /// Starts alternating `key` between `rate` frames pressed and `rate`
+    /// frames released, from `frame` on.
+    pub(crate) fn press_turbo(&mut self, key: KeypadKey, rate: u32, frame: u64) {
+        if !self.turbo.iter().any(|t| t.0 == key) {
+            self.turbo.push((key, rate.max(1), frame));
+        }
+    }
+
+    pub(crate) fn release_turbo(&mut self, key: KeypadKey) {
+        self.turbo.retain(|t| t.0 != key);
+    }
+
+    /// Plays `steps` from `frame` on, replacing the macro playing if any.
+    pub(crate) fn play_macro(&mut self, steps: Macro, frame: u64) {
+        self.playing = Some((steps, frame));
+    }
+
+    pub(crate) fn record_macro(&mut self) {
+        self.recording = Some(Macro::new());
+    }
+
+    pub(crate) fn recording_macro(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub(crate) fn stop_macro_recording(&mut self) -> Option<Macro> {
+        self.recording.take()
+    }
+
+    /// Turbo or a macro needs the buttons updated on every frame.
+    pub(crate) fn animated(&self) -> bool {
+        !self.turbo.is_empty() || self.playing.is_some() || self.recording.is_some()
+    }
+
+    /// Appends the buttons the joypad had during a frame to the macro being
+    /// recorded.
+    pub(crate) fn sample(&mut self, pressed: impl Fn(KeypadKey) -> bool) {
+        let Some(recording) = &mut self.recording else {
+            return;
+        };
+        let buttons: Vec<KeypadKey> = KeypadKey::ALL.into_iter().filter(|&k| pressed(k)).collect();
+        match recording.last_mut() {
+            Some(step) if step.buttons == buttons => step.frames += 1,
+            _ => recording.push(MacroStep { buttons, frames: 1 }),
+        }
+    }
+
+    /// Buttons that should be pressed on `frame`.
+    pub(crate) fn buttons(&mut self, frame: u64) -> HashSet<KeypadKey> {
+        let mut buttons = self.held.clone();
+        for &(key, rate, start) in &self.turbo {
+            if (frame.saturating_sub(start) / rate as u64).is_multiple_of(2) {
+                buttons.insert(key);
+            }
+        }
+        if let Some((steps, start)) = &self.playing {
+            let mut elapsed = frame.saturating_sub(*start);
+            let step = steps.iter().find(|step| {
+                let current = elapsed < step.frames as u64;
+                elapsed = elapsed.saturating_sub(step.frames as u64);
+                current
+            });
+            match step {
+                Some(step) => buttons.extend(&step.buttons),
+                None => self.playing = None,
+            }
+        }
+        buttons
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pressed(inputs: &mut Inputs, frames: std::ops::Range<u64>, key: KeypadKey) -> Vec<bool> {
+        frames.map(|f| inputs.buttons(f).contains(&key)).collect()
+    }
+
+    #[test]
+    fn holders() {
+        let mut holders = Holders::default();
+        assert!(holders.press(KeypadKey::A));
+        assert!(!holders.press(KeypadKey::A));
+        assert!(holders.press(KeypadKey::B));
+        assert!(!holders.release(KeypadKey::A));
+        assert!(holders.release(KeypadKey::A));
+        assert!(!holders.release(KeypadKey::A));
+        assert!(holders.press(KeypadKey::A));
+    }
+
+    #[test]
+    fn turbo_alternates_at_rate() {
+        let mut inputs = Inputs::default();
+        inputs.press_turbo(KeypadKey::A, 2, 10);
+        let a = pressed(&mut inputs, 10..17, KeypadKey::A);
+        assert_eq!(a, [true, true, false, false, true, true, false]);
+        inputs.release_turbo(KeypadKey::A);
+        assert!(!inputs.buttons(17).contains(&KeypadKey::A));
+        assert!(!inputs.animated());
+    }
+
+    #[test]
+    fn held_button_wins_over_turbo() {
+        let mut inputs = Inputs::default();
+        inputs.press_turbo(KeypadKey::B, 1, 0);
+        inputs.press(KeypadKey::B);
+        assert_eq!(pressed(&mut inputs, 0..3, KeypadKey::B), [true; 3]);
+    }
+
+    #[test]
+    fn macro_steps_through_frames() {
+        let mut inputs = Inputs::default();
+        let steps = vec![
+            MacroStep {
+                buttons: vec![KeypadKey::Down],
+                frames: 2,
+            },
+            MacroStep {
+                buttons: vec![],
+                frames: 1,
+            },
+            MacroStep {
+                buttons: vec![KeypadKey::Down, KeypadKey::A],
+                frames: 1,
+            },
+        ];
+        inputs.play_macro(steps, 5);
+        let down = pressed(&mut inputs, 5..10, KeypadKey::Down);
+        assert_eq!(down, [true, true, false, true, false]);
+        assert!(!inputs.animated());
+    }
+
+    #[test]
+    fn recording_merges_identical_frames() {
+        let mut inputs = Inputs::default();
+        inputs.record_macro();
+        inputs.sample(|_| false);
+        inputs.sample(|k| k == KeypadKey::Start);
+        inputs.sample(|k| k == KeypadKey::Start);
+        let steps = inputs.stop_macro_recording().unwrap();
+        let expected = [
+            MacroStep {
+                buttons: vec![],
+                frames: 1,
+            },
+            MacroStep {
+                buttons: vec![KeypadKey::Start],
+                frames: 2,
+            },
+        ];
+        assert_eq!(steps, expected);
+    }
+}
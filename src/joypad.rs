@@ -1,18 +1,42 @@
+use serde::{Deserialize, Serialize};
+
+use crate::state::{StateReader, StateWriter};
+use crate::Result;
+
+/// SGB command that enables reading several joypads.
+const MLT_REQ: u8 = 0x11;
+const SGB_PACKET_BITS: usize = 128;
+
+/// JOYP (0xFF00). Writing 0 to P14 (bit 4) selects the directions and to
+/// P15 (bit 5) the buttons; pressed keys of the selected groups pull their
+/// line in bits 0-3 low.
+#[derive(Default)]
 pub struct Joypad {
-    action: bool,
-    direction: bool,
-    up: bool,
-    down: bool,
-    right: bool,
-    left: bool,
-    a: bool,
-    b: bool,
-    start: bool,
-    select: bool,
+    /// P14 and P15 as last written.
+    select: u8,
+    /// Pressed keys, one bit per line: Right, Left, Up and Down.
+    directions: u8,
+    /// Pressed keys, one bit per line: A, B, Select and Start.
+    actions: u8,
+    sgb: Option<Sgb>,
     pub interrupt: u8,
 }
 
-#[derive(Clone, Copy)]
+/// Super Game Boy side of JOYP: command packets are sent by pulsing P14
+/// and P15, and once MLT_REQ is enabled, deselecting both reads the
+/// current joypad number.
+#[derive(Default)]
+struct Sgb {
+    packet: [u8; SGB_PACKET_BITS / 8],
+    /// Next bit of the packet being received, if any.
+    bit: Option<usize>,
+    /// Both lines went back high since the last pulse.
+    ready: bool,
+    players: u8,
+    player: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum KeypadKey {
     Right,
     Left,
@@ -24,111 +48,415 @@ pub enum KeypadKey {
     Start,
 }
 
+impl KeypadKey {
+    pub const ALL: [KeypadKey; 8] = [
+        KeypadKey::Right,
+        KeypadKey::Left,
+        KeypadKey::Up,
+        KeypadKey::Down,
+        KeypadKey::A,
+        KeypadKey::B,
+        KeypadKey::Select,
+        KeypadKey::Start,
+    ];
+
+    /// Group select bit and input line of the key.
+    fn line(self) -> (u8, u8) {
+        match self {
+            KeypadKey::Right => (0x10, 0x01),
+            KeypadKey::Left => (0x10, 0x02),
+            KeypadKey::Up => (0x10, 0x04),
+            KeypadKey::Down => (0x10, 0x08),
+            KeypadKey::A => (0x20, 0x01),
+            KeypadKey::B => (0x20, 0x02),
+            KeypadKey::Select => (0x20, 0x04),
+            KeypadKey::Start => (0x20, 0x08),
+        }
+    }
+}
+
 impl Joypad {
     pub fn new() -> Joypad {
-        Joypad {
-            action: false,
-            direction: false,
-            up: false,
-            down: false,
-            right: false,
-            left: false,
-            a: false,
-            b: false,
-            start: false,
-            select: false,
-            interrupt: 0,
+        Joypad::default()
+    }
+
+    /// Emulates the Super Game Boy's multiplayer adapter.
+    pub fn set_sgb(&mut self, enabled: bool) {
+        self.sgb = enabled.then(|| Sgb {
+            players: 1,
+            ..Sgb::default()
+        });
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.select);
+        w.u8(self.directions);
+        w.u8(self.actions);
+        w.bool(self.sgb.is_some());
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(w);
         }
     }
 
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.select = r.u8()?;
+        self.directions = r.u8()?;
+        self.actions = r.u8()?;
+        self.sgb = if r.bool()? {
+            Some(Sgb::load_state(r)?)
+        } else {
+            None
+        };
+        Ok(())
+    }
+
     pub fn rb(&self) -> u8 {
-        let mut res = 0xC0;
+        let player = self.sgb.as_ref().map_or(0, |sgb| sgb.player);
+        let mut lines = 0x0F;
+        if self.select == 0x30 {
+            lines -= player;
+        } else if player == 0 {
+            if self.select & 0x10 == 0 {
+                lines &= !self.directions;
+            }
+            if self.select & 0x20 == 0 {
+                lines &= !self.actions;
+            }
+        }
+        0xC0 | self.select | lines
+    }
+
+    pub fn wb(&mut self, v: u8) {
+        let lines = self.rb();
+        let previous = self.select;
+        self.select = v & 0x30;
+        if let Some(sgb) = &mut self.sgb {
+            sgb.write(previous, self.select);
+        }
+        self.update_interrupt(lines);
+    }
 
-        if self.action {
-            res |= 0x20;
+    pub fn press_button(&mut self, button: KeypadKey) {
+        let lines = self.rb();
+        match button.line() {
+            (0x10, line) => self.directions |= line,
+            (_, line) => self.actions |= line,
         }
-        if self.direction {
-            res |= 0x10;
+        self.update_interrupt(lines);
+    }
+
+    pub fn is_pressed(&self, button: KeypadKey) -> bool {
+        match button.line() {
+            (0x10, line) => self.directions & line != 0,
+            (_, line) => self.actions & line != 0,
         }
+    }
 
-        if !self.direction {
-            if self.right {
-                res &= !0x01;
-            }
-            if self.left {
-                res &= !0x02;
-            }
-            if self.up {
-                res &= !0x04;
-            }
-            if self.down {
-                res &= !0x08;
-            }
+    pub fn release_button(&mut self, button: KeypadKey) {
+        let lines = self.rb();
+        match button.line() {
+            (0x10, line) => self.directions &= !line,
+            (_, line) => self.actions &= !line,
         }
+        self.update_interrupt(lines);
+    }
 
-        if !self.action {
-            if self.a {
-                res &= !0x01;
-            }
-            if self.b {
-                res &= !0x02;
+    /// Requests the joypad interrupt if any input line went from high to
+    /// low since it read `lines`.
+    fn update_interrupt(&mut self, lines: u8) {
+        if lines & !self.rb() & 0x0F != 0 {
+            self.interrupt |= 0x10;
+        }
+    }
+}
+
+impl Sgb {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.packet);
+        w.bool(self.bit.is_some());
+        w.u8(self.bit.unwrap_or(0) as u8);
+        w.bool(self.ready);
+        w.u8(self.players);
+        w.u8(self.player);
+    }
+
+    fn load_state(r: &mut StateReader) -> Result<Sgb> {
+        let mut sgb = Sgb::default();
+        r.bytes(&mut sgb.packet)?;
+        let receiving = r.bool()?;
+        let bit = r.u8()? as usize;
+        sgb.bit = receiving.then_some(bit.min(SGB_PACKET_BITS));
+        sgb.ready = r.bool()?;
+        sgb.players = r.u8()?.clamp(1, 4);
+        sgb.player = r.u8()? % sgb.players;
+        Ok(sgb)
+    }
+
+    /// Both lines low starts a packet, then P14 low sends a 0 bit and P15
+    /// low a 1, each followed by both lines high. A 0 stop bit follows the
+    /// 128 bits of the packet.
+    fn write(&mut self, previous: u8, select: u8) {
+        match select {
+            0x00 => {
+                self.packet = [0; SGB_PACKET_BITS / 8];
+                self.bit = Some(0);
+                self.ready = false;
             }
-            if self.select {
-                res &= !0x04;
+            0x10 | 0x20 if self.ready => {
+                self.ready = false;
+                let Some(bit) = self.bit else {
+                    return;
+                };
+                let one = select == 0x10;
+                if bit == SGB_PACKET_BITS {
+                    self.bit = None;
+                    if !one {
+                        self.run_command();
+                    }
+                } else {
+                    if one {
+                        self.packet[bit / 8] |= 1 << (bit % 8);
+                    }
+                    self.bit = Some(bit + 1);
+                }
             }
-            if self.start {
-                res &= !0x08;
+            0x30 => {
+                self.ready = true;
+                // The next joypad is selected when P15 goes high outside
+                // of a packet.
+                if previous == 0x10 && self.bit.is_none() {
+                    self.player = (self.player + 1) % self.players;
+                }
             }
+            _ => (),
         }
-
-        res
     }
 
-    pub fn wb(&mut self, v: u8) {
-        self.action = v & 0x20 != 0;
-        self.direction = v & 0x10 != 0;
+    fn run_command(&mut self) {
+        if self.packet[0] >> 3 == MLT_REQ {
+            self.players = match self.packet[1] & 0x03 {
+                0x01 => 2,
+                0x03 => 4,
+                _ => 1,
+            };
+            self.player = 0;
+        }
     }
+}
 
-    pub fn press_button(&mut self, button: KeypadKey) {
-        let was_pressed = self.any_button_pressed();
-
-        match button {
-            KeypadKey::Right => self.right = true,
-            KeypadKey::Left => self.left = true,
-            KeypadKey::Up => self.up = true,
-            KeypadKey::Down => self.down = true,
-            KeypadKey::A => self.a = true,
-            KeypadKey::B => self.b = true,
-            KeypadKey::Start => self.start = true,
-            KeypadKey::Select => self.select = true,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::Mmu;
+    use crate::rom::Rom;
 
-        if !was_pressed {
-            self.interrupt |= 0x10
+    fn selected(select: u8, pressed: &[KeypadKey]) -> Joypad {
+        let mut joypad = Joypad::new();
+        joypad.wb(select);
+        for &key in pressed {
+            joypad.press_button(key);
         }
+        joypad.interrupt = 0;
+        joypad
     }
 
-    pub fn release_button(&mut self, button: KeypadKey) {
-        match button {
-            KeypadKey::Right => self.right = false,
-            KeypadKey::Left => self.left = false,
-            KeypadKey::Up => self.up = false,
-            KeypadKey::Down => self.down = false,
-            KeypadKey::A => self.a = false,
-            KeypadKey::B => self.b = false,
-            KeypadKey::Start => self.start = false,
-            KeypadKey::Select => self.select = false,
+    #[test]
+    fn no_group_selected_reads_all_high() {
+        let joypad = selected(0x30, &[KeypadKey::Right, KeypadKey::A]);
+        assert_eq!(joypad.rb(), 0xFF);
+    }
+
+    #[test]
+    fn directions_selected() {
+        let joypad = selected(0x20, &[KeypadKey::Right, KeypadKey::Down, KeypadKey::A]);
+        assert_eq!(joypad.rb(), 0xE6);
+    }
+
+    #[test]
+    fn actions_selected() {
+        let joypad = selected(0x10, &[KeypadKey::Right, KeypadKey::B, KeypadKey::Start]);
+        assert_eq!(joypad.rb(), 0xD5);
+    }
+
+    #[test]
+    fn both_groups_selected() {
+        let joypad = selected(0x00, &[KeypadKey::Right, KeypadKey::B]);
+        assert_eq!(joypad.rb(), 0xCC);
+        let joypad = selected(0x00, &[KeypadKey::Up, KeypadKey::Select]);
+        assert_eq!(joypad.rb(), 0xCB);
+    }
+
+    #[test]
+    fn unused_bits_read_high() {
+        let mut joypad = Joypad::new();
+        joypad.wb(0x0F);
+        assert_eq!(joypad.rb(), 0xCF);
+        joypad.wb(0xFF);
+        assert_eq!(joypad.rb(), 0xFF);
+    }
+
+    #[test]
+    fn release() {
+        let mut joypad = selected(0x20, &[KeypadKey::Left, KeypadKey::Up]);
+        joypad.release_button(KeypadKey::Left);
+        assert_eq!(joypad.rb(), 0xEB);
+        assert_eq!(joypad.interrupt, 0);
+    }
+
+    #[test]
+    fn interrupt_on_press_of_selected_line() {
+        let mut joypad = selected(0x10, &[]);
+        joypad.press_button(KeypadKey::A);
+        assert_eq!(joypad.interrupt, 0x10);
+    }
+
+    #[test]
+    fn interrupt_with_another_button_held() {
+        let mut joypad = selected(0x10, &[KeypadKey::A]);
+        joypad.press_button(KeypadKey::Start);
+        assert_eq!(joypad.interrupt, 0x10);
+    }
+
+    #[test]
+    fn no_interrupt_for_unselected_group() {
+        let mut joypad = selected(0x20, &[]);
+        joypad.press_button(KeypadKey::A);
+        assert_eq!(joypad.interrupt, 0);
+    }
+
+    #[test]
+    fn no_interrupt_when_line_already_low() {
+        let mut joypad = selected(0x00, &[KeypadKey::Right]);
+        joypad.press_button(KeypadKey::A);
+        assert_eq!(joypad.interrupt, 0);
+    }
+
+    #[test]
+    fn interrupt_when_selecting_held_button() {
+        let mut joypad = selected(0x30, &[KeypadKey::Down]);
+        joypad.wb(0x20);
+        assert_eq!(joypad.interrupt, 0x10);
+    }
+
+    fn send_packet(joypad: &mut Joypad, packet: &[u8; 16]) {
+        joypad.wb(0x00);
+        joypad.wb(0x30);
+        for i in 0..SGB_PACKET_BITS {
+            let one = packet[i / 8] & (1 << (i % 8)) != 0;
+            joypad.wb(if one { 0x10 } else { 0x20 });
+            joypad.wb(0x30);
         }
+        joypad.wb(0x20);
+        joypad.wb(0x30);
+    }
+
+    fn mlt_req(players: u8) -> [u8; 16] {
+        let mut packet = [0; 16];
+        packet[0] = MLT_REQ << 3 | 1;
+        packet[1] = players;
+        packet
+    }
+
+    /// Joypad number read with both groups deselected, after each
+    /// P15 pulse.
+    fn players(joypad: &mut Joypad, reads: usize) -> Vec<u8> {
+        (0..reads)
+            .map(|_| {
+                joypad.wb(0x10);
+                joypad.wb(0x30);
+                joypad.rb()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sgb_two_players() {
+        let mut joypad = selected(0x30, &[]);
+        joypad.set_sgb(true);
+        send_packet(&mut joypad, &mlt_req(0x01));
+        assert_eq!(joypad.rb(), 0xFF);
+        assert_eq!(players(&mut joypad, 3), [0xFE, 0xFF, 0xFE]);
+    }
+
+    #[test]
+    fn sgb_four_players() {
+        let mut joypad = selected(0x30, &[]);
+        joypad.set_sgb(true);
+        send_packet(&mut joypad, &mlt_req(0x03));
+        assert_eq!(players(&mut joypad, 4), [0xFE, 0xFD, 0xFC, 0xFF]);
+    }
+
+    #[test]
+    fn sgb_other_players_read_no_buttons() {
+        let mut joypad = selected(0x30, &[KeypadKey::A]);
+        joypad.set_sgb(true);
+        send_packet(&mut joypad, &mlt_req(0x01));
+        joypad.wb(0x10);
+        assert_eq!(joypad.rb(), 0xDE);
+        joypad.wb(0x30);
+        joypad.wb(0x10);
+        assert_eq!(joypad.rb(), 0xDF);
+    }
+
+    #[test]
+    fn sgb_single_player_again() {
+        let mut joypad = selected(0x30, &[]);
+        joypad.set_sgb(true);
+        send_packet(&mut joypad, &mlt_req(0x01));
+        send_packet(&mut joypad, &mlt_req(0x00));
+        assert_eq!(players(&mut joypad, 2), [0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn sgb_state() {
+        let mut joypad = selected(0x30, &[]);
+        joypad.set_sgb(true);
+        send_packet(&mut joypad, &mlt_req(0x03));
+        players(&mut joypad, 2);
+        // Stop halfway through the next packet.
+        joypad.wb(0x00);
+        joypad.wb(0x30);
+        joypad.wb(0x10);
+
+        let mut w = StateWriter::new();
+        joypad.save_state(&mut w);
+        let state = w.finish();
+        let mut restored = Joypad::new();
+        restored
+            .load_state(&mut StateReader::new(&state).unwrap())
+            .unwrap();
+        let sgb = restored.sgb.as_ref().unwrap();
+        assert_eq!((sgb.players, sgb.player, sgb.bit), (4, 2, Some(1)));
+        assert_eq!(sgb.packet[0], 0x01);
+        assert!(!sgb.ready);
+
+        let mut w = StateWriter::new();
+        Joypad::new().save_state(&mut w);
+        let state = w.finish();
+        restored
+            .load_state(&mut StateReader::new(&state).unwrap())
+            .unwrap();
+        assert!(restored.sgb.is_none());
+    }
+
+    #[test]
+    fn sgb_from_cartridge_header() {
+        let mut image = vec![0; 0x8000];
+        image[0x146] = 0x03;
+        let mut mmu = Mmu::new(Rom::from_bytes(image.clone()));
+        assert!(mmu.joypad.sgb.is_some());
+        mmu.reset();
+        assert!(mmu.joypad.sgb.is_some());
+
+        image[0x146] = 0x00;
+        let mmu = Mmu::new(Rom::from_bytes(image));
+        assert!(mmu.joypad.sgb.is_none());
     }
 
-    fn any_button_pressed(&self) -> bool {
-        self.right
-            || self.left
-            || self.up
-            || self.down
-            || self.a
-            || self.b
-            || self.start
-            || self.select
+    #[test]
+    fn packets_ignored_without_sgb() {
+        let mut joypad = selected(0x30, &[]);
+        send_packet(&mut joypad, &mlt_req(0x01));
+        assert_eq!(players(&mut joypad, 2), [0xFF, 0xFF]);
     }
 }
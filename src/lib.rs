@@ -1,13 +1,34 @@
+pub mod bus;
+pub mod cdl;
+pub mod config;
+pub mod debugger;
 pub mod device;
+pub mod disasm;
+pub mod gamepad;
+pub mod gdb;
+pub mod input;
+pub mod joypad;
+pub mod link;
+pub mod movie;
+pub mod pacing;
+pub mod printer;
+pub mod profiler;
+pub mod rewind;
+pub mod serial;
+pub mod symbols;
+pub mod trace;
+pub mod watch;
 pub mod window;
 pub use crate::error::{EmulatorError, Result};
-mod cpu;
+pub mod cpu;
 mod error;
 mod hram;
-mod joypad;
-mod mmu;
+mod image;
+pub mod mmu;
 mod ppu;
-mod registers;
+pub mod registers;
+mod render;
 mod rom;
+pub mod state;
 mod timer;
 mod wram;
@@ -0,0 +1,280 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use log::{info, warn};
+
+use crate::serial::{SerialDevice, TRANSFER_CYCLES};
+use crate::Result;
+
+/// T-cycles between synchronization points. Neither side runs more than
+/// this far ahead of the other, and it must not exceed the transfer time
+/// for a transfer to reach the partner before it completes.
+const SYNC_CYCLES: u64 = TRANSFER_CYCLES as u64;
+
+const SYNC: u8 = 0;
+const START: u8 = 1;
+const REPLY: u8 = 2;
+
+/// Transfer clocked by the partner, completing at cycle `done`.
+struct Transfer {
+    byte: u8,
+    done: u64,
+}
+
+/// Link cable to another instance over TCP.
+///
+/// Both sides count T-cycles from the moment they connect and exchange
+/// their clocks every `SYNC_CYCLES`, waiting for each other. A transfer is
+/// announced with its completion cycle when it starts, and the partner
+/// answers with its SB when its own clock reaches that cycle, so both sides
+/// see the same bytes at the same cycle whatever the network latency. A
+/// partner not waiting on an external clock answers 0xFF and receives
+/// nothing.
+///
+/// A transfer shorter than `SYNC_CYCLES`, such as a fast CGB one, reaches
+/// the partner only at its next synchronization point. The initiator lets
+/// the partner get there while it waits for the reply, and the partner
+/// answers as soon as it reads the announcement.
+pub struct LinkCable {
+    stream: Option<TcpStream>,
+    cycles: u64,
+    next_sync: u64,
+    /// Last synchronization point announced to the partner.
+    announced: u64,
+    partner_cycles: u64,
+    incoming: VecDeque<Transfer>,
+    reply: Option<u8>,
+}
+
+impl LinkCable {
+    /// Waits on `port` until the other instance connects.
+    pub fn host(port: u16) -> Result<LinkCable> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        info!("Waiting for link cable connection on port {port} ...");
+        let (stream, addr) = listener.accept()?;
+        info!("Link cable connected from {addr}");
+        LinkCable::new(stream)
+    }
+
+    /// Connects to an instance waiting with `host`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<LinkCable> {
+        let stream = TcpStream::connect(addr)?;
+        info!("Link cable connected to {}", stream.peer_addr()?);
+        LinkCable::new(stream)
+    }
+
+    fn new(stream: TcpStream) -> Result<LinkCable> {
+        stream.set_nodelay(true)?;
+        Ok(LinkCable {
+            stream: Some(stream),
+            cycles: 0,
+            next_sync: SYNC_CYCLES,
+            announced: 0,
+            partner_cycles: 0,
+            incoming: VecDeque::new(),
+            reply: None,
+        })
+    }
+
+    pub fn connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Messages are a tag, a byte and a little-endian cycle count.
+    fn send(&mut self, tag: u8, byte: u8, cycles: u64) -> io::Result<()> {
+        let mut message = [0; 10];
+        message[0] = tag;
+        message[1] = byte;
+        message[2..].copy_from_slice(&cycles.to_le_bytes());
+        match &mut self.stream {
+            Some(stream) => stream.write_all(&message),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        let mut message = [0; 10];
+        match &mut self.stream {
+            Some(stream) => stream.read_exact(&mut message)?,
+            None => return Err(io::ErrorKind::NotConnected.into()),
+        }
+        let byte = message[1];
+        let cycles = u64::from_le_bytes(message[2..].try_into().unwrap());
+        match message[0] {
+            SYNC => self.partner_cycles = cycles,
+            START => self.incoming.push_back(Transfer { byte, done: cycles }),
+            REPLY => self.reply = Some(byte),
+            tag => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown link message {tag:#04x}"),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Unplugs the cable, leaving the port as if nothing were connected.
+    fn lost(&mut self, e: io::Error) {
+        warn!("Link cable connection lost: {e}");
+        self.stream = None;
+        self.incoming.clear();
+    }
+
+    /// Tells the partner it may run up to the next synchronization point.
+    fn announce(&mut self) -> io::Result<()> {
+        if self.announced < self.next_sync {
+            self.send(SYNC, 0, self.next_sync)?;
+            self.announced = self.next_sync;
+        }
+        Ok(())
+    }
+
+    /// Advances to `self.cycles`, completing a transfer clocked by the
+    /// partner if this side listens with `byte` in SB. Otherwise the partner
+    /// reads 0xFF, as from an unplugged port.
+    fn clock(&mut self, byte: Option<u8>) -> io::Result<Option<u8>> {
+        if self.cycles >= self.next_sync {
+            self.announce()?;
+            while self.partner_cycles < self.next_sync {
+                self.receive()?;
+            }
+            self.next_sync += SYNC_CYCLES;
+        }
+        if self.incoming.front().is_some_and(|t| t.done <= self.cycles) {
+            let transfer = self.incoming.pop_front().unwrap();
+            self.send(REPLY, byte.unwrap_or(0xFF), self.cycles)?;
+            return Ok(byte.map(|_| transfer.byte));
+        }
+        Ok(None)
+    }
+
+    /// Waits for the partner to answer a transfer clocked by this side,
+    /// answering with `byte` any it clocks meanwhile.
+    fn wait_reply(&mut self, byte: u8) -> io::Result<u8> {
+        // The partner may be waiting for this side at a synchronization
+        // point that it will not reach before the reply.
+        self.announce()?;
+        loop {
+            if let Some(reply) = self.reply.take() {
+                return Ok(reply);
+            }
+            if self.incoming.pop_front().is_some() {
+                self.send(REPLY, byte, self.cycles)?;
+            }
+            self.receive()?;
+        }
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn start(&mut self, byte: u8, cycles: u32) {
+        if self.stream.is_none() {
+            return;
+        }
+        if let Err(e) = self.send(START, byte, self.cycles + cycles as u64) {
+            self.lost(e);
+        }
+    }
+
+    fn exchange(&mut self, byte: u8) -> u8 {
+        if self.stream.is_none() {
+            return 0xFF;
+        }
+        self.wait_reply(byte).unwrap_or_else(|e| {
+            self.lost(e);
+            0xFF
+        })
+    }
+
+    fn poll(&mut self, ticks: u32, byte: Option<u8>) -> Option<u8> {
+        self.stream.as_ref()?;
+        self.cycles += ticks as u64;
+        self.clock(byte).unwrap_or_else(|e| {
+            self.lost(e);
+            None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::Serial;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Plugs two cables into each other over the loopback interface. A
+    /// deadlock shows up as a timeout, which unplugs the cable.
+    fn cables() -> (LinkCable, LinkCable) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || TcpStream::connect(addr).unwrap());
+        let (host, _) = listener.accept().unwrap();
+        let client = client.join().unwrap();
+        for stream in [&host, &client] {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+        }
+        (
+            LinkCable::new(host).unwrap(),
+            LinkCable::new(client).unwrap(),
+        )
+    }
+
+    /// Runs a Game Boy for `cycles` T-cycles with `sb` in SB, writing `sc`
+    /// to SC once `start` cycles have passed. Returns SB, SC and the
+    /// interrupt flags at the end.
+    fn run(cable: LinkCable, sb: u8, sc: u8, start: u64, cycles: u64) -> (u8, u8, u8) {
+        let mut serial = Serial::new();
+        serial.connect(Box::new(cable));
+        serial.wb(0xFF01, sb);
+        for cycle in (0..cycles).step_by(4) {
+            if cycle == start {
+                serial.wb(0xFF02, sc);
+            }
+            serial.do_cycle(4);
+        }
+        (serial.rb(0xFF01), serial.rb(0xFF02), serial.interrupt)
+    }
+
+    fn transfer(sc: u8, partner_sc: u8) -> ((u8, u8, u8), (u8, u8, u8)) {
+        let (host, client) = cables();
+        let cycles = 4 * SYNC_CYCLES;
+        // Start halfway between two synchronization points.
+        let start = SYNC_CYCLES + SYNC_CYCLES / 2;
+        let initiator = thread::spawn(move || run(host, 0x12, sc, start, cycles));
+        let partner = run(client, 0x34, partner_sc, start, cycles);
+        (initiator.join().unwrap(), partner)
+    }
+
+    #[test]
+    fn normal_speed_transfer() {
+        let (initiator, partner) = transfer(0x81, 0x80);
+        assert_eq!(initiator, (0x34, 0x7D, 0x08));
+        assert_eq!(partner, (0x12, 0x7C, 0x08));
+    }
+
+    #[test]
+    fn fast_transfer() {
+        let (initiator, partner) = transfer(0x83, 0x80);
+        assert_eq!(initiator, (0x34, 0x7F, 0x08));
+        assert_eq!(partner, (0x12, 0x7C, 0x08));
+    }
+
+    #[test]
+    fn both_sides_clocking() {
+        let (host, client) = transfer(0x83, 0x83);
+        assert_eq!(host, (0x34, 0x7F, 0x08));
+        assert_eq!(client, (0x12, 0x7F, 0x08));
+    }
+
+    #[test]
+    fn partner_not_listening() {
+        let (initiator, partner) = transfer(0x81, 0x00);
+        assert_eq!(initiator, (0xFF, 0x7D, 0x08));
+        assert_eq!(partner, (0x34, 0x7C, 0x00));
+    }
+}
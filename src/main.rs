@@ -1,31 +1,252 @@
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError, TrySendError};
 
 use anyhow::{anyhow, Error};
-use clap::Parser;
-use log::info;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use log::{info, warn};
 use rekop_gbc::{
-    device::Device,
-    window::{App, GBEvent},
+    config::{Config, Hotkey, Rewind, Speed},
+    debugger::{DebugInterface, Debugger},
+    device::{Device, CYCLES_PER_FRAME},
+    disasm,
+    gdb::GdbStub,
+    input::Macro,
+    link::LinkCable,
+    movie::Movie,
+    pacing::{FramePacer, SpeedMeter, FRAME_RATE},
+    printer::Printer,
+    rewind::RewindBuffer,
+    trace,
+    window::{App, Frame, GBEvent, Status},
 };
 use winit::event_loop::{self, EventLoop};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(name = "rekop-gbc")]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
-    rom: String,
+    #[command(subcommand)]
+    command: Option<Command>,
 
+    #[arg(required = true)]
+    rom: Option<String>,
+
+    #[command(flatten)]
+    options: Options,
+}
+
+#[derive(clap::Args)]
+struct Options {
     #[arg(short, long, value_name = "FILE")]
     save_state: Option<String>,
 
     #[arg(short, long)]
     debug: bool,
+
+    /// Log every executed instruction in Gameboy Doctor format, with LY
+    /// reading 0x90 as Gameboy Doctor expects
+    #[arg(long, value_name = "FILE")]
+    trace: Option<String>,
+
+    /// Name the PC of each trace line from the ROM's .sym file
+    #[arg(long, requires = "trace")]
+    trace_symbols: bool,
+
+    /// Write flat, call-tree and folded-stack profiles to PREFIX.* on exit
+    #[arg(long, value_name = "PREFIX")]
+    profile: Option<String>,
+
+    /// Mark ROM bytes as code or data in ROM.cdl, which disasm reads back
+    #[arg(long)]
+    cdl: bool,
+
+    /// Save the bytes sent over the link port on exit
+    #[arg(long, value_name = "FILE")]
+    serial_out: Option<String>,
+
+    /// Wait for another instance to plug a link cable in on this TCP port
+    #[arg(long, value_name = "PORT")]
+    link_host: Option<u16>,
+
+    /// Plug a link cable into an instance waiting at ADDR (HOST:PORT)
+    #[arg(long, value_name = "ADDR", conflicts_with = "link_host")]
+    link_connect: Option<String>,
+
+    /// Plug a Game Boy Printer in, saving each page to PREFIX-N.png
+    #[arg(long, value_name = "PREFIX", conflicts_with_all = ["link_host", "link_connect"])]
+    printer: Option<String>,
+
+    /// Key bindings file, created with the defaults if missing
+    /// [default: rekop-gbc/config.toml in the user configuration directory]
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Record the buttons pressed to a movie, saved on exit
+    #[arg(long, value_name = "FILE")]
+    record_movie: Option<String>,
+
+    /// Start the movie from this save state instead of power-on
+    #[arg(long, value_name = "FILE", requires = "record_movie")]
+    movie_start: Option<String>,
+
+    /// Replay a movie, checking that the emulation matches the recording
+    #[arg(long, value_name = "FILE", conflicts_with = "record_movie")]
+    play_movie: Option<String>,
+
+    /// Start paused in the interactive debugger
+    #[arg(long)]
+    debugger: bool,
+
+    /// Wait for a GDB remote connection on this local TCP port
+    #[arg(long, value_name = "PORT", conflicts_with = "debugger")]
+    gdb: Option<u16>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Disassemble part of a ROM image to RGBDS source, using ROM.sym and
+    /// ROM.cdl when present
+    Disasm {
+        rom: String,
+
+        /// ROM bank mapped at 0x4000-0x7FFF
+        #[arg(short, long, default_value_t = 1)]
+        bank: usize,
+
+        #[arg(long, value_parser = parse_address, default_value = "0x0000")]
+        from: u16,
+
+        /// End address (exclusive)
+        #[arg(long, value_parser = parse_address, default_value = "0x8000")]
+        to: u16,
+    },
+    /// Report the first divergence between a trace and a reference log
+    TraceDiff { trace: String, reference: String },
+    /// Run a ROM for a number of frames and save the final state
+    Run {
+        rom: String,
+
+        #[command(flatten)]
+        options: Box<Options>,
+
+        /// Run without a window, as fast as possible. Needs --frames unless a
+        /// movie or a debugger ends the run
+        #[arg(long)]
+        headless: bool,
+
+        /// Stop after this many frames, or at the end of the movie played
+        #[arg(long, value_name = "N")]
+        frames: Option<u64>,
+
+        /// Save the last rendered frame as a PNG on exit
+        #[arg(long, value_name = "FILE")]
+        screenshot: Option<String>,
+
+        /// Save work RAM (0xC000-0xDFFF) on exit
+        #[arg(long, value_name = "FILE")]
+        dump_ram: Option<String>,
+    },
+}
+
+fn parse_address(s: &str) -> Result<u16, String> {
+    let res = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix('$')) {
+        u16::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    res.map_err(|e| format!("invalid address '{s}': {e}"))
 }
 
 fn main() -> Result<(), Error> {
     let args = Args::parse();
 
-    if args.debug {
+    match args.command {
+        Some(Command::Disasm {
+            rom,
+            bank,
+            from,
+            to,
+        }) => {
+            print!("{}", disasm::disassemble_file(&rom, bank, from, to)?);
+        }
+        Some(Command::TraceDiff { trace, reference }) => {
+            match trace::diff_files(&trace, &reference)? {
+                Some(divergence) => {
+                    println!("{divergence}");
+                    std::process::exit(1);
+                }
+                None => println!("Traces match"),
+            }
+        }
+        Some(Command::Run {
+            rom,
+            options,
+            headless,
+            frames,
+            screenshot,
+            dump_ram,
+        }) => {
+            let bounded = frames.is_some()
+                || options.play_movie.is_some()
+                || options.debugger
+                || options.gdb.is_some();
+            if headless && !bounded {
+                let mut command = Args::command();
+                command
+                    .find_subcommand_mut("run")
+                    .expect("run is a subcommand")
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "--headless needs --frames, --play-movie, --debugger or --gdb to stop",
+                    )
+                    .exit();
+            }
+            let (device, debugger) = start(&rom, &options)?;
+            // A movie plays to its end unless told otherwise.
+            let limit = frames
+                .map(|frames| frames * CYCLES_PER_FRAME)
+                .or_else(|| device.playback().map(|p| p.movie().end));
+            let device = if headless {
+                run_headless(device, debugger, limit)
+            } else {
+                run_windowed(device, debugger, limit, config_path(&options))?
+            };
+            if let Some(path) = &screenshot {
+                info!("Saving screenshot to {path}");
+                device.screenshot(path)?;
+            }
+            if let Some(path) = &dump_ram {
+                info!("Saving work RAM to {path}");
+                device.dump_ram(path)?;
+            }
+            finish(device, &rom, &options)?;
+        }
+        None => {
+            let rom = args.rom.expect("rom is required without a subcommand");
+            let (device, debugger) = start(&rom, &args.options)?;
+            let device = run_windowed(device, debugger, None, config_path(&args.options))?;
+            finish(device, &rom, &args.options)?;
+        }
+    }
+    Ok(())
+}
+
+fn cdl_path(rom: &str) -> String {
+    Path::new(rom)
+        .with_extension("cdl")
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn config_path(options: &Options) -> PathBuf {
+    options.config.clone().unwrap_or_else(Config::default_path)
+}
+
+/// Creates the device and debugger front-end requested by `options`.
+fn start(rom: &str, options: &Options) -> Result<(Device, Option<Box<dyn DebugInterface>>), Error> {
+    if options.debug {
         std::env::set_var("RUST_LOG", "debug");
     } else {
         std::env::set_var("RUST_LOG", "info");
@@ -35,34 +256,239 @@ fn main() -> Result<(), Error> {
 
     info!("Starting emulator ...");
     info!("Creating device ...");
-    let device = Device::new(&args.rom, args.save_state)?;
+    let mut device = Device::new(rom, options.save_state.clone())?;
+    if let Some(path) = &options.trace {
+        info!("Tracing instructions to {path}");
+        device.set_trace(path, options.trace_symbols)?;
+    }
+    if options.cdl {
+        let cdl = cdl_path(rom);
+        info!("Logging code and data to {cdl}");
+        device.enable_cdl(&cdl)?;
+    }
+    if options.serial_out.is_some() {
+        device.capture_serial();
+    }
+    if let Some(port) = options.link_host {
+        device.connect_serial(Box::new(LinkCable::host(port)?));
+    } else if let Some(addr) = &options.link_connect {
+        device.connect_serial(Box::new(LinkCable::connect(addr.as_str())?));
+    } else if let Some(prefix) = &options.printer {
+        device.connect_serial(Box::new(Printer::new(prefix)));
+    }
+    if let Some(path) = &options.record_movie {
+        match &options.movie_start {
+            Some(state) => {
+                device.load_state_file(state)?;
+                info!("Recording a movie to {path} from {state}");
+            }
+            None => info!("Recording a movie to {path} from power-on"),
+        }
+        device.record_movie(options.movie_start.is_some());
+    } else if let Some(path) = &options.play_movie {
+        info!("Playing movie {path}");
+        device.play_movie(Movie::load(path)?)?;
+    }
+    if options.profile.is_some() || options.debugger {
+        device.enable_profiler();
+    }
+    let debugger: Option<Box<dyn DebugInterface>> = match options.gdb {
+        Some(port) => Some(Box::new(GdbStub::listen(port)?)),
+        None if options.debugger => Some(Box::new(Debugger::new())),
+        None => None,
+    };
+    Ok((device, debugger))
+}
+
+/// Writes the reports and logs requested by `options`, failing if a movie
+/// did not replay as recorded.
+fn finish(mut device: Device, rom: &str, options: &Options) -> Result<(), Error> {
+    if let Some(prefix) = &options.profile {
+        info!("Writing profile to {prefix}.*");
+        device.write_profile(prefix)?;
+    }
+    device.save_cdl(&cdl_path(rom))?;
+    if let Some(path) = &options.serial_out {
+        info!("Saving serial output to {path}");
+        device.save_serial_output(path)?;
+    }
+    if let Some(playback) = device.playback() {
+        if let Some(cycle) = playback.desync() {
+            return Err(anyhow!("Movie desynced at cycle {cycle}"));
+        }
+        info!("Movie matched at {} checkpoints", playback.verified());
+    }
+    if let (Some(path), Some(movie)) = (&options.record_movie, device.stop_movie()) {
+        info!("Saving movie to {path}");
+        movie.save(path)?;
+    }
+    Ok(())
+}
+
+/// Runs the device on its own thread, feeding a window, until either the
+/// window closes or `limit` T-cycles have run.
+fn run_windowed(
+    device: Device,
+    debugger: Option<Box<dyn DebugInterface>>,
+    limit: Option<u64>,
+    config: PathBuf,
+) -> Result<Device, Error> {
     let (sender1, receiver1) = mpsc::channel();
     let (sender2, receiver2) = mpsc::sync_channel(1);
-    let device_thread = std::thread::spawn(move || run_device(device, sender2, receiver1));
+    let device_thread =
+        std::thread::spawn(move || run_device(device, debugger, limit, sender2, receiver1));
 
-    run_window(sender1, receiver2).map_err(|e| {
+    run_window(sender1, receiver2, &config).map_err(|e| {
         eprintln!("{e}");
         e
     })?;
 
-    let _ = device_thread.join();
-    Ok(())
+    device_thread
+        .join()
+        .map_err(|_| anyhow!("Device thread panicked"))
 }
 
-fn run_device(mut device: Device, sender: SyncSender<Vec<u8>>, receiver: Receiver<GBEvent>) {
+/// Runs the device on the current thread at unlimited speed until `limit`
+/// T-cycles have run or the debugger quits.
+fn run_headless(
+    mut device: Device,
+    mut debugger: Option<Box<dyn DebugInterface>>,
+    limit: Option<u64>,
+) -> Device {
+    loop {
+        let budget = match limit {
+            Some(limit) if device.cycles() >= limit => break,
+            Some(limit) => (limit - device.cycles()).min(CYCLES_PER_FRAME),
+            None => CYCLES_PER_FRAME,
+        };
+        match &mut debugger {
+            Some(debugger) => {
+                if !debugger.update(&mut device, budget) {
+                    break;
+                }
+            }
+            None => device.run_until(device.cycles() + budget),
+        }
+    }
+    device
+}
+
+/// Slowest speed accepted from the configuration.
+const MIN_SPEED: f64 = 0.01;
+
+/// Emulation settings changed by hotkeys.
+#[derive(Default)]
+struct Controls {
+    paused: bool,
+    /// Run one frame while paused.
+    advance: bool,
+    /// Fast-forward key held.
+    fast_forward: bool,
+    fast_forward_toggled: bool,
+    slow_motion: bool,
+    speeds: Speed,
+    /// Rewind key held.
+    rewinding: bool,
+    /// Snapshots to go back by, accumulated while rewinding.
+    rewind_steps: f64,
+    rewind: Rewind,
+    /// Last macro recorded with `Hotkey::RecordMacro`.
+    recorded_macro: Option<Macro>,
+}
+
+impl Controls {
+    /// Speed relative to the Game Boy, or `None` to run as fast as possible.
+    fn speed(&self) -> Option<f64> {
+        if self.rewinding {
+            Some(1.0)
+        } else if self.fast_forward || self.fast_forward_toggled {
+            (self.speeds.fast_forward > 0.0).then(|| self.speeds.fast_forward.max(MIN_SPEED))
+        } else if self.slow_motion {
+            Some(self.speeds.slow_motion.max(MIN_SPEED))
+        } else {
+            Some(1.0)
+        }
+    }
+}
+
+/// Runs the device at the speed set by the controls, showing every frame
+/// that the window can take. There is no audio to keep in step.
+fn run_device(
+    mut device: Device,
+    mut debugger: Option<Box<dyn DebugInterface>>,
+    limit: Option<u64>,
+    sender: SyncSender<Frame>,
+    receiver: Receiver<GBEvent>,
+) -> Device {
+    let mut controls = Controls::default();
+    let mut pacer = FramePacer::new(FRAME_RATE);
+    let mut speed = controls.speed();
+    let mut meter = SpeedMeter::new();
+    let mut rewind = rewind_buffer(&controls.rewind);
     'outer: loop {
-        // device.do_cycle();
-        let data = device.ppu_data();
-        if let Err(TrySendError::Disconnected(..)) = sender.try_send(data) {
+        if limit.is_some_and(|limit| device.cycles() >= limit) {
+            break 'outer;
+        }
+        if controls.rewinding {
+            rewind_frame(&mut device, &mut rewind, &mut controls);
+            meter.reset();
+        } else if !controls.paused || std::mem::take(&mut controls.advance) {
+            if !run_frame(&mut device, &mut debugger) {
+                break 'outer;
+            }
+            meter.frame();
+            // Snapshots are of no use during a movie, which cannot be
+            // rewound.
+            if rewind.frame() && controls.rewind.memory > 0 && !device.movie_active() {
+                rewind.push(device.save_state());
+            }
+        } else {
+            meter.reset();
+        }
+        let status = if controls.rewinding {
+            Status::Rewinding
+        } else if controls.paused {
+            Status::Paused
+        } else {
+            Status::Running(meter.speed())
+        };
+        let frame = Frame {
+            data: device.frame().to_vec(),
+            status,
+        };
+        if let Err(TrySendError::Disconnected(..)) = sender.try_send(frame) {
             eprintln!("Send error: frontend disconnected, exiting..");
             break 'outer;
         }
+        if controls.speed() != speed {
+            speed = controls.speed();
+            if let Some(speed) = speed {
+                pacer.set_rate(FRAME_RATE * speed);
+            }
+        }
+        if speed.is_some() || controls.paused {
+            pacer.wait();
+        }
 
         'recv: loop {
             match receiver.try_recv() {
                 Ok(event) => match event {
-                    GBEvent::ArrowUp => {}
-                    GBEvent::ArrowDown => {}
+                    GBEvent::KeyDown(key) => device.press_button(key),
+                    GBEvent::KeyUp(key) => device.release_button(key),
+                    GBEvent::HotkeyDown(hotkey) => {
+                        hotkey_pressed(&mut device, &mut controls, hotkey)
+                    }
+                    GBEvent::HotkeyUp(Hotkey::FastForward) => controls.fast_forward = false,
+                    GBEvent::HotkeyUp(Hotkey::Rewind) => controls.rewinding = false,
+                    GBEvent::HotkeyUp(_) => {}
+                    GBEvent::TurboDown(key, rate) => device.press_turbo(key, rate),
+                    GBEvent::TurboUp(key) => device.release_turbo(key),
+                    GBEvent::PlayMacro(steps) => device.play_macro(steps),
+                    GBEvent::Speed(speeds) => controls.speeds = speeds,
+                    GBEvent::Rewind(config) => {
+                        rewind.set_limits(config.interval, config.memory << 20);
+                        controls.rewind = config;
+                    }
                 },
                 Err(TryRecvError::Empty) => break 'recv,
                 Err(TryRecvError::Disconnected) => {
@@ -72,13 +498,131 @@ fn run_device(mut device: Device, sender: SyncSender<Vec<u8>>, receiver: Receive
             }
         }
     }
+    device
+}
+
+fn rewind_buffer(config: &Rewind) -> RewindBuffer {
+    RewindBuffer::new(config.interval, config.memory << 20)
+}
+
+/// Goes back as many snapshots as the rewind speed calls for in a frame.
+fn rewind_frame(device: &mut Device, rewind: &mut RewindBuffer, controls: &mut Controls) {
+    controls.rewind_steps += controls.rewind.speed / rewind.interval() as f64;
+    while controls.rewind_steps >= 1.0 {
+        controls.rewind_steps -= 1.0;
+        let Some(state) = rewind.pop() else {
+            break;
+        };
+        if let Err(e) = device.load_state(&state) {
+            warn!("Cannot rewind: {e}");
+            rewind.clear();
+        }
+    }
+}
+
+/// Runs the device to the end of the frame, through the debugger if any.
+/// Returns false when the debugger quits.
+fn run_frame(device: &mut Device, debugger: &mut Option<Box<dyn DebugInterface>>) -> bool {
+    match debugger {
+        Some(debugger) => {
+            let end = (device.cycles() / CYCLES_PER_FRAME + 1) * CYCLES_PER_FRAME;
+            debugger.update(device, end - device.cycles())
+        }
+        None => {
+            device.run_frame();
+            true
+        }
+    }
+}
+
+fn hotkey_pressed(device: &mut Device, controls: &mut Controls, hotkey: Hotkey) {
+    match hotkey {
+        Hotkey::Pause => {
+            controls.paused = !controls.paused;
+            info!("{}", if controls.paused { "Paused" } else { "Resumed" });
+        }
+        Hotkey::FrameAdvance => {
+            controls.paused = true;
+            controls.advance = true;
+        }
+        Hotkey::FastForward => controls.fast_forward = true,
+        Hotkey::ToggleFastForward => {
+            controls.fast_forward_toggled = !controls.fast_forward_toggled;
+            let state = if controls.fast_forward_toggled {
+                "on"
+            } else {
+                "off"
+            };
+            info!("Fast-forward {state}");
+        }
+        Hotkey::SlowMotion => {
+            controls.slow_motion = !controls.slow_motion;
+            let state = if controls.slow_motion { "on" } else { "off" };
+            info!("Slow motion {state}");
+        }
+        Hotkey::SaveState => {
+            let path = device.state_path();
+            match device.save_state_file(&path) {
+                Ok(()) => info!("Saved state to {path}"),
+                Err(e) => warn!("Cannot save state to {path}: {e}"),
+            }
+        }
+        Hotkey::LoadState => {
+            let path = device.state_path();
+            match device.load_state_file(&path) {
+                Ok(()) => info!("Loaded state from {path}"),
+                Err(e) => warn!("Cannot load state from {path}: {e}"),
+            }
+        }
+        Hotkey::Screenshot => {
+            let path = format!(
+                "{}-{}.png",
+                Path::new(device.rom_path()).with_extension("").display(),
+                device.cycles() / CYCLES_PER_FRAME
+            );
+            match device.screenshot(&path) {
+                Ok(()) => info!("Saved screenshot to {path}"),
+                Err(e) => warn!("Cannot save screenshot to {path}: {e}"),
+            }
+        }
+        Hotkey::Rewind if device.movie_active() => {
+            warn!("Cannot rewind during a movie");
+        }
+        Hotkey::Rewind => {
+            controls.rewinding = true;
+            controls.rewind_steps = 1.0;
+        }
+        Hotkey::Reset => {
+            device.reset();
+            info!("Reset");
+        }
+        Hotkey::RecordMacro => match device.stop_macro_recording() {
+            Some(steps) => {
+                let frames: u32 = steps.iter().map(|step| step.frames).sum();
+                info!("Recorded a macro of {frames} frames");
+                controls.recorded_macro = Some(steps);
+            }
+            None => {
+                device.record_macro();
+                info!("Recording a macro");
+            }
+        },
+        Hotkey::PlayMacro => match &controls.recorded_macro {
+            Some(steps) => device.play_macro(steps.clone()),
+            None => warn!("No macro recorded"),
+        },
+    }
 }
 
-fn run_window(sender: Sender<GBEvent>, receiver: Receiver<Vec<u8>>) -> Result<(), Error> {
+fn run_window(
+    sender: Sender<GBEvent>,
+    receiver: Receiver<Frame>,
+    config: &Path,
+) -> Result<(), Error> {
     let event_loop = EventLoop::new().expect("Failed to create event Loop");
     event_loop.set_control_flow(event_loop::ControlFlow::Poll);
 
-    let mut app = App::new(sender, receiver);
+    let mut app = App::new(sender, receiver, &Config::load_or_create(config)?, config);
     let res = event_loop.run_app(&mut app).map_err(|e| anyhow!(e));
     drop(app.receiver);
 
@@ -1,9 +1,15 @@
+use crate::bus::Bus;
+use crate::cdl::{self, CodeDataLog, CDL_DATA, CDL_DMA};
 use crate::hram::{Hram, HRAM_END, HRAM_START};
 use crate::joypad::Joypad;
 use crate::ppu::{Ppu, OAM_END, OAM_START, VRAM_END, VRAM_START};
 use crate::rom::{Rom, ERAM_END, ERAM_START, ROM_BANK_END, ROM_START};
+use crate::serial::Serial;
+use crate::state::{StateReader, StateWriter};
 use crate::timer::Timer;
+use crate::watch::Watchpoints;
 use crate::wram::{Wram, ECHO_END, ECHO_START, WRAM_END, WRAM_START};
+use crate::Result;
 
 pub struct Mmu {
     pub rom: Rom,
@@ -11,26 +17,33 @@ pub struct Mmu {
     pub wram: Wram,
     pub hram: Hram,
     pub joypad: Joypad,
+    pub serial: Serial,
     pub timer: Timer,
     pub inte: u8,
     pub intf: u8,
-    wram_bank: u8,
-    vram_bank: u8,
+    pub watch: Option<Box<Watchpoints>>,
+    pub cdl: Option<Box<CodeDataLog>>,
+    /// LY reads 0x90, as Gameboy Doctor's reference logs assume.
+    pub doctor: bool,
 }
 
 impl Mmu {
     pub fn new(_rom: Rom) -> Mmu {
+        let mut joypad = Joypad::new();
+        joypad.set_sgb(_rom.supports_sgb());
         Mmu {
             rom: _rom,
             ppu: Ppu::new(),
             wram: Wram::new(),
             hram: Hram::new(),
-            joypad: Joypad::new(),
+            joypad,
+            serial: Serial::new(),
             timer: Timer::new(),
             inte: 0,
             intf: 0,
-            wram_bank: 1,
-            vram_bank: 1,
+            watch: None,
+            cdl: None,
+            doctor: false,
         }
     }
 
@@ -45,14 +58,81 @@ impl Mmu {
         self.intf |= self.joypad.interrupt;
         self.joypad.interrupt = 0;
 
+        self.serial.do_cycle(ticks);
+        self.intf |= self.serial.interrupt;
+        self.serial.interrupt = 0;
+
         self.ppu.do_cycle(ticks);
         self.intf |= self.ppu.interrupt;
         self.ppu.interrupt = 0;
 
-        return ticks;
+        ticks
+    }
+
+    /// Powers the hardware back on, keeping cartridge RAM, whatever is
+    /// plugged into the link port and the debugging hooks.
+    pub fn reset(&mut self) {
+        self.ppu = Ppu::new();
+        self.wram = Wram::new();
+        self.hram = Hram::new();
+        self.joypad = Joypad::new();
+        self.joypad.set_sgb(self.rom.supports_sgb());
+        self.serial.reset();
+        self.timer = Timer::new();
+        self.inte = 0;
+        self.intf = 0;
+    }
+
+    /// Saves everything but the ROM image and the debugging hooks.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.rom.save_state(w);
+        self.ppu.save_state(w);
+        self.wram.save_state(w);
+        self.hram.save_state(w);
+        self.joypad.save_state(w);
+        self.serial.save_state(w);
+        self.timer.save_state(w);
+        w.u8(self.inte);
+        w.u8(self.intf);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.rom.load_state(r)?;
+        self.ppu.load_state(r)?;
+        self.wram.load_state(r)?;
+        self.hram.load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.serial.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.inte = r.u8()?;
+        self.intf = r.u8()?;
+        Ok(())
     }
 
     pub fn rb(&mut self, a: u16) -> u8 {
+        self.fetch(a, CDL_DATA)
+    }
+
+    /// Reads a byte, recording it in the code/data log as `flag`.
+    pub fn fetch(&mut self, a: u16, flag: u8) -> u8 {
+        let v = self.peek(a);
+        if let Some(watch) = &mut self.watch {
+            watch.on_read(a, v);
+        }
+        self.log(a, flag);
+        v
+    }
+
+    fn log(&mut self, a: u16, flag: u8) {
+        if let Some(cdl) = &mut self.cdl {
+            if let Some(offset) = cdl::rom_offset(a, self.rom.bank()) {
+                cdl.mark(offset, flag);
+            }
+        }
+    }
+
+    /// Reads a byte without triggering watchpoints, for debugging tools.
+    pub fn peek(&self, a: u16) -> u8 {
         match a {
             ROM_START..=ROM_BANK_END => self.rom.rb(a),
             VRAM_START..=VRAM_END => self.ppu.rb(a),
@@ -62,11 +142,28 @@ impl Mmu {
             OAM_START..=OAM_END => self.ppu.rb(a),
             HRAM_START..=HRAM_END => self.hram.rb(a),
             0xFF00 => self.joypad.rb(),
+            0xFF01..=0xFF02 => self.serial.rb(a),
+            0xFF04..=0xFF07 => self.timer.rb(a),
+            0xFF0F => self.intf | 0xE0,
+            0xFF44 if self.doctor => 0x90,
+            0xFF40..=0xFF4B | 0xFF4F => self.ppu.rb(a),
+            0xFFFF => self.inte,
             _ => 0xFF,
         }
     }
 
     pub fn wb(&mut self, a: u16, v: u8) {
+        let old = match self.watch {
+            Some(_) => self.peek(a),
+            None => 0,
+        };
+        self.write(a, v);
+        if let Some(watch) = &mut self.watch {
+            watch.on_write(a, old, v);
+        }
+    }
+
+    fn write(&mut self, a: u16, v: u8) {
         match a {
             ROM_START..=ROM_BANK_END => self.rom.wb(a, v),
             VRAM_START..=VRAM_END => self.ppu.wb(a, v),
@@ -76,19 +173,67 @@ impl Mmu {
             OAM_START..=OAM_END => self.ppu.wb(a, v),
             HRAM_START..=HRAM_END => self.hram.wb(a, v),
             0xFF00 => self.joypad.wb(v),
-            // 0xFF46 => {
-            //    DMA transfert
-            // },
+            0xFF01..=0xFF02 => self.serial.wb(a, v),
+            0xFF04..=0xFF07 => self.timer.wb(a, v),
+            0xFF0F => self.intf = v & 0x1F,
+            0xFF46 => {
+                self.ppu.wb(a, v);
+                self.oam_dma(v);
+            }
+            0xFF40..=0xFF4B | 0xFF4F => self.ppu.wb(a, v),
+            0xFFFF => self.inte = v,
             _ => (),
         };
     }
 
-    pub fn rw(&mut self, address: u16) -> u16 {
-        (self.rb(address) as u16) | ((self.rb(address + 1) as u16) << 8)
+    /// Copies 0xXX00-0xXX9F into OAM. The transfer is done at once rather
+    /// than over 160 M-cycles.
+    fn oam_dma(&mut self, source: u8) {
+        let source = (source as u16) << 8;
+        for i in 0..(OAM_END - OAM_START + 1) {
+            let v = self.peek(source + i);
+            self.log(source + i, CDL_DMA);
+            self.ppu.wb(OAM_START + i, v);
+        }
+    }
+}
+
+impl Bus for Mmu {
+    fn rb(&mut self, a: u16) -> u8 {
+        Mmu::rb(self, a)
+    }
+
+    fn wb(&mut self, a: u16, v: u8) {
+        Mmu::wb(self, a, v)
+    }
+
+    fn tick(&mut self) {
+        self.do_cycle(4);
+    }
+
+    fn peek(&self, a: u16) -> u8 {
+        Mmu::peek(self, a)
     }
 
-    pub fn ww(&mut self, a: u16, v: u16) {
-        self.wb(a, (v & 0xFF) as u8);
-        self.wb(a + 1, (v >> 8) as u8)
+    fn fetch(&mut self, a: u16, flag: u8) -> u8 {
+        Mmu::fetch(self, a, flag)
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.inte & self.intf & 0x1F
+    }
+
+    fn acknowledge_interrupt(&mut self, n: u32) {
+        self.intf &= !(1 << n);
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom.bank()
+    }
+
+    fn on_instruction(&mut self, pc: u16) {
+        if let Some(watch) = &mut self.watch {
+            watch.pc = pc;
+        }
     }
 }
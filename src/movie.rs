@@ -0,0 +1,250 @@
+use std::fs;
+
+use log::warn;
+
+use crate::device::CYCLES_PER_FRAME;
+use crate::error::{MovieError, StateError};
+use crate::joypad::KeypadKey;
+use crate::state::{StateReader, StateWriter};
+use crate::{EmulatorError, Result};
+
+const MAGIC: &[u8; 4] = b"RKMV";
+const VERSION: u32 = 1;
+
+/// Cycles between two state hashes checked during playback.
+pub const CHECKPOINT_CYCLES: u64 = 60 * CYCLES_PER_FRAME;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MovieInput {
+    Press(KeypadKey),
+    Release(KeypadKey),
+    Reset,
+}
+
+/// Joypad changes along with the T-cycle they happened at, replayed on the
+/// same ROM from the same start.
+///
+/// A movie starts either from an embedded save state or from power-on. A
+/// power-on start does not include cartridge RAM, so games reading their
+/// save data should be recorded from a save state.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Movie {
+    /// `Device::save_state` checksum of the ROM.
+    pub checksum: u32,
+    pub start_state: Option<Vec<u8>>,
+    /// `Device::cycles()` when recording started and stopped.
+    pub start: u64,
+    pub end: u64,
+    pub inputs: Vec<(u64, MovieInput)>,
+    /// Hash of the screen and machine state every `CHECKPOINT_CYCLES`,
+    /// with the cycle it was taken at.
+    pub checkpoints: Vec<(u64, u64)>,
+}
+
+impl Movie {
+    pub fn new(checksum: u32, start_state: Option<Vec<u8>>, start: u64) -> Movie {
+        Movie {
+            checksum,
+            start_state,
+            start,
+            end: start,
+            inputs: Vec::new(),
+            checkpoints: Vec::new(),
+        }
+    }
+
+    /// Cycle at which the next checkpoint is due.
+    fn next_checkpoint(&self, index: usize) -> u64 {
+        self.start + (index as u64 + 1) * CHECKPOINT_CYCLES
+    }
+
+    pub fn load(path: &str) -> Result<Movie> {
+        Movie::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::with_header(MAGIC, VERSION);
+        w.u32(self.checksum);
+        w.u64(self.start);
+        w.u64(self.end);
+        match &self.start_state {
+            Some(state) => {
+                w.bool(true);
+                w.u32(state.len() as u32);
+                w.bytes(state);
+            }
+            None => w.bool(false),
+        }
+        w.u32(self.inputs.len() as u32);
+        for &(cycle, input) in &self.inputs {
+            w.u64(cycle);
+            let (kind, key) = match input {
+                MovieInput::Press(key) => (0, key),
+                MovieInput::Release(key) => (1, key),
+                MovieInput::Reset => (2, KeypadKey::Right),
+            };
+            w.u8(kind);
+            w.u8(KeypadKey::ALL.iter().position(|&k| k == key).unwrap() as u8);
+        }
+        w.u32(self.checkpoints.len() as u32);
+        for &(cycle, hash) in &self.checkpoints {
+            w.u64(cycle);
+            w.u64(hash);
+        }
+        w.finish()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Movie> {
+        Movie::read(bytes).map_err(|e| match e {
+            EmulatorError::State(StateError::BadMagic) => MovieError::BadMagic.into(),
+            EmulatorError::State(StateError::UnsupportedVersion(v)) => {
+                MovieError::UnsupportedVersion(v).into()
+            }
+            EmulatorError::State(_) => MovieError::Corrupt.into(),
+            e => e,
+        })
+    }
+
+    fn read(bytes: &[u8]) -> Result<Movie> {
+        let mut r = StateReader::with_header(bytes, MAGIC, VERSION)?;
+        let mut movie = Movie::new(r.u32()?, None, r.u64()?);
+        movie.end = r.u64()?;
+        if r.bool()? {
+            let len = r.u32()? as usize;
+            movie.start_state = Some(r.vec(len)?);
+        }
+        for _ in 0..r.u32()? {
+            let cycle = r.u64()?;
+            let kind = r.u8()?;
+            let key = *KeypadKey::ALL
+                .get(r.u8()? as usize)
+                .ok_or(MovieError::Corrupt)?;
+            let input = match kind {
+                0 => MovieInput::Press(key),
+                1 => MovieInput::Release(key),
+                2 => MovieInput::Reset,
+                _ => return Err(MovieError::Corrupt.into()),
+            };
+            movie.inputs.push((cycle, input));
+        }
+        for _ in 0..r.u32()? {
+            movie.checkpoints.push((r.u64()?, r.u64()?));
+        }
+        Ok(movie)
+    }
+}
+
+/// A movie being replayed, and how well it matches so far.
+pub struct Playback {
+    movie: Movie,
+    next_input: usize,
+    next_checkpoint: usize,
+    verified: usize,
+    desync: Option<u64>,
+}
+
+impl Playback {
+    pub(crate) fn new(movie: Movie) -> Playback {
+        Playback {
+            movie,
+            next_input: 0,
+            next_checkpoint: 0,
+            verified: 0,
+            desync: None,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Checkpoints whose hash matched the recording.
+    pub fn verified(&self) -> usize {
+        self.verified
+    }
+
+    /// Cycle of the first checkpoint that did not match, if any.
+    pub fn desync(&self) -> Option<u64> {
+        self.desync
+    }
+
+    pub fn finished(&self, cycles: u64) -> bool {
+        cycles >= self.movie.end
+    }
+}
+
+/// What `Device` does with a movie on every instruction.
+pub(crate) enum Session {
+    Recording(Movie),
+    Playing(Playback),
+}
+
+impl Session {
+    /// Cycle at which `inputs` or `checkpoint` has something to do.
+    pub(crate) fn next_event(&self) -> u64 {
+        match self {
+            Session::Recording(movie) => movie.next_checkpoint(movie.checkpoints.len()),
+            Session::Playing(p) => {
+                let input = p.movie.inputs.get(p.next_input).map_or(u64::MAX, |i| i.0);
+                let checkpoint = match p.movie.checkpoints.get(p.next_checkpoint) {
+                    Some(&(cycle, _)) if p.desync.is_none() => cycle,
+                    _ => u64::MAX,
+                };
+                input.min(checkpoint)
+            }
+        }
+    }
+
+    /// Recorded inputs due at `cycles`.
+    pub(crate) fn inputs(&mut self, cycles: u64) -> Vec<MovieInput> {
+        let Session::Playing(p) = self else {
+            return Vec::new();
+        };
+        let inputs = &p.movie.inputs[p.next_input..];
+        let due = inputs.iter().take_while(|i| i.0 <= cycles).count();
+        p.next_input += due;
+        inputs[..due].iter().map(|i| i.1).collect()
+    }
+
+    /// Records or checks the checkpoint due at `cycles`, if any, given the
+    /// hash of the state once the inputs due are applied.
+    pub(crate) fn checkpoint(&mut self, cycles: u64, hash: impl FnOnce() -> u64) {
+        match self {
+            Session::Recording(movie) => {
+                if cycles >= movie.next_checkpoint(movie.checkpoints.len()) {
+                    movie.checkpoints.push((cycles, hash()));
+                }
+            }
+            Session::Playing(p) => {
+                let Some(&(cycle, expected)) = p.movie.checkpoints.get(p.next_checkpoint) else {
+                    return;
+                };
+                if cycles < cycle || p.desync.is_some() {
+                    return;
+                }
+                p.next_checkpoint += 1;
+                if cycles == cycle && hash() == expected {
+                    p.verified += 1;
+                } else {
+                    warn!("Movie desynced at cycle {cycle}");
+                    p.desync = Some(cycle);
+                }
+            }
+        }
+    }
+}
+
+/// FNV-1a, stable across builds and platforms unlike `DefaultHasher`.
+pub(crate) fn hash(chunks: &[&[u8]]) -> u64 {
+    chunks
+        .iter()
+        .flat_map(|chunk| chunk.iter())
+        .fold(0xCBF2_9CE4_8422_2325, |hash, &b| {
+            (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01B3)
+        })
+}
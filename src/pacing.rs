@@ -0,0 +1,157 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::device::CYCLES_PER_FRAME;
+
+/// T-cycles per second.
+pub const CLOCK_RATE: u64 = 4_194_304;
+
+/// Frames per second of the Game Boy screen, about 59.7275 Hz.
+pub const FRAME_RATE: f64 = CLOCK_RATE as f64 / CYCLES_PER_FRAME as f64;
+
+/// How early to wake up from sleeping and spin until the deadline, to make
+/// up for the scheduler's latency.
+const SPIN: Duration = Duration::from_millis(2);
+
+/// Frames the pacer may fall behind before giving up on catching up.
+const MAX_LAG: u32 = 4;
+
+/// Waits for frame deadlines at a steady rate.
+///
+/// Deadlines are counted from a fixed origin rather than from the last
+/// wait, so that oversleeping on one frame is made up on the next ones
+/// instead of accumulating.
+pub struct FramePacer {
+    period: Duration,
+    origin: Instant,
+    frames: u32,
+}
+
+impl FramePacer {
+    pub fn new(rate: f64) -> FramePacer {
+        FramePacer {
+            period: Duration::from_secs_f64(1.0 / rate),
+            origin: Instant::now(),
+            frames: 0,
+        }
+    }
+
+    /// Changes the rate from the next frame on.
+    pub fn set_rate(&mut self, rate: f64) {
+        self.period = Duration::from_secs_f64(1.0 / rate);
+        self.reset();
+    }
+
+    /// Starts counting frames from now, after a pause for example.
+    pub fn reset(&mut self) {
+        self.origin = Instant::now();
+        self.frames = 0;
+    }
+
+    fn deadline(&self) -> Instant {
+        self.origin + self.period * self.frames
+    }
+
+    /// Sleeps, then spins, until the end of the current frame.
+    pub fn wait(&mut self) {
+        self.frames += 1;
+        let deadline = self.deadline();
+        let now = Instant::now();
+        if now > deadline + self.period * MAX_LAG {
+            // Too far behind, e.g. after the emulator was suspended.
+            self.reset();
+            return;
+        }
+        if let Some(sleep) = deadline.checked_duration_since(now + SPIN) {
+            thread::sleep(sleep);
+        }
+        while Instant::now() < deadline {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+/// Measures the emulation speed relative to the Game Boy.
+pub struct SpeedMeter {
+    start: Instant,
+    frames: u32,
+    speed: f64,
+}
+
+impl Default for SpeedMeter {
+    fn default() -> Self {
+        SpeedMeter::new()
+    }
+}
+
+impl SpeedMeter {
+    pub fn new() -> SpeedMeter {
+        SpeedMeter {
+            start: Instant::now(),
+            frames: 0,
+            speed: 1.0,
+        }
+    }
+
+    /// Starts a new measurement, e.g. while paused.
+    pub fn reset(&mut self) {
+        self.start = Instant::now();
+        self.frames = 0;
+    }
+
+    /// Counts an emulated frame.
+    pub fn frame(&mut self) {
+        self.frames += 1;
+        let elapsed = self.start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.speed = self.frames as f64 / elapsed.as_secs_f64() / FRAME_RATE;
+            self.reset();
+        }
+    }
+
+    /// Speed over the last second, 1.0 being full speed.
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_rate() {
+        assert!((FRAME_RATE - 59.7275).abs() < 0.0001);
+    }
+
+    #[test]
+    fn waits_for_each_frame() {
+        let mut pacer = FramePacer::new(200.0);
+        let start = Instant::now();
+        for _ in 0..10 {
+            pacer.wait();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn makes_up_for_a_late_frame() {
+        let mut pacer = FramePacer::new(100.0);
+        thread::sleep(Duration::from_millis(22));
+        let start = Instant::now();
+        // Deadlines are at 10 and 20 ms from the origin, both missed.
+        pacer.wait();
+        pacer.wait();
+        assert!(start.elapsed() < Duration::from_millis(5));
+    }
+
+    #[test]
+    fn gives_up_when_far_behind() {
+        let mut pacer = FramePacer::new(200.0);
+        thread::sleep(Duration::from_millis(30));
+        pacer.wait();
+        let start = Instant::now();
+        pacer.wait();
+        assert!(start.elapsed() >= Duration::from_millis(4));
+    }
+}
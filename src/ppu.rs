@@ -1,3 +1,7 @@
+use crate::error::StateError;
+use crate::state::{StateReader, StateWriter};
+use crate::Result;
+
 const VRAM_BANK_SIZE: usize = 0x2000; // 8KB
 pub const VRAM_START: u16 = 0x8000;
 pub const VRAM_END: u16 = 0x9FFF;
@@ -6,6 +10,21 @@ const OAM_SIZE: usize = 0xA0; // 160B
 pub const OAM_START: u16 = 0xFE00;
 pub const OAM_END: u16 = 0xFE9F;
 
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const OAM_SCAN_DOTS: u32 = 80;
+const DRAWING_DOTS: u32 = 172;
+const HBLANK_DOTS: u32 = 204;
+const LINE_DOTS: u32 = 456;
+const VBLANK_LINE: u8 = 144;
+const LAST_LINE: u8 = 153;
+
+const MODE_HBLANK: u8 = 0;
+const MODE_VBLANK: u8 = 1;
+const MODE_OAM_SCAN: u8 = 2;
+const MODE_DRAWING: u8 = 3;
+
 pub struct Ppu {
     vram: [[u8; VRAM_BANK_SIZE]; 2],
     vram_bank: u8,
@@ -23,40 +42,286 @@ pub struct Ppu {
     wy: u8,
     wx: u8,
     mode: u8,
-    mode_clock: u32,
+    dots: u32,
+    /// Line of the window to draw next, which only advances on lines where
+    /// the window is visible.
+    window_line: u8,
+    stat_line: bool,
+    /// Shades 0 (white) to 3 (black), one per pixel, row by row.
+    frame: Vec<u8>,
     pub interrupt: u8,
 }
 
 impl Ppu {
+    /// The PPU as the DMG boot ROM leaves it: LCD on, with the usual
+    /// background palette.
     pub fn new() -> Ppu {
         Ppu {
             vram: [[0; VRAM_BANK_SIZE]; 2],
-            vram_bank: 1,
+            vram_bank: 0,
             oam: [0; OAM_SIZE],
-            lcdc: 0,
+            lcdc: 0x91,
             stat: 0,
             scy: 0,
             scx: 0,
             ly: 0,
             lyc: 0,
             dma: 0,
-            bgp: 0,
+            bgp: 0xFC,
             obp0: 0,
             obp1: 0,
             wy: 0,
             wx: 0,
-            mode: 0,
-            mode_clock: 0,
+            mode: MODE_OAM_SCAN,
+            dots: 0,
+            window_line: 0,
+            stat_line: false,
+            frame: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
             interrupt: 0,
         }
     }
 
+    /// Last rendered screen, as one shade per pixel.
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
     pub fn get_vram(&self) -> Vec<u8> {
         self.vram.concat()
     }
 
+    fn lcd_on(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for bank in &self.vram {
+            w.bytes(bank);
+        }
+        w.u8(self.vram_bank);
+        w.bytes(&self.oam);
+        for v in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.dma, self.bgp,
+            self.obp0, self.obp1, self.wy, self.wx, self.mode,
+        ] {
+            w.u8(v);
+        }
+        w.u32(self.dots);
+        w.u8(self.window_line);
+        w.bool(self.stat_line);
+        w.bytes(&self.frame);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        for bank in &mut self.vram {
+            r.bytes(bank)?;
+        }
+        self.vram_bank = r.u8()?;
+        if self.vram_bank as usize >= self.vram.len() {
+            return Err(StateError::Corrupt.into());
+        }
+        r.bytes(&mut self.oam)?;
+        for v in [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.dma,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+            &mut self.mode,
+        ] {
+            *v = r.u8()?;
+        }
+        self.dots = r.u32()?;
+        self.window_line = r.u8()?;
+        self.stat_line = r.bool()?;
+        r.bytes(&mut self.frame)
+    }
+
+    /// Advances the PPU by `ticks` dots, rendering each line as mode 3 ends.
     pub fn do_cycle(&mut self, ticks: u32) -> u32 {
-        return 0;
+        if !self.lcd_on() {
+            return 0;
+        }
+        self.dots += ticks;
+        loop {
+            match self.mode {
+                MODE_OAM_SCAN if self.dots >= OAM_SCAN_DOTS => {
+                    self.dots -= OAM_SCAN_DOTS;
+                    self.mode = MODE_DRAWING;
+                }
+                MODE_DRAWING if self.dots >= DRAWING_DOTS => {
+                    self.dots -= DRAWING_DOTS;
+                    self.render_line();
+                    self.mode = MODE_HBLANK;
+                }
+                MODE_HBLANK if self.dots >= HBLANK_DOTS => {
+                    self.dots -= HBLANK_DOTS;
+                    self.ly += 1;
+                    if self.ly == VBLANK_LINE {
+                        self.mode = MODE_VBLANK;
+                        self.interrupt |= 0x01;
+                    } else {
+                        self.mode = MODE_OAM_SCAN;
+                    }
+                }
+                MODE_VBLANK if self.dots >= LINE_DOTS => {
+                    self.dots -= LINE_DOTS;
+                    if self.ly == LAST_LINE {
+                        self.ly = 0;
+                        self.window_line = 0;
+                        self.mode = MODE_OAM_SCAN;
+                    } else {
+                        self.ly += 1;
+                    }
+                }
+                _ => break,
+            }
+            self.update_stat();
+        }
+        0
+    }
+
+    /// Requests the STAT interrupt on a rising edge of the OR of all enabled
+    /// STAT sources.
+    fn update_stat(&mut self) {
+        let line = (self.stat & 0x40 != 0 && self.ly == self.lyc)
+            || (self.stat & 0x20 != 0 && self.mode == MODE_OAM_SCAN)
+            || (self.stat & 0x10 != 0 && self.mode == MODE_VBLANK)
+            || (self.stat & 0x08 != 0 && self.mode == MODE_HBLANK);
+        if line && !self.stat_line {
+            self.interrupt |= 0x02;
+        }
+        self.stat_line = line;
+    }
+
+    /// Color index 0-3 of pixel (`x`, `y`) of `tile`, addressed with the
+    /// LCDC.4 tile data mode.
+    fn tile_pixel(&self, tile: u8, x: u8, y: u8) -> u8 {
+        let base = if self.lcdc & 0x10 != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + (tile as i8 as isize) * 16) as usize
+        };
+        self.pixel(base, x, y)
+    }
+
+    fn pixel(&self, base: usize, x: u8, y: u8) -> u8 {
+        let lo = self.vram[0][base + y as usize * 2];
+        let hi = self.vram[0][base + y as usize * 2 + 1];
+        let bit = 7 - x;
+        ((hi >> bit) & 1) << 1 | ((lo >> bit) & 1)
+    }
+
+    fn render_line(&mut self) {
+        let y = self.ly;
+        let row = y as usize * SCREEN_WIDTH;
+        let mut colors = [0u8; SCREEN_WIDTH];
+
+        if self.lcdc & 0x01 != 0 {
+            let map = if self.lcdc & 0x08 != 0 {
+                0x1C00
+            } else {
+                0x1800
+            };
+            let bg_y = y.wrapping_add(self.scy);
+            for (x, color) in colors.iter_mut().enumerate() {
+                let bg_x = (x as u8).wrapping_add(self.scx);
+                let tile = self.vram[0][map + (bg_y as usize / 8) * 32 + bg_x as usize / 8];
+                *color = self.tile_pixel(tile, bg_x % 8, bg_y % 8);
+            }
+
+            let wx = self.wx as i16 - 7;
+            if self.lcdc & 0x20 != 0 && y >= self.wy && wx < SCREEN_WIDTH as i16 {
+                let map = if self.lcdc & 0x40 != 0 {
+                    0x1C00
+                } else {
+                    0x1800
+                };
+                let win_y = self.window_line;
+                for (x, color) in colors.iter_mut().enumerate().skip(wx.max(0) as usize) {
+                    let win_x = (x as i16 - wx) as u8;
+                    let tile = self.vram[0][map + (win_y as usize / 8) * 32 + win_x as usize / 8];
+                    *color = self.tile_pixel(tile, win_x % 8, win_y % 8);
+                }
+                self.window_line += 1;
+            }
+        }
+
+        for (x, color) in colors.iter().enumerate() {
+            self.frame[row + x] = (self.bgp >> (color * 2)) & 0x03;
+        }
+
+        if self.lcdc & 0x02 != 0 {
+            self.render_sprites(y, &colors);
+        }
+    }
+
+    fn render_sprites(&mut self, y: u8, bg_colors: &[u8; SCREEN_WIDTH]) {
+        let height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+        // The first ten sprites in OAM order that overlap the line.
+        let mut sprites: Vec<(usize, &[u8])> = self
+            .oam
+            .chunks(4)
+            .enumerate()
+            .filter(|(_, s)| {
+                let top = s[0] as i16 - 16;
+                (top..top + height).contains(&(y as i16))
+            })
+            .take(10)
+            .collect();
+        // Lower X wins, then lower OAM index.
+        sprites.sort_by_key(|(i, s)| (s[1], *i));
+
+        let row = y as usize * SCREEN_WIDTH;
+        let mut taken = [false; SCREEN_WIDTH];
+        let mut pixels = Vec::new();
+        for (_, sprite) in sprites {
+            let (top, left, flags) = (sprite[0] as i16 - 16, sprite[1] as i16 - 8, sprite[3]);
+            let mut tile = sprite[2];
+            let mut line = (y as i16 - top) as u8;
+            if flags & 0x40 != 0 {
+                line = height as u8 - 1 - line;
+            }
+            if height == 16 {
+                tile &= 0xFE;
+            }
+            let palette = if flags & 0x10 != 0 {
+                self.obp1
+            } else {
+                self.obp0
+            };
+            for px in 0..8u8 {
+                let x = left + px as i16;
+                if !(0..SCREEN_WIDTH as i16).contains(&x) || taken[x as usize] {
+                    continue;
+                }
+                let tx = if flags & 0x20 != 0 { 7 - px } else { px };
+                let color = self.pixel(tile as usize * 16, tx, line);
+                if color == 0 {
+                    continue;
+                }
+                // The first opaque sprite pixel hides the others, even when
+                // it is itself behind the background.
+                taken[x as usize] = true;
+                if flags & 0x80 == 0 || bg_colors[x as usize] == 0 {
+                    pixels.push((x as usize, (palette >> (color * 2)) & 0x03));
+                }
+            }
+        }
+        for (x, shade) in pixels {
+            self.frame[row + x] = shade;
+        }
     }
 
     pub fn rb(&self, a: u16) -> u8 {
@@ -64,9 +329,14 @@ impl Ppu {
             0x8000..=0x9FFF => self.vram[self.vram_bank as usize][(a - 0x8000) as usize],
             0xFE00..=0xFE9F => self.oam[(a - 0xFE00) as usize],
             0xFF40 => self.lcdc,
-            0xFF41 => self.stat,
+            0xFF41 => {
+                let coincidence = if self.ly == self.lyc { 0x04 } else { 0 };
+                let mode = if self.lcd_on() { self.mode } else { 0 };
+                self.stat | 0x80 | coincidence | mode
+            }
             0xFF42 => self.scy,
             0xFF43 => self.scx,
+            0xFF44 => self.ly,
             0xFF45 => self.lyc,
             0xFF46 => self.dma,
             0xFF47 => self.bgp,
@@ -74,6 +344,7 @@ impl Ppu {
             0xFF49 => self.obp1,
             0xFF4A => self.wy,
             0xFF4B => self.wx,
+            0xFF4F => self.vram_bank | 0xFE,
             _ => 0xFF,
         }
     }
@@ -82,12 +353,29 @@ impl Ppu {
         match a {
             0x8000..=0x9FFF => self.vram[self.vram_bank as usize][(a - 0x8000) as usize] = v,
             0xFE00..=0xFE9F => self.oam[(a - 0xFE00) as usize] = v,
-            0xFF40 => self.lcdc = v,
-            0xFF41 => self.stat = v,
+            0xFF40 => {
+                let was_on = self.lcd_on();
+                self.lcdc = v;
+                if was_on && !self.lcd_on() {
+                    self.ly = 0;
+                    self.dots = 0;
+                    self.window_line = 0;
+                    self.mode = MODE_HBLANK;
+                } else if !was_on && self.lcd_on() {
+                    self.mode = MODE_OAM_SCAN;
+                }
+            }
+            0xFF41 => self.stat = v & 0x78,
             0xFF42 => self.scy = v,
             0xFF43 => self.scx = v,
-            0xFF44 => self.ly = 0,
-            0xFF45 => self.lyc = v,
+            // LY is read-only.
+            0xFF44 => {}
+            0xFF45 => {
+                self.lyc = v;
+                if self.lcd_on() {
+                    self.update_stat();
+                }
+            }
             0xFF46 => self.dma = v,
             0xFF47 => self.bgp = v,
             0xFF48 => self.obp0 = v,
@@ -0,0 +1,424 @@
+use log::{info, warn};
+
+use crate::image;
+use crate::serial::SerialDevice;
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM: u8 = 0x01;
+const STATUS_BUSY: u8 = 0x02;
+const STATUS_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+/// Replies to the first of the two bytes closing each packet.
+const ALIVE: u8 = 0x81;
+
+const PRINTER_WIDTH: usize = 160;
+/// Data packets carry two rows of 20 tiles.
+const BAND_HEIGHT: usize = 16;
+const BAND_SIZE: usize = 0x280;
+/// The printer buffers up to nine bands between print commands.
+const BUFFER_SIZE: usize = BAND_SIZE * 9;
+/// White lines fed per unit of the margin nibbles.
+const MARGIN_LINES: usize = 8;
+/// Packets answered as busy after a print command.
+const BUSY_PACKETS: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// Game Boy Printer on the link port.
+///
+/// Packets are `88 33`, a command, a compression flag, a little-endian
+/// length, the data and a checksum of everything from the command on,
+/// followed by two bytes answered with `ALIVE` and the status. Printed
+/// bands accumulate on a page, which is saved as `<prefix>-<n>.png` when a
+/// print command feeds paper after it.
+pub struct Printer {
+    prefix: String,
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    checksum: u16,
+    packet: Vec<u8>,
+    /// Decompressed tile data waiting for a print command.
+    buffer: Vec<u8>,
+    status: u8,
+    busy: u8,
+    /// Gray levels of the page being printed, `PRINTER_WIDTH` per line.
+    page: Vec<u8>,
+    pages: usize,
+}
+
+impl Printer {
+    pub fn new(prefix: &str) -> Printer {
+        Printer {
+            prefix: prefix.to_string(),
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            checksum: 0,
+            packet: Vec::new(),
+            buffer: Vec::new(),
+            status: 0,
+            busy: 0,
+            page: Vec::new(),
+            pages: 0,
+        }
+    }
+
+    /// Pages saved so far.
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    fn status(&mut self) -> u8 {
+        let mut status = self.status;
+        if !self.buffer.is_empty() {
+            status |= STATUS_UNPROCESSED;
+        }
+        if self.buffer.len() >= BUFFER_SIZE {
+            status |= STATUS_FULL;
+        }
+        if self.busy > 0 {
+            self.busy -= 1;
+            status |= STATUS_BUSY | STATUS_FULL;
+        }
+        status
+    }
+
+    fn run_command(&mut self) {
+        if self.checksum != self.packet_checksum() {
+            self.status |= STATUS_CHECKSUM;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM;
+        match self.command {
+            INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy = 0;
+            }
+            DATA => {
+                let data = if self.compressed {
+                    decompress(&self.packet)
+                } else {
+                    std::mem::take(&mut self.packet)
+                };
+                let room = BUFFER_SIZE.saturating_sub(self.buffer.len());
+                self.buffer.extend(data.into_iter().take(room));
+            }
+            PRINT => {
+                if let &[sheets, margins, palette, exposure] = &self.packet[..] {
+                    self.print(sheets, margins, palette, exposure);
+                }
+            }
+            STATUS => (),
+            command => warn!("Printer: unknown command {command:#04x}"),
+        }
+    }
+
+    fn packet_checksum(&self) -> u16 {
+        let header = [
+            self.command,
+            self.compressed as u8,
+            self.length as u8,
+            (self.length >> 8) as u8,
+        ];
+        header
+            .iter()
+            .chain(&self.packet)
+            .fold(0u16, |sum, &b| sum.wrapping_add(b as u16))
+    }
+
+    /// Prints the buffered bands. The margins' high nibble feeds paper
+    /// before them and the low nibble after, ending the page; exposure
+    /// darkens or lightens the ink around the default of 0x40, leaving the
+    /// paper white.
+    fn print(&mut self, sheets: u8, margins: u8, palette: u8, exposure: u8) {
+        let data = std::mem::take(&mut self.buffer);
+        self.busy = BUSY_PACKETS;
+        if sheets == 0 {
+            return;
+        }
+        // Games commonly send 0 for the usual palette.
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        let darken = (exposure & 0x7F) as i32 - 0x40;
+
+        self.feed((margins >> 4) as usize * MARGIN_LINES);
+        for band in data.chunks(BAND_SIZE) {
+            let mut lines = vec![0xFF; PRINTER_WIDTH * BAND_HEIGHT];
+            for (tile, bytes) in band.chunks_exact(16).enumerate() {
+                let (row, column) = (tile / 20, tile % 20);
+                for (y, pair) in bytes.chunks_exact(2).enumerate() {
+                    for x in 0..8 {
+                        let bit = 7 - x;
+                        let color = ((pair[0] >> bit) & 1) | (((pair[1] >> bit) & 1) << 1);
+                        let shade = (palette >> (color * 2)) & 0x03;
+                        let gray = match shade {
+                            0 => 0xFF,
+                            _ => (0xFF - shade as i32 * 0x55 - darken).clamp(0, 0xFF),
+                        };
+                        let offset = (row * 8 + y) * PRINTER_WIDTH + column * 8 + x;
+                        lines[offset] = gray as u8;
+                    }
+                }
+            }
+            self.page.extend(lines);
+        }
+        self.feed((margins & 0x0F) as usize * MARGIN_LINES);
+
+        if margins & 0x0F != 0 {
+            self.save_page();
+        }
+    }
+
+    fn feed(&mut self, lines: usize) {
+        self.page
+            .resize(self.page.len() + lines * PRINTER_WIDTH, 0xFF);
+    }
+
+    fn save_page(&mut self) {
+        let page = std::mem::take(&mut self.page);
+        if page.is_empty() {
+            return;
+        }
+        self.pages += 1;
+        let path = format!("{}-{}.png", self.prefix, self.pages);
+        let height = page.len() / PRINTER_WIDTH;
+        match image::save_grayscale_png(&path, PRINTER_WIDTH, height, &page) {
+            Ok(()) => info!("Printed {path}"),
+            Err(e) => warn!("Printer: cannot save {path}: {e}"),
+        }
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.save_page();
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut reply = 0x00;
+        self.state = match self.state {
+            State::Magic1 if byte == 0x88 => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if byte == 0x33 => State::Command,
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = byte;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as usize;
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as usize) << 8;
+                self.packet.clear();
+                if self.length == 0 {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                }
+            }
+            State::Data => {
+                self.packet.push(byte);
+                if self.packet.len() < self.length {
+                    State::Data
+                } else {
+                    State::ChecksumLow
+                }
+            }
+            State::ChecksumLow => {
+                self.checksum = byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.checksum |= (byte as u16) << 8;
+                State::Alive
+            }
+            State::Alive => {
+                reply = ALIVE;
+                State::Status
+            }
+            State::Status => {
+                reply = self.status();
+                self.run_command();
+                State::Magic1
+            }
+        };
+        reply
+    }
+}
+
+/// Expands run-length encoded data: a control byte with bit 7 set repeats
+/// the next byte `(n & 0x7F) + 2` times, otherwise `n + 1` literal bytes
+/// follow.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut bytes = data.iter().copied();
+    while let Some(control) = bytes.next() {
+        if control & 0x80 != 0 {
+            if let Some(byte) = bytes.next() {
+                out.extend(std::iter::repeat_n(byte, (control & 0x7F) as usize + 2));
+            }
+        } else {
+            out.extend(bytes.by_ref().take(control as usize + 1));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends a packet, returning the replies to its last two bytes.
+    fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> [u8; 2] {
+        let length = data.len() as u16;
+        let header = [command, compressed as u8, length as u8, (length >> 8) as u8];
+        let checksum = header
+            .iter()
+            .chain(data)
+            .fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        send_raw(printer, &header, data, checksum)
+    }
+
+    fn send_raw(printer: &mut Printer, header: &[u8], data: &[u8], checksum: u16) -> [u8; 2] {
+        for &byte in [0x88, 0x33].iter().chain(header).chain(data) {
+            assert_eq!(printer.exchange(byte), 0);
+        }
+        printer.exchange(checksum as u8);
+        printer.exchange((checksum >> 8) as u8);
+        [printer.exchange(0), printer.exchange(0)]
+    }
+
+    fn printer(name: &str) -> Printer {
+        let prefix = std::env::temp_dir().join(format!("rekop-gbc-{name}"));
+        Printer::new(prefix.to_str().unwrap())
+    }
+
+    #[test]
+    fn checksum() {
+        let mut printer = printer("printer-checksum");
+        assert_eq!(send(&mut printer, INIT, false, &[]), [ALIVE, 0]);
+        let bad = send_raw(&mut printer, &[DATA, 0, 2, 0], &[1, 2], 0x1234);
+        assert_eq!(bad, [ALIVE, 0]);
+        assert!(printer.buffer.is_empty());
+        assert_eq!(
+            send(&mut printer, STATUS, false, &[]),
+            [ALIVE, STATUS_CHECKSUM]
+        );
+        // A good packet clears the error.
+        send(&mut printer, DATA, false, &[1, 2]);
+        assert_eq!(
+            send(&mut printer, STATUS, false, &[]),
+            [ALIVE, STATUS_UNPROCESSED]
+        );
+    }
+
+    #[test]
+    fn run_length_encoding() {
+        let data = [0x81, 0xAA, 0x02, 1, 2, 3, 0x80, 0x55];
+        assert_eq!(decompress(&data), [0xAA, 0xAA, 0xAA, 1, 2, 3, 0x55, 0x55]);
+        // A run missing its byte and a short literal end the data.
+        assert_eq!(decompress(&[0x00, 7, 0x85]), [7]);
+        assert_eq!(decompress(&[0x03, 1, 2]), [1, 2]);
+    }
+
+    #[test]
+    fn bands() {
+        let mut printer = printer("printer-bands");
+        send(&mut printer, INIT, false, &[]);
+
+        // First tile black, the one below the second light gray.
+        let mut band = vec![0; BAND_SIZE];
+        band[..16].fill(0xFF);
+        for pair in band[21 * 16..22 * 16].chunks_exact_mut(2) {
+            pair[0] = 0xFF;
+        }
+        send(&mut printer, DATA, false, &band);
+        // A white band, as runs of at most 129 bytes.
+        let mut runs = [0xFF, 0x00].repeat(4);
+        runs.extend([0x80 | (BAND_SIZE - 4 * 129 - 2) as u8, 0x00]);
+        assert_eq!(decompress(&runs).len(), BAND_SIZE);
+        send(&mut printer, DATA, true, &runs);
+        assert_eq!(printer.buffer.len(), 2 * BAND_SIZE);
+
+        let status = send(&mut printer, PRINT, false, &[1, 0x10, 0xE4, 0x40]);
+        assert_eq!(status, [ALIVE, STATUS_UNPROCESSED]);
+        assert!(printer.buffer.is_empty());
+        let busy = send(&mut printer, STATUS, false, &[]);
+        assert_eq!(busy, [ALIVE, STATUS_BUSY | STATUS_FULL]);
+
+        let page = &printer.page;
+        let pixel = |x: usize, y: usize| page[(MARGIN_LINES + y) * PRINTER_WIDTH + x];
+        assert_eq!(page.len(), (MARGIN_LINES + 2 * BAND_HEIGHT) * PRINTER_WIDTH);
+        assert!(page[..MARGIN_LINES * PRINTER_WIDTH]
+            .iter()
+            .all(|&g| g == 0xFF));
+        assert_eq!((pixel(0, 0), pixel(7, 7), pixel(8, 0)), (0x00, 0x00, 0xFF));
+        assert_eq!(
+            (pixel(8, 8), pixel(15, 15), pixel(16, 8)),
+            (0xAA, 0xAA, 0xFF)
+        );
+        assert!((BAND_HEIGHT..2 * BAND_HEIGHT).all(|y| pixel(0, y) == 0xFF));
+
+        // Feeding paper after the bands ends the page.
+        send(&mut printer, PRINT, false, &[1, 0x01, 0xE4, 0x40]);
+        assert_eq!(printer.pages(), 1);
+        assert!(printer.page.is_empty());
+        let path = format!("{}-1.png", printer.prefix);
+        let decoder =
+            png::Decoder::new(std::io::BufReader::new(std::fs::File::open(path).unwrap()));
+        let info = decoder.read_info().unwrap();
+        let (width, height) = info.info().size();
+        assert_eq!(width as usize, PRINTER_WIDTH);
+        assert_eq!(height as usize, 2 * MARGIN_LINES + 2 * BAND_HEIGHT);
+    }
+
+    #[test]
+    fn exposure_darkens_only_ink() {
+        let mut printer = printer("printer-exposure");
+        send(&mut printer, INIT, false, &[]);
+        // First tile light gray, the rest white.
+        let mut band = vec![0; BAND_SIZE];
+        for pair in band[..16].chunks_exact_mut(2) {
+            pair[0] = 0xFF;
+        }
+        send(&mut printer, DATA, false, &band);
+        send(&mut printer, PRINT, false, &[1, 0x00, 0xE4, 0x7F]);
+        let page = &printer.page;
+        assert_eq!(page[0], 0xAA - 0x3F);
+        let mut paper = page
+            .chunks(PRINTER_WIDTH)
+            .enumerate()
+            .flat_map(|(y, line)| line.iter().enumerate().map(move |(x, &g)| (x, y, g)))
+            .filter(|&(x, y, _)| x >= 8 || y >= 8);
+        assert!(paper.all(|(_, _, g)| g == 0xFF));
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+
+use crate::disasm;
+use crate::symbols::Symbols;
+use crate::Result;
+
+/// Bank-qualified code address.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct Location {
+    pub bank: usize,
+    pub address: u16,
+}
+
+impl Location {
+    pub fn new(address: u16, rom_bank: usize) -> Location {
+        Location {
+            bank: disasm::bank_of(address, rom_bank),
+            address,
+        }
+    }
+
+    pub fn name(&self, symbols: Option<&Symbols>) -> String {
+        match symbols.and_then(|s| s.name(self.address, self.bank)) {
+            Some(name) => name.to_string(),
+            None => format!("{:02X}:{:04X}", self.bank, self.address),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub function: Location,
+    pub return_address: u16,
+    pub interrupt: bool,
+    /// Stack pointer right after the return address was pushed.
+    sp: u32,
+    node: usize,
+}
+
+/// Node of the call tree: one per distinct call path.
+struct Node {
+    function: Location,
+    parent: usize,
+    children: HashMap<Location, usize>,
+    cycles: u64,
+    calls: u64,
+}
+
+#[derive(Default, Clone, Copy)]
+struct FunctionStats {
+    self_cycles: u64,
+    total_cycles: u64,
+    calls: u64,
+}
+
+/// Shadow call stack fed by the CPU on CALL/RST/interrupt entries and
+/// RET/RETI exits, accumulating T-cycles per PC and per call path.
+#[derive(Default)]
+pub struct Profiler {
+    stack: Vec<Frame>,
+    nodes: Vec<Node>,
+    pcs: HashMap<Location, u64>,
+    cycles: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Frames from the outermost function to the current one.
+    pub fn stack(&self) -> &[Frame] {
+        &self.stack
+    }
+
+    /// Attributes the cycles of the instruction at `pc` to the current
+    /// function.
+    pub fn record(&mut self, pc: u16, rom_bank: usize, cycles: u64) {
+        let location = Location::new(pc, rom_bank);
+        if self.stack.is_empty() {
+            self.nodes.push(Node {
+                function: location,
+                parent: 0,
+                children: HashMap::new(),
+                cycles: 0,
+                calls: 1,
+            });
+            self.stack.push(Frame {
+                function: location,
+                return_address: 0,
+                interrupt: false,
+                sp: u32::MAX,
+                node: 0,
+            });
+        }
+
+        *self.pcs.entry(location).or_default() += cycles;
+        let node = self.stack.last().map_or(0, |f| f.node);
+        self.nodes[node].cycles += cycles;
+        self.cycles += cycles;
+    }
+
+    pub fn enter(
+        &mut self,
+        target: u16,
+        rom_bank: usize,
+        return_address: u16,
+        sp: u16,
+        interrupt: bool,
+    ) {
+        let function = Location::new(target, rom_bank);
+        let parent = self.stack.last().map_or(0, |f| f.node);
+        if self.nodes.is_empty() {
+            return;
+        }
+        let next = self.nodes.len();
+        let node = *self.nodes[parent].children.entry(function).or_insert(next);
+        if node == next {
+            self.nodes.push(Node {
+                function,
+                parent,
+                children: HashMap::new(),
+                cycles: 0,
+                calls: 0,
+            });
+        }
+        self.nodes[node].calls += 1;
+        self.stack.push(Frame {
+            function,
+            return_address,
+            interrupt,
+            sp: sp as u32,
+            node,
+        });
+    }
+
+    /// Pops every frame whose return address lies below the new stack
+    /// pointer, so that unbalanced stack manipulation does not leak frames.
+    pub fn leave(&mut self, sp: u16) {
+        while self.stack.len() > 1 && self.stack.last().is_some_and(|f| f.sp < sp as u32) {
+            self.stack.pop();
+        }
+    }
+
+    fn inclusive(&self) -> Vec<u64> {
+        let mut inclusive: Vec<u64> = self.nodes.iter().map(|n| n.cycles).collect();
+        // Children are always created after their parent.
+        for i in (1..self.nodes.len()).rev() {
+            let parent = self.nodes[i].parent;
+            inclusive[parent] += inclusive[i];
+        }
+        inclusive
+    }
+
+    fn path(&self, mut node: usize) -> Vec<Location> {
+        let mut path = vec![self.nodes[node].function];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            path.push(self.nodes[node].function);
+        }
+        path.reverse();
+        path
+    }
+
+    fn percent(&self, cycles: u64) -> f64 {
+        if self.cycles == 0 {
+            0.0
+        } else {
+            cycles as f64 * 100.0 / self.cycles as f64
+        }
+    }
+
+    pub fn flat_report(&self, symbols: Option<&Symbols>) -> String {
+        let inclusive = self.inclusive();
+        let mut functions: HashMap<Location, FunctionStats> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let stats = functions.entry(node.function).or_default();
+            stats.self_cycles += node.cycles;
+            stats.calls += node.calls;
+            // Count recursive calls only once in the total.
+            if !self.path(i)[..]
+                .split_last()
+                .is_some_and(|(_, a)| a.contains(&node.function))
+            {
+                stats.total_cycles += inclusive[i];
+            }
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.self_cycles.cmp(&a.1.self_cycles).then(a.0.cmp(&b.0)));
+
+        let mut out = String::new();
+        let _ = writeln!(out, "Flat profile ({} cycles)", self.cycles);
+        let _ = writeln!(
+            out,
+            "{:>8} {:>12} {:>12} {:>8}  function",
+            "self%", "self", "total", "calls"
+        );
+        for (location, stats) in &functions {
+            let _ = writeln!(
+                out,
+                "{:>7.2}% {:>12} {:>12} {:>8}  {}",
+                self.percent(stats.self_cycles),
+                stats.self_cycles,
+                stats.total_cycles,
+                stats.calls,
+                location.name(symbols)
+            );
+        }
+
+        let mut pcs: Vec<_> = self.pcs.iter().collect();
+        pcs.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let _ = writeln!(out, "\nHot spots");
+        for (location, cycles) in pcs.iter().take(50) {
+            let name = symbols
+                .and_then(|s| s.describe(location.address, location.bank))
+                .map(|name| format!(" ({})", name))
+                .unwrap_or_default();
+            let _ = writeln!(
+                out,
+                "{:>7.2}% {:>12}  {:02X}:{:04X}{}",
+                self.percent(**cycles),
+                cycles,
+                location.bank,
+                location.address,
+                name
+            );
+        }
+        out
+    }
+
+    pub fn tree_report(&self, symbols: Option<&Symbols>) -> String {
+        let mut out = String::new();
+        if self.nodes.is_empty() {
+            return out;
+        }
+        let inclusive = self.inclusive();
+        let _ = writeln!(out, "Call tree ({} cycles)", self.cycles);
+        let mut pending = vec![(0, 0)];
+        while let Some((node, depth)) = pending.pop() {
+            let _ = writeln!(
+                out,
+                "{:>7.2}% {:>12} {:>8}  {:indent$}{}",
+                self.percent(inclusive[node]),
+                inclusive[node],
+                self.nodes[node].calls,
+                "",
+                self.nodes[node].function.name(symbols),
+                indent = depth * 2
+            );
+            let mut children: Vec<usize> = self.nodes[node].children.values().copied().collect();
+            children.sort_by_key(|&c| inclusive[c]);
+            pending.extend(children.into_iter().map(|c| (c, depth + 1)));
+        }
+        out
+    }
+
+    /// Folded stacks (`outer;inner cycles`) for flamegraph tools.
+    pub fn folded(&self, symbols: Option<&Symbols>) -> String {
+        let mut lines: Vec<String> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.cycles > 0)
+            .map(|(i, node)| {
+                let path: Vec<String> = self.path(i).iter().map(|l| l.name(symbols)).collect();
+                format!("{} {}", path.join(";"), node.cycles)
+            })
+            .collect();
+        lines.sort();
+        lines.join("\n") + "\n"
+    }
+
+    /// Writes `<prefix>.flat.txt`, `<prefix>.tree.txt` and `<prefix>.folded`.
+    pub fn write_reports(&self, prefix: &str, symbols: Option<&Symbols>) -> Result<()> {
+        fs::write(format!("{prefix}.flat.txt"), self.flat_report(symbols))?;
+        fs::write(format!("{prefix}.tree.txt"), self.tree_report(symbols))?;
+        fs::write(format!("{prefix}.folded"), self.folded(symbols))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+
+    fn folded(profiler: &Profiler) -> Vec<String> {
+        profiler.folded(None).lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        let mut profiler = Profiler::new();
+        profiler.record(0x0150, 1, 8);
+        profiler.enter(0x0200, 1, 0x0153, 0xFFFC, false);
+        profiler.record(0x0200, 1, 12);
+        profiler.enter(0x0300, 1, 0x0203, 0xFFFA, false);
+        profiler.record(0x0300, 1, 4);
+        profiler.leave(0xFFFC);
+        profiler.record(0x0203, 1, 4);
+        profiler.leave(0xFFFE);
+        profiler.record(0x0153, 1, 4);
+        profiler.enter(0x0200, 1, 0x0156, 0xFFFC, false);
+        profiler.record(0x0200, 1, 8);
+        assert_eq!(
+            folded(&profiler),
+            [
+                "00:0150 12",
+                "00:0150;00:0200 24",
+                "00:0150;00:0200;00:0300 4"
+            ]
+        );
+        let stack: Vec<u16> = profiler
+            .stack()
+            .iter()
+            .map(|f| f.function.address)
+            .collect();
+        assert_eq!(stack, [0x0150, 0x0200]);
+
+        let tree = profiler.tree_report(None);
+        let tree: Vec<Vec<_>> = tree
+            .lines()
+            .map(|l| l.split_whitespace().collect())
+            .collect();
+        assert_eq!(
+            tree[1..],
+            [
+                ["100.00%", "40", "1", "00:0150"],
+                ["70.00%", "28", "2", "00:0200"],
+                ["10.00%", "4", "1", "00:0300"]
+            ]
+        );
+    }
+
+    #[test]
+    fn banked_functions_and_interrupts() {
+        let mut profiler = Profiler::new();
+        profiler.record(0x0150, 1, 4);
+        profiler.enter(0x4000, 1, 0x0153, 0xFFFC, false);
+        profiler.enter(0x0040, 2, 0x4000, 0xFFFA, true);
+        profiler.record(0x0040, 2, 20);
+        let frames = profiler.stack();
+        assert!(frames[2].interrupt);
+        assert_eq!(frames[2].return_address, 0x4000);
+        // The same address in another bank is another function.
+        profiler.leave(0xFFFC);
+        profiler.leave(0xFFFE);
+        profiler.enter(0x4000, 2, 0x0156, 0xFFFC, false);
+        profiler.record(0x4000, 2, 8);
+        assert_eq!(
+            folded(&profiler),
+            [
+                "00:0150 4",
+                "00:0150;01:4000;00:0040 20",
+                "00:0150;02:4000 8"
+            ]
+        );
+    }
+
+    #[test]
+    fn unbalanced_stack() {
+        let mut profiler = Profiler::new();
+        profiler.record(0x0150, 1, 4);
+        profiler.enter(0x0200, 1, 0x0153, 0xFFFC, false);
+        profiler.enter(0x0300, 1, 0x0203, 0xFFFA, false);
+        // Popping the return addresses by hand and jumping back leaves
+        // neither frame behind.
+        profiler.leave(0xFFFE);
+        assert_eq!(profiler.stack().len(), 1);
+        profiler.leave(0xFFFE);
+        assert_eq!(profiler.stack().len(), 1);
+    }
+
+    #[test]
+    fn cpu_reports_calls() {
+        let mut rom = vec![0; 0x8000];
+        // 0100: CALL 0200; JR -2 / 0200: NOP; RET
+        rom[0x100..0x105].copy_from_slice(&[0xCD, 0x00, 0x02, 0x18, 0xFE]);
+        rom[0x200..0x202].copy_from_slice(&[0x00, 0xC9]);
+        let mut device = Device::from_rom_bytes(rom);
+        device.enable_profiler();
+        for _ in 0..5 {
+            device.do_cycle();
+        }
+        assert_eq!(
+            folded(device.profiler().unwrap()),
+            ["00:0100 48", "00:0100;00:0200 20"]
+        );
+        assert_eq!(device.profiler().unwrap().stack().len(), 1);
+    }
+}
@@ -22,6 +22,12 @@ pub enum CpuFlag {
     Z = 0b10000000,
 }
 
+impl Default for Registers {
+    fn default() -> Self {
+        Registers::new()
+    }
+}
+
 impl Registers {
     pub fn new() -> Registers {
         use CpuFlag::*;
@@ -44,32 +50,32 @@ impl Registers {
     }
 
     pub fn bc(&self) -> u16 {
-        ((self.b as u16) << 8) | ((self.c & 0xF0) as u16)
+        ((self.b as u16) << 8) | (self.c as u16)
     }
 
     pub fn de(&self) -> u16 {
-        ((self.d as u16) << 8) | ((self.e & 0xF0) as u16)
+        ((self.d as u16) << 8) | (self.e as u16)
     }
 
     pub fn hl(&self) -> u16 {
-        ((self.h as u16) << 8) | ((self.l & 0xF0) as u16)
+        ((self.h as u16) << 8) | (self.l as u16)
     }
 
     pub fn hld(&mut self) -> u16 {
         let res = self.hl();
-        self.sethl(res - 1);
+        self.sethl(res.wrapping_sub(1));
         res
     }
 
     pub fn hli(&mut self) -> u16 {
         let res = self.hl();
-        self.sethl(res + 1);
+        self.sethl(res.wrapping_add(1));
         res
     }
 
     pub fn setaf(&mut self, value: u16) {
         self.a = (value >> 8) as u8;
-        self.f = (value & 0x00FF) as u8;
+        self.f = (value & 0x00F0) as u8;
     }
 
     pub fn setbc(&mut self, value: u16) {
@@ -100,8 +106,4 @@ impl Registers {
         let mask = flags as u8;
         self.f & mask > 0
     }
-
-    pub fn setf(&mut self, flags: u8) {
-        self.f = flags & 0xF0;
-    }
 }
@@ -0,0 +1,404 @@
+use std::error::Error;
+use std::num::NonZeroU32;
+use std::rc::Rc;
+
+use glium::backend::glutin::Display;
+use glium::glutin::config::{Config, ConfigTemplateBuilder, GlConfig};
+use glium::glutin::context::ContextAttributesBuilder;
+use glium::glutin::display::{self, DisplayApiPreference, GetGlDisplay, GlDisplay};
+use glium::glutin::prelude::*;
+use glium::glutin::surface::{SwapInterval, WindowSurface};
+use glium::index::{NoIndices, PrimitiveType};
+use glium::texture::{MipmapsOption, RawImage2d, Texture2d, UncompressedFloatFormat};
+use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter};
+use glium::{implement_vertex, program, uniform, DrawParameters, Program, Rect, Surface};
+use glutin_winit::GlWindow;
+use log::{info, warn};
+use raw_window_handle::{HasDisplayHandle, HasWindowHandle, RawWindowHandle};
+use winit::dpi::PhysicalSize;
+use winit::event_loop::ActiveEventLoop;
+use winit::window::{Window, WindowAttributes};
+
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Gray level of each shade, as in screenshots.
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+/// Where the screen goes in the window: the largest whole multiple of its
+/// size that fits, centered, with black bars around it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Viewport {
+    pub left: u32,
+    pub top: u32,
+    pub scale: u32,
+}
+
+impl Viewport {
+    pub fn fit(window: PhysicalSize<u32>) -> Viewport {
+        let (width, height) = (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+        let scale = (window.width / width).min(window.height / height).max(1);
+        Viewport {
+            left: window.width.saturating_sub(width * scale) / 2,
+            top: window.height.saturating_sub(height * scale) / 2,
+            scale,
+        }
+    }
+
+    fn width(&self) -> u32 {
+        SCREEN_WIDTH as u32 * self.scale
+    }
+
+    fn height(&self) -> u32 {
+        SCREEN_HEIGHT as u32 * self.scale
+    }
+}
+
+/// Draws the screen into a window, with OpenGL when available.
+pub(crate) enum Renderer {
+    Gl(Box<GlRenderer>),
+    Software(SoftwareRenderer),
+}
+
+impl Renderer {
+    /// Opens a window and its renderer, falling back to software rendering
+    /// when OpenGL cannot be set up or when `software` is set.
+    pub fn create(
+        event_loop: &ActiveEventLoop,
+        attributes: WindowAttributes,
+        software: bool,
+    ) -> Result<(Rc<Window>, Renderer), Box<dyn Error>> {
+        let window = if software {
+            None
+        } else {
+            match GlRenderer::create(event_loop, attributes.clone()) {
+                Ok((window, renderer)) => {
+                    info!("Rendering with OpenGL");
+                    return Ok((window, Renderer::Gl(Box::new(renderer))));
+                }
+                Err((window, e)) => {
+                    warn!("OpenGL unavailable, rendering in software: {e}");
+                    window
+                }
+            }
+        };
+        let window = match window {
+            Some(window) => window,
+            None => Rc::new(event_loop.create_window(attributes)?),
+        };
+        let renderer = SoftwareRenderer::new(window.clone())?;
+        Ok((window, Renderer::Software(renderer)))
+    }
+
+    pub fn resize(&mut self, size: PhysicalSize<u32>) {
+        if let Renderer::Gl(gl) = self {
+            gl.display.resize(size.into());
+        }
+    }
+
+    /// Draws `frame`, one shade per pixel, or a black screen without one.
+    pub fn draw(
+        &mut self,
+        frame: Option<&[u8]>,
+        size: PhysicalSize<u32>,
+    ) -> Result<(), Box<dyn Error>> {
+        match self {
+            Renderer::Gl(gl) => gl.draw(frame, size),
+            Renderer::Software(software) => software.draw(frame, size),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Vertex {
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+}
+
+implement_vertex!(Vertex, position, tex_coords);
+
+/// Uploads the screen as a texture and draws it on a quad, waiting for
+/// vertical sync.
+pub(crate) struct GlRenderer {
+    display: Display<WindowSurface>,
+    texture: Texture2d,
+    quad: glium::VertexBuffer<Vertex>,
+    program: Program,
+}
+
+type GlError = (Option<Rc<Window>>, Box<dyn Error>);
+
+impl GlRenderer {
+    /// Returns the window if it was opened before failing.
+    fn create(
+        event_loop: &ActiveEventLoop,
+        attributes: WindowAttributes,
+    ) -> Result<(Rc<Window>, GlRenderer), GlError> {
+        let window = event_loop
+            .create_window(attributes)
+            .map_err(|e| (None, e.into()))?;
+        let window = Rc::new(window);
+        match GlRenderer::open(&window) {
+            Ok(renderer) => Ok((window, renderer)),
+            Err(e) => Err((Some(window), e)),
+        }
+    }
+
+    /// Sets up OpenGL on `window` with the best configuration matching it.
+    fn open(window: &Window) -> Result<GlRenderer, Box<dyn Error>> {
+        let handle = window.window_handle()?.as_raw();
+        let display = unsafe {
+            display::Display::new(window.display_handle()?.as_raw(), preference(handle))?
+        };
+        let template = ConfigTemplateBuilder::new()
+            .compatible_with_native_window(handle)
+            .build();
+        let configs = unsafe { display.find_configs(template)? };
+        let config = pick_config(configs).ok_or("the display offers no OpenGL configuration")?;
+        GlRenderer::new(window, &config)
+    }
+
+    fn new(window: &Window, config: &Config) -> Result<GlRenderer, Box<dyn Error>> {
+        let attributes = window.build_surface_attributes(Default::default())?;
+        let gl_display = config.display();
+        let surface = unsafe { gl_display.create_window_surface(config, &attributes)? };
+        let context_attributes =
+            ContextAttributesBuilder::new().build(Some(window.window_handle()?.as_raw()));
+        let context = unsafe { gl_display.create_context(config, &context_attributes)? };
+        let context = context.make_current(&surface)?;
+        let vsync = SwapInterval::Wait(NonZeroU32::MIN);
+        if let Err(e) = surface.set_swap_interval(&context, vsync) {
+            warn!("Cannot enable vsync: {e}");
+        }
+        let display = Display::from_context_surface(context, surface)?;
+
+        let texture = Texture2d::empty_with_format(
+            &display,
+            UncompressedFloatFormat::U8U8U8U8,
+            MipmapsOption::NoMipmap,
+            SCREEN_WIDTH as u32,
+            SCREEN_HEIGHT as u32,
+        )?;
+        // Texture rows go up from the bottom, the screen's go down.
+        let corners = [([-1.0, -1.0], [0.0, 1.0]), ([1.0, -1.0], [1.0, 1.0])]
+            .into_iter()
+            .chain([([-1.0, 1.0], [0.0, 0.0]), ([1.0, 1.0], [1.0, 0.0])]);
+        let vertices: Vec<Vertex> = corners
+            .map(|(position, tex_coords)| Vertex {
+                position,
+                tex_coords,
+            })
+            .collect();
+        let quad = glium::VertexBuffer::new(&display, &vertices)?;
+        let program = program!(&display,
+            140 => {
+                vertex: "
+                    #version 140
+                    in vec2 position;
+                    in vec2 tex_coords;
+                    out vec2 v_tex_coords;
+                    void main() {
+                        v_tex_coords = tex_coords;
+                        gl_Position = vec4(position, 0.0, 1.0);
+                    }
+                ",
+                fragment: "
+                    #version 140
+                    uniform sampler2D tex;
+                    in vec2 v_tex_coords;
+                    out vec4 color;
+                    void main() {
+                        color = texture(tex, v_tex_coords);
+                    }
+                ",
+            },
+            100 es => {
+                vertex: "
+                    #version 100
+                    attribute vec2 position;
+                    attribute vec2 tex_coords;
+                    varying vec2 v_tex_coords;
+                    void main() {
+                        v_tex_coords = tex_coords;
+                        gl_Position = vec4(position, 0.0, 1.0);
+                    }
+                ",
+                fragment: "
+                    #version 100
+                    precision mediump float;
+                    uniform sampler2D tex;
+                    varying vec2 v_tex_coords;
+                    void main() {
+                        gl_FragColor = texture2D(tex, v_tex_coords);
+                    }
+                ",
+            },
+        )?;
+        Ok(GlRenderer {
+            display,
+            texture,
+            quad,
+            program,
+        })
+    }
+
+    fn draw(
+        &mut self,
+        frame: Option<&[u8]>,
+        size: PhysicalSize<u32>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut target = self.display.draw();
+        target.clear_color(0.0, 0.0, 0.0, 1.0);
+        if let Some(frame) = frame {
+            let pixels: Vec<u8> = frame
+                .iter()
+                .flat_map(|&shade| {
+                    let gray = SHADES[shade as usize & 3];
+                    [gray, gray, gray, 0xFF]
+                })
+                .collect();
+            let image =
+                RawImage2d::from_raw_rgba(pixels, (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32));
+            let rect = Rect {
+                left: 0,
+                bottom: 0,
+                width: SCREEN_WIDTH as u32,
+                height: SCREEN_HEIGHT as u32,
+            };
+            self.texture.write(rect, image);
+
+            let viewport = Viewport::fit(size);
+            let parameters = DrawParameters {
+                viewport: Some(Rect {
+                    left: viewport.left,
+                    bottom: size.height.saturating_sub(viewport.top + viewport.height()),
+                    width: viewport.width(),
+                    height: viewport.height(),
+                }),
+                ..Default::default()
+            };
+            let sampler = self
+                .texture
+                .sampled()
+                .magnify_filter(MagnifySamplerFilter::Nearest)
+                .minify_filter(MinifySamplerFilter::Nearest);
+            let uniforms = uniform! { tex: sampler };
+            let indices = NoIndices(PrimitiveType::TriangleStrip);
+            target.draw(&self.quad, indices, &self.program, &uniforms, &parameters)?;
+        }
+        target.finish()?;
+        Ok(())
+    }
+}
+
+/// The system's OpenGL interface, falling back to EGL where there is one.
+/// WGL needs the window it draws to.
+#[cfg(windows)]
+fn preference(window: RawWindowHandle) -> DisplayApiPreference {
+    DisplayApiPreference::WglThenEgl(Some(window))
+}
+
+#[cfg(target_os = "macos")]
+fn preference(_window: RawWindowHandle) -> DisplayApiPreference {
+    DisplayApiPreference::Cgl
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn preference(_window: RawWindowHandle) -> DisplayApiPreference {
+    let hook = winit::platform::x11::register_xlib_error_hook;
+    DisplayApiPreference::GlxThenEgl(Box::new(hook))
+}
+
+/// Prefers hardware acceleration, then the most samples.
+fn pick_config(configs: impl Iterator<Item = Config>) -> Option<Config> {
+    configs.max_by_key(|config| (config.hardware_accelerated(), config.num_samples()))
+}
+
+/// Scales the screen on the CPU and presents it with softbuffer.
+pub(crate) struct SoftwareRenderer {
+    surface: softbuffer::Surface<Rc<Window>, Rc<Window>>,
+}
+
+impl SoftwareRenderer {
+    fn new(window: Rc<Window>) -> Result<SoftwareRenderer, Box<dyn Error>> {
+        let context = softbuffer::Context::new(window.clone())?;
+        let surface = softbuffer::Surface::new(&context, window)?;
+        Ok(SoftwareRenderer { surface })
+    }
+
+    fn draw(
+        &mut self,
+        frame: Option<&[u8]>,
+        size: PhysicalSize<u32>,
+    ) -> Result<(), Box<dyn Error>> {
+        let (Some(width), Some(height)) =
+            (NonZeroU32::new(size.width), NonZeroU32::new(size.height))
+        else {
+            return Ok(());
+        };
+        self.surface.resize(width, height)?;
+        let mut buffer = self.surface.buffer_mut()?;
+        buffer.fill(0);
+        if let Some(frame) = frame {
+            let viewport = Viewport::fit(size);
+            let stride = size.width as usize;
+            let columns = (viewport.width().min(size.width - viewport.left)) as usize;
+            let rows = (viewport.height().min(size.height - viewport.top)) as usize;
+            let scale = viewport.scale as usize;
+            for y in 0..rows {
+                let line = &frame[y / scale * SCREEN_WIDTH..][..SCREEN_WIDTH];
+                let start = (viewport.top as usize + y) * stride + viewport.left as usize;
+                for (x, pixel) in buffer[start..start + columns].iter_mut().enumerate() {
+                    let gray = SHADES[line[x / scale] as usize & 3] as u32;
+                    *pixel = gray << 16 | gray << 8 | gray;
+                }
+            }
+        }
+        buffer.present()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_scale_with_letterbox() {
+        let viewport = Viewport::fit(PhysicalSize::new(800, 480));
+        assert_eq!(
+            viewport,
+            Viewport {
+                left: 160,
+                top: 24,
+                scale: 3
+            }
+        );
+        let viewport = Viewport::fit(PhysicalSize::new(640, 1000));
+        assert_eq!(
+            viewport,
+            Viewport {
+                left: 0,
+                top: 212,
+                scale: 4
+            }
+        );
+    }
+
+    #[test]
+    fn smaller_than_the_screen() {
+        let viewport = Viewport::fit(PhysicalSize::new(100, 100));
+        assert_eq!(
+            viewport,
+            Viewport {
+                left: 0,
+                top: 0,
+                scale: 1
+            }
+        );
+    }
+
+    #[test]
+    fn no_config() {
+        assert!(pick_config(std::iter::empty()).is_none());
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::VecDeque;
+
+/// Snapshots taken every few frames, kept within a memory budget.
+///
+/// Only the newest snapshot is stored whole. Each older one is stored as
+/// the difference to the snapshot after it, which is small since most of
+/// the machine's memory stays the same from one snapshot to the next. The
+/// oldest snapshots are dropped to stay within `capacity` bytes.
+pub struct RewindBuffer {
+    interval: u32,
+    capacity: usize,
+    frames: u32,
+    newest: Option<Vec<u8>>,
+    /// Differences to the next snapshot, oldest first.
+    deltas: VecDeque<Vec<u8>>,
+    used: usize,
+}
+
+impl RewindBuffer {
+    /// Takes a snapshot every `interval` frames, using at most `capacity`
+    /// bytes.
+    pub fn new(interval: u32, capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            interval: interval.max(1),
+            capacity,
+            frames: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    /// Changes the interval and memory budget, dropping the oldest
+    /// snapshots that no longer fit.
+    pub fn set_limits(&mut self, interval: u32, capacity: usize) {
+        self.interval = interval.max(1);
+        self.capacity = capacity;
+        self.trim();
+    }
+
+    /// Frames between two snapshots.
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// Bytes used by the snapshots.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// Counts a frame, returning true when a snapshot is due.
+    pub fn frame(&mut self) -> bool {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return false;
+        }
+        self.frames = 0;
+        true
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.newest.take() {
+            self.used -= newest.len();
+            if newest.len() == state.len() {
+                let delta = encode_delta(&newest, &state);
+                self.used += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                // States of another machine: older ones cannot be rebuilt.
+                self.deltas.clear();
+                self.used = 0;
+            }
+        }
+        self.used += state.len();
+        self.newest = Some(state);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.used > self.capacity {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used -= delta.len(),
+                None => {
+                    self.clear();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Removes and returns the newest snapshot.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        self.used -= newest.len();
+        if let Some(delta) = self.deltas.pop_back() {
+            self.used -= delta.len();
+            let older = apply_delta(&newest, &delta);
+            self.used += older.len();
+            self.newest = Some(older);
+        }
+        self.frames = 0;
+        Some(newest)
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.used = 0;
+        self.frames = 0;
+    }
+}
+
+/// Encodes `old` as the XOR with `new`, as runs of unchanged bytes followed
+/// by runs of changed ones: a LEB128 count of unchanged bytes, a count of
+/// changed bytes, then the changed bytes XORed.
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut i = 0;
+    while i < old.len() {
+        let same = old[i..]
+            .iter()
+            .zip(&new[i..])
+            .take_while(|(a, b)| a == b)
+            .count();
+        i += same;
+        let changed = old[i..]
+            .iter()
+            .zip(&new[i..])
+            .take_while(|(a, b)| a != b)
+            .count();
+        write_count(&mut delta, same);
+        write_count(&mut delta, changed);
+        delta.extend(
+            old[i..i + changed]
+                .iter()
+                .zip(&new[i..])
+                .map(|(a, b)| a ^ b),
+        );
+        i += changed;
+    }
+    delta
+}
+
+fn apply_delta(new: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut old = new.to_vec();
+    let mut bytes = delta.iter().copied();
+    let mut i = 0;
+    while let Some(same) = read_count(&mut bytes) {
+        i += same;
+        let changed = read_count(&mut bytes).unwrap_or(0);
+        for (b, x) in old[i..i + changed].iter_mut().zip(bytes.by_ref()) {
+            *b ^= x;
+        }
+        i += changed;
+    }
+    old
+}
+
+fn write_count(out: &mut Vec<u8>, mut n: usize) {
+    loop {
+        let byte = (n & 0x7F) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_count(bytes: &mut impl Iterator<Item = u8>) -> Option<usize> {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes.next()?;
+        n |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 4 KiB state where only a few bytes depend on `n`.
+    fn state(n: u8) -> Vec<u8> {
+        let mut state: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        state[10] = n;
+        state[300] = n.wrapping_mul(3);
+        state[301] = n.wrapping_mul(7);
+        state[4095] = n;
+        state
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let (old, new) = (state(1), state(2));
+        let delta = encode_delta(&old, &new);
+        assert!(delta.len() < 32);
+        assert_eq!(apply_delta(&new, &delta), old);
+        assert_eq!(apply_delta(&new, &encode_delta(&new, &new)), new);
+        let noise: Vec<u8> = (0..4096).map(|i| (i * 7 % 256) as u8).collect();
+        assert_eq!(apply_delta(&new, &encode_delta(&noise, &new)), noise);
+    }
+
+    #[test]
+    fn snapshots_every_interval() {
+        let mut buffer = RewindBuffer::new(3, 1 << 20);
+        let due: Vec<bool> = (0..6).map(|_| buffer.frame()).collect();
+        assert_eq!(due, [false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn pops_newest_first() {
+        let mut buffer = RewindBuffer::new(1, 1 << 20);
+        for n in 0..5 {
+            buffer.push(state(n));
+        }
+        assert_eq!(buffer.len(), 5);
+        assert!(buffer.used() < 4096 + 4 * 32);
+        for n in (0..5).rev() {
+            assert_eq!(buffer.pop(), Some(state(n)));
+        }
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.used(), 0);
+    }
+
+    #[test]
+    fn drops_oldest_over_capacity() {
+        let mut buffer = RewindBuffer::new(1, 4096 + 40);
+        for n in 0..10 {
+            buffer.push(state(n));
+        }
+        assert!(buffer.used() <= 4096 + 40);
+        let kept: Vec<u8> = std::iter::from_fn(|| buffer.pop()).map(|s| s[10]).collect();
+        assert!(kept.len() > 1 && kept.len() < 10);
+        let expected: Vec<u8> = (0..10).rev().take(kept.len()).collect();
+        assert_eq!(kept, expected);
+    }
+
+    #[test]
+    fn too_small_for_a_snapshot() {
+        let mut buffer = RewindBuffer::new(1, 100);
+        buffer.push(state(0));
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.used(), 0);
+    }
+}
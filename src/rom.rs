@@ -1,9 +1,10 @@
+use crate::state::{StateReader, StateWriter};
 use crate::Result;
 use log::info;
 use std::fs::File;
 use std::io::Read;
 
-const ROM_BANK_SIZE: usize = 0x1000; // 4KB
+pub const ROM_BANK_SIZE: usize = 0x4000; // 16KB
 pub const ROM_START: u16 = 0x0000;
 pub const ROM_BANK_END: u16 = 0x7FFF; // 32KB
 
@@ -13,6 +14,8 @@ pub const ERAM_END: u16 = 0xBFFF; // 8KB
 
 pub struct Rom {
     bytes: Vec<u8>,
+    ram: Vec<u8>,
+    checksum: u32,
 }
 
 pub fn load(path: &str) -> Result<Rom> {
@@ -20,14 +23,70 @@ pub fn load(path: &str) -> Result<Rom> {
     let mut buffer = Vec::new();
     let mut file = File::open(path)?;
     file.read_to_end(&mut buffer)?;
+    Ok(Rom::from_bytes(buffer))
+}
 
-    Ok(Rom { bytes: buffer })
+/// CRC-32 (IEEE 802.3), as printed by most ROM tools.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
 }
 
 impl Rom {
+    pub fn from_bytes(bytes: Vec<u8>) -> Rom {
+        Rom {
+            checksum: crc32(&bytes),
+            bytes,
+            ram: vec![0; ERAM_SIZE],
+        }
+    }
+
     pub fn rb(&self, address: u16) -> u8 {
-        self.bytes[address as usize]
+        match address {
+            ERAM_START..=ERAM_END => self.ram[(address - ERAM_START) as usize],
+            _ => self.bytes.get(address as usize).copied().unwrap_or(0xFF),
+        }
     }
 
-    pub fn wb(&mut self, a: u16, v: u8) {}
+    pub fn wb(&mut self, a: u16, v: u8) {
+        if let ERAM_START..=ERAM_END = a {
+            self.ram[(a - ERAM_START) as usize] = v
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// CRC-32 of the image, identifying the game in save states.
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    /// True if the header (0x146) says the game uses Super Game Boy
+    /// functions.
+    pub fn supports_sgb(&self) -> bool {
+        self.rb(0x146) == 0x03
+    }
+
+    /// Saves cartridge RAM.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes(&mut self.ram)
+    }
+
+    /// Bank currently mapped at 0x4000-0x7FFF. No MBC is emulated yet, so
+    /// this is always the second bank of the image.
+    pub fn bank(&self) -> usize {
+        1
+    }
 }
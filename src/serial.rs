@@ -0,0 +1,146 @@
+use crate::state::{StateReader, StateWriter};
+use crate::Result;
+
+/// T-cycles per byte with the internal clock: 8 bits at 8192 Hz.
+pub const TRANSFER_CYCLES: u32 = 4096;
+/// T-cycles per byte with the CGB fast clock: 8 bits at 262144 Hz.
+pub const FAST_TRANSFER_CYCLES: u32 = 128;
+
+/// Something plugged into the link port.
+pub trait SerialDevice: Send {
+    /// Called when this Game Boy starts clocking out `byte`, a transfer
+    /// that completes after `cycles` T-cycles.
+    fn start(&mut self, _byte: u8, _cycles: u32) {}
+
+    /// Completes a transfer clocked by this Game Boy: receives the byte it
+    /// shifted out and returns the byte shifted in.
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    /// Called every `ticks` T-cycles, with the byte in SB while this Game
+    /// Boy waits on an external clock. Returns the byte shifted in when the
+    /// partner clocks a transfer meanwhile.
+    fn poll(&mut self, _ticks: u32, _byte: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+/// Link port registers SB (0xFF01) and SC (0xFF02).
+#[derive(Default)]
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    /// T-cycles left in the current internal-clock transfer.
+    remaining: u32,
+    device: Option<Box<dyn SerialDevice>>,
+    /// Bytes sent by completed transfers, when capturing.
+    output: Option<Vec<u8>>,
+    pub interrupt: u8,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial::default()
+    }
+
+    pub fn rb(&self, a: u16) -> u8 {
+        match a {
+            0xFF01 => self.sb,
+            0xFF02 => self.sc | 0x7C,
+            _ => panic!("Serial error: cannot read {:4X}", a),
+        }
+    }
+
+    pub fn wb(&mut self, a: u16, v: u8) {
+        match a {
+            0xFF01 => self.sb = v,
+            0xFF02 => {
+                self.sc = v & 0x83;
+                self.remaining = match v & 0x03 {
+                    0x01 => TRANSFER_CYCLES,
+                    0x03 => FAST_TRANSFER_CYCLES,
+                    _ => 0,
+                };
+                if v & 0x81 == 0x81 {
+                    if let Some(device) = &mut self.device {
+                        device.start(self.sb, self.remaining);
+                    }
+                }
+            }
+            _ => panic!("Serial error: cannot write {:4X}", a),
+        }
+    }
+
+    /// Plugs `device` into the link port, replacing any previous one.
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = Some(device);
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.device.take()
+    }
+
+    /// Starts recording every byte sent.
+    pub fn capture(&mut self) {
+        self.output.get_or_insert_with(Vec::new);
+    }
+
+    /// Bytes sent since capture started.
+    pub fn output(&self) -> &[u8] {
+        self.output.as_deref().unwrap_or_default()
+    }
+
+    /// Clears the registers, leaving what is plugged in.
+    pub fn reset(&mut self) {
+        self.sb = 0;
+        self.sc = 0;
+        self.remaining = 0;
+        self.interrupt = 0;
+    }
+
+    /// Saves the registers; what is plugged in is not part of the state.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u32(self.remaining);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()?;
+        self.remaining = r.u32()?;
+        Ok(())
+    }
+
+    pub fn do_cycle(&mut self, ticks: u32) {
+        let listening = (self.sc & 0x81 == 0x80).then_some(self.sb);
+        let external = self.device.as_mut().and_then(|d| d.poll(ticks, listening));
+        match self.sc & 0x81 {
+            0x80 => {
+                if let Some(received) = external {
+                    self.complete(received);
+                }
+            }
+            0x81 => {
+                self.remaining = self.remaining.saturating_sub(ticks);
+                if self.remaining == 0 {
+                    // With nothing plugged in, the line floats high.
+                    let received = match &mut self.device {
+                        Some(device) => device.exchange(self.sb),
+                        None => 0xFF,
+                    };
+                    self.complete(received);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn complete(&mut self, received: u8) {
+        if let Some(output) = &mut self.output {
+            output.push(self.sb);
+        }
+        self.sb = received;
+        self.sc &= 0x7F;
+        self.interrupt |= 0x08;
+    }
+}
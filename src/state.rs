@@ -0,0 +1,124 @@
+use crate::error::StateError;
+use crate::Result;
+
+const MAGIC: &[u8; 4] = b"RKST";
+const VERSION: u32 = 2;
+
+/// Machine state as little-endian fields written in a fixed order, so
+/// that consecutive snapshots line up byte for byte.
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        StateWriter::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter::with_header(MAGIC, VERSION)
+    }
+
+    /// Writer for another kind of file, starting with its own magic and
+    /// format version.
+    pub fn with_header(magic: &[u8; 4], version: u32) -> StateWriter {
+        let mut writer = StateWriter { bytes: Vec::new() };
+        writer.bytes(magic);
+        writer.u32(version);
+        writer
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.bytes.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.bytes.extend_from_slice(v);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads back the fields of a `StateWriter` in the same order.
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<StateReader<'a>> {
+        StateReader::with_header(bytes, MAGIC, VERSION)
+    }
+
+    /// Reader for a file written by `StateWriter::with_header`.
+    pub fn with_header(bytes: &'a [u8], magic: &[u8; 4], version: u32) -> Result<StateReader<'a>> {
+        let mut reader = StateReader { bytes };
+        let mut header = [0; 4];
+        reader.bytes(&mut header)?;
+        if &header != magic {
+            return Err(StateError::BadMagic.into());
+        }
+        match reader.u32()? {
+            v if v == version => Ok(reader),
+            version => Err(StateError::UnsupportedVersion(version).into()),
+        }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            return Err(StateError::Truncated.into());
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn vec(&mut self, n: usize) -> Result<Vec<u8>> {
+        Ok(self.take(n)?.to_vec())
+    }
+
+    /// Fills `v` entirely.
+    pub fn bytes(&mut self, v: &mut [u8]) -> Result<()> {
+        v.copy_from_slice(self.take(v.len())?);
+        Ok(())
+    }
+}
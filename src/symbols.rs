@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use log::{info, warn};
+
+use crate::rom::ROM_BANK_END;
+use crate::Result;
+
+/// Symbols from an RGBDS `.sym` file, where each line reads `BB:AAAA Label`.
+#[derive(Default)]
+pub struct Symbols {
+    by_address: BTreeMap<(usize, u16), String>,
+    by_name: HashMap<String, (usize, u16)>,
+}
+
+impl Symbols {
+    pub fn parse(text: &str) -> Symbols {
+        let mut symbols = Symbols::default();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            let Some((location, name)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let Some((bank, address)) = location.split_once(':') else {
+                continue;
+            };
+            let (Ok(bank), Ok(address)) = (
+                usize::from_str_radix(bank, 16),
+                u16::from_str_radix(address, 16),
+            ) else {
+                continue;
+            };
+            symbols.insert(bank, address, name.trim());
+        }
+        symbols
+    }
+
+    pub fn load(path: &Path) -> Result<Symbols> {
+        Ok(Symbols::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Loads `rom.sym` next to `rom.gb`, if there is one.
+    pub fn load_for_rom(rom: &str) -> Option<Symbols> {
+        let path = Path::new(rom).with_extension("sym");
+        if !path.exists() {
+            return None;
+        }
+        match Symbols::load(&path) {
+            Ok(symbols) => {
+                info!("Loaded {} symbols from {}", symbols.len(), path.display());
+                Some(symbols)
+            }
+            Err(e) => {
+                warn!("Cannot load symbols from {}: {e}", path.display());
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, bank: usize, address: u16, name: &str) {
+        // Keep the first name for an address, which RGBDS lists as the
+        // enclosing global label before its local labels.
+        self.by_address
+            .entry((bank, address))
+            .or_insert_with(|| name.to_string());
+        self.by_name.insert(name.to_string(), (bank, address));
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Bank-qualified location of a label.
+    pub fn lookup(&self, name: &str) -> Option<(usize, u16)> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Label at exactly `address`, with `rom_bank` mapped at 0x4000-0x7FFF.
+    pub fn name(&self, address: u16, rom_bank: usize) -> Option<&str> {
+        self.by_address
+            .get(&(symbol_bank(address, rom_bank), address))
+            .map(String::as_str)
+    }
+
+    /// Nearest label at or before `address` in the same bank and memory
+    /// region, as `Label` or `Label+$12`.
+    pub fn describe(&self, address: u16, rom_bank: usize) -> Option<String> {
+        let bank = symbol_bank(address, rom_bank);
+        let region = region_start(address);
+        let ((_, start), name) = self
+            .by_address
+            .range((bank, region)..=(bank, address))
+            .next_back()?;
+        match address - start {
+            0 => Some(name.clone()),
+            offset => Some(format!("{}+${:x}", name, offset)),
+        }
+    }
+}
+
+/// Bank number RGBDS uses for an address: the mapped ROM bank for ROMX and
+/// bank 1 for WRAMX, 0 everywhere else.
+pub fn symbol_bank(address: u16, rom_bank: usize) -> usize {
+    match address {
+        0x4000..=ROM_BANK_END => rom_bank,
+        0xD000..=0xDFFF => 1,
+        _ => 0,
+    }
+}
+
+fn region_start(address: u16) -> u16 {
+    match address {
+        0x0000..=0x3FFF => 0x0000,
+        0x4000..=0x7FFF => 0x4000,
+        0x8000..=0x9FFF => 0x8000,
+        0xA000..=0xBFFF => 0xA000,
+        0xC000..=0xCFFF => 0xC000,
+        0xD000..=0xDFFF => 0xD000,
+        0xE000..=0xFE9F => 0xE000,
+        0xFEA0..=0xFF7F => 0xFEA0,
+        _ => 0xFF80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "\
+; File generated by rgblink
+00:0150 Start
+00:0150 Start.loop
+00:0200 VBlank ; handler
+01:4000 Music
+02:4000 Level
+02:4010 Level.draw
+00:c000 wBuffer
+01:d000 wScore
+bogus line
+xx:0000 NotHex
+";
+
+    #[test]
+    fn parse() {
+        let symbols = Symbols::parse(SYM);
+        assert_eq!(symbols.len(), 8);
+        assert_eq!(symbols.lookup("Level.draw"), Some((2, 0x4010)));
+        assert_eq!(symbols.lookup("VBlank"), Some((0, 0x0200)));
+        assert_eq!(symbols.lookup("NotHex"), None);
+        // The global label wins over a local one at the same address.
+        assert_eq!(symbols.name(0x0150, 1), Some("Start"));
+    }
+
+    #[test]
+    fn banked_lookups() {
+        let symbols = Symbols::parse(SYM);
+        assert_eq!(symbols.name(0x4000, 1), Some("Music"));
+        assert_eq!(symbols.name(0x4000, 2), Some("Level"));
+        assert_eq!(symbols.name(0x4000, 3), None);
+        // Bank 0 and WRAM ignore the mapped ROM bank.
+        assert_eq!(symbols.name(0x0200, 5), Some("VBlank"));
+        assert_eq!(symbols.name(0xD000, 5), Some("wScore"));
+    }
+
+    #[test]
+    fn describe_offsets() {
+        let symbols = Symbols::parse(SYM);
+        assert_eq!(symbols.describe(0x0150, 1).as_deref(), Some("Start"));
+        assert_eq!(symbols.describe(0x0163, 1).as_deref(), Some("Start+$13"));
+        assert_eq!(
+            symbols.describe(0x4012, 2).as_deref(),
+            Some("Level.draw+$2")
+        );
+        assert_eq!(symbols.describe(0x4012, 1).as_deref(), Some("Music+$12"));
+        // Never reach back into another region.
+        assert_eq!(symbols.describe(0x4000, 4), None);
+        assert_eq!(symbols.describe(0xC800, 1).as_deref(), Some("wBuffer+$800"));
+        assert_eq!(symbols.describe(0xE000, 1), None);
+    }
+}
@@ -1,5 +1,8 @@
 use serde::{Deserialize, Serialize};
 
+use crate::state::{StateReader, StateWriter};
+use crate::Result;
+
 #[derive(Serialize, Deserialize)]
 pub struct Timer {
     tima: u8,
@@ -24,7 +27,7 @@ impl Timer {
         }
     }
 
-    pub fn rb(&mut self, a: u16) -> u8 {
+    pub fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF04 => self.div,
             0xFF05 => self.tima,
@@ -44,6 +47,25 @@ impl Timer {
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.u8(self.div);
+        w.u32(self.internal_div);
+        w.u32(self.internal_counter);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()?;
+        self.div = r.u8()?;
+        self.internal_div = r.u32()?;
+        self.internal_counter = r.u32()?;
+        Ok(())
+    }
+
     pub fn do_cycle(&mut self, ticks: u32) {
         self.internal_div += ticks;
         while self.internal_div >= 256 {
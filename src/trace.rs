@@ -0,0 +1,226 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::sync::Arc;
+
+use log::error;
+
+use crate::bus::Bus;
+use crate::registers::Registers;
+use crate::symbols::Symbols;
+use crate::Result;
+
+/// Writes one line per executed instruction in the Gameboy Doctor format.
+pub struct Tracer {
+    out: BufWriter<File>,
+    /// When set, lines end with a `; Label` comment naming the PC.
+    pub symbols: Option<Arc<Symbols>>,
+}
+
+impl Tracer {
+    pub fn create(path: &str) -> Result<Tracer> {
+        Ok(Tracer {
+            out: BufWriter::new(File::create(path)?),
+            symbols: None,
+        })
+    }
+
+    /// Logs the state before the instruction at `regs.pc` executes. Returns
+    /// false once the trace file can no longer be written.
+    pub fn log<B: Bus>(&mut self, regs: &Registers, mmu: &B) -> bool {
+        let mut line = format_line(regs, mmu);
+        if let Some(name) = self
+            .symbols
+            .as_ref()
+            .and_then(|s| s.describe(regs.pc, mmu.rom_bank()))
+        {
+            line = format!("{} ; {}", line, name);
+        }
+        if let Err(e) = writeln!(self.out, "{}", line) {
+            error!("Trace error: {e}, disabling trace");
+            return false;
+        }
+        true
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+pub fn format_line<B: Bus>(regs: &Registers, mmu: &B) -> String {
+    let pc = regs.pc;
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+        regs.a,
+        regs.f,
+        regs.b,
+        regs.c,
+        regs.d,
+        regs.e,
+        regs.h,
+        regs.l,
+        regs.sp,
+        pc,
+        mmu.peek(pc),
+        mmu.peek(pc.wrapping_add(1)),
+        mmu.peek(pc.wrapping_add(2)),
+        mmu.peek(pc.wrapping_add(3)),
+    )
+}
+
+/// First line where two traces disagree. `line` is 1-based; a missing side
+/// means that trace ended early.
+pub struct Divergence {
+    pub line: usize,
+    pub previous: Option<String>,
+    pub actual: Option<String>,
+    pub expected: Option<String>,
+}
+
+impl Divergence {
+    /// Names of the fields that differ, e.g. `["F", "PC"]`.
+    pub fn fields(&self) -> Vec<String> {
+        let (Some(actual), Some(expected)) = (&self.actual, &self.expected) else {
+            return Vec::new();
+        };
+        strip_comment(actual)
+            .split_whitespace()
+            .zip(strip_comment(expected).split_whitespace())
+            .filter(|(a, e)| a != e)
+            .map(|(a, _)| a.split(':').next().unwrap_or(a).to_string())
+            .collect()
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Traces diverge at line {}", self.line)?;
+        if let Some(previous) = &self.previous {
+            writeln!(f, "  after:    {}", previous)?;
+        }
+        writeln!(
+            f,
+            "  actual:   {}",
+            self.actual.as_deref().unwrap_or("<end of trace>")
+        )?;
+        writeln!(
+            f,
+            "  expected: {}",
+            self.expected.as_deref().unwrap_or("<end of trace>")
+        )?;
+        let fields = self.fields();
+        if !fields.is_empty() {
+            write!(f, "  fields:   {}", fields.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares a trace against a reference log line by line, ignoring trailing
+/// `;` comments.
+pub fn diff<A: BufRead, E: BufRead>(actual: A, expected: E) -> io::Result<Option<Divergence>> {
+    let mut actual = actual.lines();
+    let mut expected = expected.lines();
+    let mut previous = None;
+    let mut line = 0;
+
+    loop {
+        line += 1;
+        let a = actual.next().transpose()?;
+        let e = expected.next().transpose()?;
+        match (a, e) {
+            (None, None) => return Ok(None),
+            (Some(a), Some(e)) if strip_comment(&a) == strip_comment(&e) => previous = Some(a),
+            (a, e) => {
+                return Ok(Some(Divergence {
+                    line,
+                    previous,
+                    actual: a,
+                    expected: e,
+                }))
+            }
+        }
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.split(';').next().unwrap_or(line).trim_end()
+}
+
+pub fn diff_files(actual: &str, expected: &str) -> Result<Option<Divergence>> {
+    let actual = io::BufReader::new(File::open(actual)?);
+    let expected = io::BufReader::new(File::open(expected)?);
+    Ok(diff(actual, expected)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+
+    const LINE_1: &str =
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01";
+    const LINE_2: &str =
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,50,01,CE";
+    const LINE_3: &str =
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:F3,AF,E0,0F";
+
+    fn lines(lines: &[&str]) -> String {
+        lines.iter().map(|l| format!("{l}\n")).collect()
+    }
+
+    #[test]
+    fn identical_traces() {
+        let actual = lines(&[LINE_1, &format!("{LINE_2} ; Start"), LINE_3]);
+        let expected = lines(&[LINE_1, LINE_2, LINE_3]);
+        let divergence = diff(actual.as_bytes(), expected.as_bytes()).unwrap();
+        assert!(divergence.is_none());
+    }
+
+    #[test]
+    fn first_differing_line() {
+        let wrong = LINE_3.replace("F:B0", "F:80").replace("PC:0150", "PC:0151");
+        let actual = lines(&[LINE_1, LINE_2, &wrong, LINE_1]);
+        let expected = lines(&[LINE_1, LINE_2, LINE_3, LINE_3]);
+        let divergence = diff(actual.as_bytes(), expected.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.line, 3);
+        assert_eq!(divergence.previous.as_deref(), Some(LINE_2));
+        assert_eq!(divergence.fields(), ["F", "PC"]);
+    }
+
+    #[test]
+    fn trace_ending_early() {
+        let actual = lines(&[LINE_1]);
+        let expected = lines(&[LINE_1, LINE_2]);
+        let divergence = diff(actual.as_bytes(), expected.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.line, 2);
+        assert_eq!(divergence.actual, None);
+        assert_eq!(divergence.expected.as_deref(), Some(LINE_2));
+        assert!(divergence.fields().is_empty());
+    }
+
+    #[test]
+    fn ly_reads_0x90() {
+        let mut rom = vec![0; 0x8000];
+        // 0100: LDH A,(44); JR -4
+        rom[0x100..0x104].copy_from_slice(&[0xF0, 0x44, 0x18, 0xFC]);
+        let mut device = Device::from_rom_bytes(rom);
+        let path = std::env::temp_dir().join("rekop-gbc-doctor.log");
+        device.set_trace(path.to_str().unwrap(), false).unwrap();
+        device.run_until(3 * 456);
+        assert_ne!(device.cpu.bus.ppu.ly(), 0x90);
+        device.cpu.tracer = None;
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        let after_reads: Vec<&str> = trace.lines().filter(|l| l.contains("PC:0102")).collect();
+        assert!(after_reads.len() > 10);
+        assert!(after_reads.iter().all(|l| l.starts_with("A:90 ")));
+    }
+}
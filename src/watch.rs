@@ -0,0 +1,221 @@
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+    /// A write that changes the stored value.
+    Change,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub start: u16,
+    pub end: u16,
+    /// Only trigger when the value read or written equals this one.
+    pub value: Option<u8>,
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Access => "access",
+            WatchKind::Change => "change",
+        };
+        write!(f, "{} {:04X}", kind, self.start)?;
+        if self.end != self.start {
+            write!(f, "-{:04X}", self.end)?;
+        }
+        if let Some(value) = self.value {
+            write!(f, " == {:02X}", value)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    pub access: Access,
+    pub address: u16,
+    pub old: u8,
+    pub value: u8,
+    /// Address of the instruction that performed the access.
+    pub pc: u16,
+}
+
+/// Watchpoints checked by `Mmu::rb`/`Mmu::wb`. The MMU only holds one when
+/// at least one watchpoint is set.
+#[derive(Default)]
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+    hits: Vec<WatchHit>,
+    pub pc: u16,
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        Watchpoints::default()
+    }
+
+    pub fn add(&mut self, watchpoint: Watchpoint) {
+        if !self.list.contains(&watchpoint) {
+            self.list.push(watchpoint);
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watchpoint> {
+        (index < self.list.len()).then(|| self.list.remove(index))
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn list(&self) -> &[Watchpoint] {
+        &self.list
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.hits)
+    }
+
+    pub fn on_read(&mut self, address: u16, value: u8) {
+        self.check(Access::Read, address, value, value);
+    }
+
+    pub fn on_write(&mut self, address: u16, old: u8, value: u8) {
+        self.check(Access::Write, address, old, value);
+    }
+
+    fn check(&mut self, access: Access, address: u16, old: u8, value: u8) {
+        for watchpoint in &self.list {
+            if address < watchpoint.start || address > watchpoint.end {
+                continue;
+            }
+            let kind = match (watchpoint.kind, access) {
+                (WatchKind::Read, Access::Read) => true,
+                (WatchKind::Write, Access::Write) => true,
+                (WatchKind::Access, _) => true,
+                (WatchKind::Change, Access::Write) => old != value,
+                _ => false,
+            };
+            if kind && watchpoint.value.is_none_or(|v| v == value) {
+                self.hits.push(WatchHit {
+                    watchpoint: *watchpoint,
+                    access,
+                    address,
+                    old,
+                    value,
+                    pc: self.pc,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+
+    fn watchpoint(kind: WatchKind, start: u16, end: u16, value: Option<u8>) -> Watchpoint {
+        Watchpoint {
+            kind,
+            start,
+            end,
+            value,
+        }
+    }
+
+    fn hits(watch: &mut Watchpoints) -> Vec<(Access, u16, u8)> {
+        watch
+            .take_hits()
+            .iter()
+            .map(|h| (h.access, h.address, h.value))
+            .collect()
+    }
+
+    #[test]
+    fn kinds_and_ranges() {
+        let mut watch = Watchpoints::new();
+        watch.add(watchpoint(WatchKind::Read, 0xC000, 0xC00F, None));
+        watch.add(watchpoint(WatchKind::Write, 0xFF40, 0xFF40, None));
+        watch.on_read(0xC00F, 1);
+        watch.on_read(0xC010, 2);
+        watch.on_write(0xC000, 0, 3);
+        watch.on_write(0xFF40, 0x91, 0x91);
+        watch.on_read(0xFF40, 0x91);
+        assert_eq!(
+            hits(&mut watch),
+            [(Access::Read, 0xC00F, 1), (Access::Write, 0xFF40, 0x91)]
+        );
+        assert!(watch.take_hits().is_empty());
+    }
+
+    #[test]
+    fn change_and_value() {
+        let mut watch = Watchpoints::new();
+        watch.add(watchpoint(WatchKind::Change, 0xC000, 0xC000, None));
+        watch.add(watchpoint(WatchKind::Access, 0xD000, 0xD000, Some(0x42)));
+        watch.on_write(0xC000, 5, 5);
+        watch.on_write(0xC000, 5, 6);
+        watch.on_read(0xD000, 0x41);
+        watch.on_write(0xD000, 0x41, 0x42);
+        assert_eq!(
+            hits(&mut watch),
+            [(Access::Write, 0xC000, 6), (Access::Write, 0xD000, 0x42)]
+        );
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let mut watch = Watchpoints::new();
+        let read = watchpoint(WatchKind::Read, 0xC000, 0xC000, None);
+        watch.add(read);
+        watch.add(read);
+        assert_eq!(watch.list().len(), 1);
+        assert_eq!(watch.remove(1), None);
+        assert_eq!(watch.remove(0), Some(read));
+        assert!(watch.is_empty());
+    }
+
+    #[test]
+    fn mmu_reports_accesses() {
+        let mut device = Device::from_rom_bytes(vec![0; 0x8000]);
+        device
+            .watchpoints()
+            .add(watchpoint(WatchKind::Access, 0xC000, 0xC001, None));
+        device.cpu.bus.wb(0xC000, 0x12);
+        device.cpu.bus.wb(0xC002, 0x34);
+        assert_eq!(device.cpu.bus.rb(0xC000), 0x12);
+        assert_eq!(device.peek(0xC001), 0);
+        let watch = device.cpu.bus.watch.as_mut().unwrap();
+        let hits = watch.take_hits();
+        let hits: Vec<_> = hits
+            .iter()
+            .map(|h| (h.access, h.address, h.old, h.value))
+            .collect();
+        assert_eq!(
+            hits,
+            [
+                (Access::Write, 0xC000, 0, 0x12),
+                (Access::Read, 0xC000, 0x12, 0x12)
+            ]
+        );
+    }
+}
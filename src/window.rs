@@ -1,53 +1,216 @@
-use std::sync::mpsc::{Receiver, Sender};
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
 
+use log::error;
 use winit::application::ApplicationHandler;
-use winit::event::WindowEvent;
+use winit::event::{ElementState, WindowEvent};
 use winit::event_loop::ActiveEventLoop;
 use winit::keyboard::KeyCode;
 use winit::keyboard::PhysicalKey;
 use winit::window::{Window, WindowId};
 
+use crate::config::{Binding, Config, ConfigWatcher, Hotkey, Rewind, Speed};
+use crate::gamepad::Gamepads;
+use crate::input::{Holders, Macro};
+use crate::joypad::KeypadKey;
+use crate::render::Renderer;
+
 pub enum GBEvent {
-    ArrowDown,
-    ArrowUp,
+    KeyDown(KeypadKey),
+    KeyUp(KeypadKey),
+    HotkeyDown(Hotkey),
+    HotkeyUp(Hotkey),
+    TurboDown(KeypadKey, u32),
+    TurboUp(KeypadKey),
+    PlayMacro(Macro),
+    /// Settings sent at start and when the configuration changes.
+    Speed(Speed),
+    Rewind(Rewind),
+}
+
+/// What the emulator shows after each frame.
+pub struct Frame {
+    /// The screen, one shade from 0 (white) to 3 (black) per pixel.
+    pub data: Vec<u8>,
+    pub status: Status,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Status {
+    /// Measured speed relative to the Game Boy.
+    Running(f64),
+    Paused,
+    Rewinding,
+}
+
+const TITLE: &str = "RekopGBC";
+
+fn title(status: Status) -> String {
+    match status {
+        Status::Running(speed) => format!("{TITLE} - {:.0}%", speed * 100.0),
+        Status::Paused => format!("{TITLE} - Paused"),
+        Status::Rewinding => format!("{TITLE} - Rewinding"),
+    }
 }
 
 pub struct App {
-    window: Option<Window>,
+    window: Option<Rc<Window>>,
+    renderer: Option<Renderer>,
+    software: bool,
     sender: Sender<GBEvent>,
-    pub receiver: Receiver<Vec<u8>>,
+    pub receiver: Receiver<Frame>,
     data: Option<Vec<u8>>,
+    title: String,
+    bindings: HashMap<KeyCode, Binding>,
+    /// Keys held down, with the binding they were pressed with.
+    held: HashMap<KeyCode, Binding>,
+    /// Keys and controllers holding each Game Boy button.
+    buttons: Holders,
+    macros: Vec<Macro>,
+    config: ConfigWatcher,
+    gamepads: Option<Gamepads>,
 }
 
 impl App {
-    pub fn new(sender: Sender<GBEvent>, receiver: Receiver<Vec<u8>>) -> App {
+    /// `config` was loaded from `config_path`, which is watched for changes.
+    pub fn new(
+        sender: Sender<GBEvent>,
+        receiver: Receiver<Frame>,
+        config: &Config,
+        config_path: &Path,
+    ) -> App {
+        // The device thread is gone if this fails, which the event loop
+        // finds out on its own.
+        _ = sender.send(GBEvent::Speed(config.speed));
+        _ = sender.send(GBEvent::Rewind(config.rewind));
         App {
             window: None,
+            renderer: None,
+            software: config.video.software,
             sender,
             receiver,
             data: None,
+            title: TITLE.to_string(),
+            bindings: config.bindings(),
+            held: HashMap::new(),
+            buttons: Holders::default(),
+            macros: macros(config),
+            config: ConfigWatcher::new(config_path),
+            gamepads: Gamepads::new(&config.gamepad),
         }
     }
+
+    fn press(&mut self, binding: Binding) -> Option<GBEvent> {
+        match binding {
+            Binding::Button(key) => self.buttons.press(key).then_some(GBEvent::KeyDown(key)),
+            Binding::Hotkey(hotkey) => Some(GBEvent::HotkeyDown(hotkey)),
+            Binding::Turbo(key, rate) => Some(GBEvent::TurboDown(key, rate)),
+            Binding::Macro(i) => Some(GBEvent::PlayMacro(self.macros[i].clone())),
+        }
+    }
+
+    /// Event ending what pressing `binding` started, if anything. A button
+    /// is only released once nothing else holds it.
+    fn release(&mut self, binding: Binding) -> Option<GBEvent> {
+        match binding {
+            Binding::Button(key) => self.buttons.release(key).then_some(GBEvent::KeyUp(key)),
+            Binding::Hotkey(hotkey) => Some(GBEvent::HotkeyUp(hotkey)),
+            Binding::Turbo(key, _) => Some(GBEvent::TurboUp(key)),
+            Binding::Macro(_) => None,
+        }
+    }
+
+    /// Events for the button changes of the controllers, which hold each
+    /// button as one more input besides the keys.
+    fn pad_events(&mut self, changes: Vec<(KeypadKey, bool)>) -> Vec<GBEvent> {
+        changes
+            .into_iter()
+            .filter_map(|(key, pressed)| {
+                let binding = Binding::Button(key);
+                if pressed {
+                    self.press(binding)
+                } else {
+                    self.release(binding)
+                }
+            })
+            .collect()
+    }
+}
+
+fn macros(config: &Config) -> Vec<Macro> {
+    config.macros.iter().map(|m| m.steps.clone()).collect()
 }
 
 impl ApplicationHandler for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        self.window = Some(
-            event_loop
-                .create_window(Window::default_attributes().with_title("RekopGBC"))
-                .unwrap(),
-        )
+        if self.window.is_some() {
+            return;
+        }
+        let attributes = Window::default_attributes().with_title(&self.title);
+        match Renderer::create(event_loop, attributes, self.software) {
+            Ok((window, renderer)) => {
+                self.window = Some(window);
+                self.renderer = Some(renderer);
+            }
+            Err(e) => {
+                error!("Cannot open a window: {e}");
+                event_loop.exit();
+            }
+        }
     }
 
     fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
-        let _ = event_loop;
+        let mut events = Vec::new();
+        if let Some(config) = self.config.poll() {
+            // Held keys may be bound differently now, so release them while
+            // their old bindings are known.
+            let held: Vec<Binding> = self.held.drain().map(|(_, binding)| binding).collect();
+            events.extend(held.into_iter().filter_map(|binding| self.release(binding)));
+            self.bindings = config.bindings();
+            self.macros = macros(&config);
+            events.push(GBEvent::Speed(config.speed));
+            events.push(GBEvent::Rewind(config.rewind));
+            if let Some(gamepads) = &mut self.gamepads {
+                let changes = gamepads.set_config(&config.gamepad);
+                events.extend(self.pad_events(changes));
+            }
+        }
+        if let Some(gamepads) = &mut self.gamepads {
+            let changes = gamepads.poll();
+            events.extend(self.pad_events(changes));
+        }
+        for event in events {
+            if self.sender.send(event).is_err() {
+                event_loop.exit();
+                return;
+            }
+        }
 
-        while let Ok(data) = self.receiver.try_recv() {
-            self.data = Some(data);
-            // TODO: transform Vec<u8> into pixels
+        let mut redraw = false;
+        loop {
+            match self.receiver.try_recv() {
+                Ok(frame) => {
+                    self.data = Some(frame.data);
+                    redraw = true;
+                    let title = title(frame.status);
+                    if title != self.title {
+                        if let Some(window) = &self.window {
+                            window.set_title(&title);
+                        }
+                        self.title = title;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    event_loop.exit();
+                    return;
+                }
+            }
         }
 
-        if self.data.is_some() {
+        if redraw {
             if let Some(window) = &self.window {
                 window.request_redraw();
             }
@@ -66,12 +229,19 @@ impl ApplicationHandler for App {
                 event_loop.exit();
             }
             WindowEvent::RedrawRequested => {
-                if let Some(pixels) = &self.data {
-                    // TBD pixel rendering
+                if let (Some(window), Some(renderer)) = (&self.window, &mut self.renderer) {
+                    if let Err(e) = renderer.draw(self.data.as_deref(), window.inner_size()) {
+                        error!("Cannot draw the screen: {e}");
+                    }
                 }
             }
             WindowEvent::Resized(size) => {
-                println!("Resized: {}, {}", size.width, size.height)
+                if let Some(renderer) = &mut self.renderer {
+                    renderer.resize(size);
+                }
+                if let Some(window) = &self.window {
+                    window.request_redraw();
+                }
             }
             WindowEvent::KeyboardInput {
                 device_id,
@@ -81,22 +251,36 @@ impl ApplicationHandler for App {
                 _ = is_synthetic;
                 _ = device_id;
 
-                match event.physical_key {
-                    PhysicalKey::Code(KeyCode::ArrowUp) => {
-                        if self.sender.send(GBEvent::ArrowUp).is_err() {
-                            eprintln!("Send error: backend disconnected, exiting..");
-                            event_loop.exit();
+                let PhysicalKey::Code(code) = event.physical_key else {
+                    return;
+                };
+                if event.repeat {
+                    return;
+                }
+                let event = match event.state {
+                    ElementState::Pressed => {
+                        let Some(&binding) = self.bindings.get(&code) else {
+                            return;
+                        };
+                        if self.held.insert(code, binding).is_some() {
+                            return;
                         }
+                        self.press(binding)
                     }
-                    PhysicalKey::Code(KeyCode::ArrowDown) => {
-                        if self.sender.send(GBEvent::ArrowDown).is_err() {
-                            eprintln!("Send error: backend disconnected, exiting..");
-                            event_loop.exit();
-                        }
+                    ElementState::Released => {
+                        let Some(binding) = self.held.remove(&code) else {
+                            return;
+                        };
+                        self.release(binding)
                     }
-                    _ => {}
+                };
+                let Some(event) = event else {
+                    return;
+                };
+                if self.sender.send(event).is_err() {
+                    eprintln!("Send error: backend disconnected, exiting..");
+                    event_loop.exit();
                 }
-                println!("Keyboard key pressed: {:?}", event.physical_key)
             }
             _ => (),
         }
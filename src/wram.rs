@@ -1,6 +1,9 @@
+use crate::error::StateError;
+use crate::state::{StateReader, StateWriter};
+use crate::Result;
+
 const WRAM_BANK_SIZE: usize = 0x1000; // 4KB
 const WRAM_BANK_COUNT: usize = 8;
-const WRAM_SIZE: usize = WRAM_BANK_SIZE * WRAM_BANK_COUNT; // 32KB
 
 pub const WRAM_START: u16 = 0xC000;
 pub const WRAM_END: u16 = 0xDFFF; // 8KB
@@ -25,21 +28,41 @@ impl Wram {
         self.wram_bank
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for bank in &self.wram {
+            w.bytes(bank);
+        }
+        w.u8(self.wram_bank as u8);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        for bank in &mut self.wram {
+            r.bytes(bank)?;
+        }
+        self.wram_bank = r.u8()? as usize;
+        if self.wram_bank >= WRAM_BANK_COUNT {
+            return Err(StateError::Corrupt.into());
+        }
+        Ok(())
+    }
+
     pub fn rb(&self, address: u16) -> u8 {
         match address {
-            0xC00..=0xCFFF => {
-                let offset = (address - 0xC00) as usize;
-                self.wram[0][offset]
-            }
-            0xD00..=0xDFFF => {
-                let offset = (address - 0xD00) as usize;
-                self.wram[self.wram_bank][offset]
+            0xC000..=0xCFFF | 0xE000..=0xEFFF => self.wram[0][(address & 0x0FFF) as usize],
+            0xD000..=0xDFFF | 0xF000..=0xFDFF => {
+                self.wram[self.wram_bank][(address & 0x0FFF) as usize]
             }
             _ => panic!("Invalid WRAM address"),
         }
     }
 
     pub fn wb(&mut self, a: u16, v: u8) {
-        self.wram[self.wram_bank][a as usize] = v
+        match a {
+            0xC000..=0xCFFF | 0xE000..=0xEFFF => self.wram[0][(a & 0x0FFF) as usize] = v,
+            0xD000..=0xDFFF | 0xF000..=0xFDFF => {
+                self.wram[self.wram_bank][(a & 0x0FFF) as usize] = v
+            }
+            _ => panic!("Invalid WRAM address"),
+        }
     }
 }
@@ -0,0 +1,99 @@
+// Each test crate uses only some of these helpers.
+#![allow(dead_code)]
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rekop_gbc::device::Device;
+
+pub enum Outcome {
+    Pass,
+    Fail(String),
+}
+
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown".to_string()
+    }
+}
+
+/// Collapses whitespace and keeps the message short enough for a table.
+pub fn summary(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    text.chars().take(80).collect()
+}
+
+/// Prints the pass/fail table of a suite and writes it to
+/// `target/tmp/conformance/<suite>.md`, then fails if any test listed in
+/// the baseline `tests/conformance/<suite>.pass` did not pass.
+pub fn report(suite: &str, results: &[(String, Outcome)]) {
+    let passed = results
+        .iter()
+        .filter(|(_, o)| matches!(o, Outcome::Pass))
+        .count();
+    let mut table = format!("# {suite}: {passed}/{} passed\n\n", results.len());
+    let _ = writeln!(table, "| Test | Result |\n| --- | --- |");
+    for (name, outcome) in results {
+        let result = match outcome {
+            Outcome::Pass => "pass".to_string(),
+            Outcome::Fail(reason) => format!("FAIL: {}", reason.replace('|', "\\|")),
+        };
+        let _ = writeln!(table, "| {name} | {result} |");
+    }
+    print!("{table}");
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join("conformance");
+    fs::create_dir_all(&out).expect("create report directory");
+    fs::write(out.join(format!("{suite}.md")), &table).expect("write report");
+
+    let expected = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/conformance")
+        .join(format!("{suite}.pass"));
+    let expected =
+        fs::read_to_string(&expected).unwrap_or_else(|e| panic!("{}: {e}", expected.display()));
+    let regressions: Vec<&str> = expected
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter(|l| {
+            !results
+                .iter()
+                .any(|(name, o)| name == l && matches!(o, Outcome::Pass))
+        })
+        .collect();
+    assert!(
+        regressions.is_empty(),
+        "{suite} regressions: {regressions:?}"
+    );
+}
+
+/// ROM that keeps adding the button lines read from JOYP to the bytes of
+/// 0xC000-0xC0FF in turn, so that the state depends on the exact timing of
+/// every press. `seed` in the header tells ROMs apart.
+pub fn write_rom(name: &str, seed: u8) -> PathBuf {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x134] = seed;
+    rom[0x150..0x15F].copy_from_slice(&[
+        0x21, 0x00, 0xC0, // ld hl, $C000
+        0x3E, 0x10, // ld a, $10
+        0xE0, 0x00, // ldh [$00], a
+        0xF0, 0x00, // ldh a, [$00]
+        0x86, // add [hl]
+        0x22, // ld [hl+], a
+        0x26, 0xC0, // ld h, $C0
+        0x18, 0xF4, // jr -12
+    ]);
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::write(&path, rom).unwrap();
+    path
+}
+
+/// Device running `write_rom(name, seed)`.
+pub fn device(name: &str, seed: u8) -> Device {
+    Device::new(&write_rom(name, seed).to_string_lossy(), None).unwrap()
+}
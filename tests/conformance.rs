@@ -0,0 +1,224 @@
+//! Conformance suites over third-party test ROMs, which are not distributed
+//! with the crate. Point `REKOP_TEST_ROMS` at a directory laid out as:
+//!
+//! - `blargg/**/*.gb`: passes on "Passed" over serial, on screen or in the
+//!   $A000 result block
+//! - `mooneye/**/*.gb`: passes on the Fibonacci registers at `ld b, b`
+//! - `acid2/*.gb`: passes when the screen matches the `.png` next to the ROM
+//!
+//! Every suite must find ROMs once `REKOP_TEST_ROMS` is set. See
+//! `common::report` for the pass/fail tables and the baselines of tests
+//! that must keep passing.
+
+mod common;
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use rekop_gbc::device::{Device, CYCLES_PER_FRAME};
+
+use common::{panic_message, report, summary, Outcome};
+
+const LD_B_B: u8 = 0x40;
+const FIBONACCI: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+const BLARGG_FRAMES: u64 = 60 * 120;
+const MOONEYE_FRAMES: u64 = 60 * 60;
+const ACID2_FRAMES: u64 = 60 * 5;
+/// Serial output kept for matching, so that a ROM stuck printing does not
+/// make each check slower.
+const SERIAL_TAIL: usize = 4096;
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("gb") || e.eq_ignore_ascii_case("gbc"))
+        {
+            roms.push(path);
+        }
+    }
+}
+
+fn run_suite(suite: &str, check: fn(&mut Device, &Path) -> Outcome) {
+    let Some(dir) = std::env::var_os("REKOP_TEST_ROMS").map(|d| PathBuf::from(d).join(suite))
+    else {
+        eprintln!("Skipping {suite}: REKOP_TEST_ROMS is not set");
+        return;
+    };
+    // Once ROMs are configured, a suite finding none must not pass quietly.
+    let mut roms = Vec::new();
+    find_roms(&dir, &mut roms);
+    assert!(!roms.is_empty(), "{suite}: no ROMs in {}", dir.display());
+    roms.sort();
+
+    let mut results = Vec::new();
+    for rom in &roms {
+        let name = rom
+            .strip_prefix(&dir)
+            .unwrap_or(rom)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let outcome = match Device::new(&rom.to_string_lossy(), None) {
+            Ok(mut device) => panic::catch_unwind(AssertUnwindSafe(|| check(&mut device, rom)))
+                .unwrap_or_else(|e| Outcome::Fail(format!("panic: {}", panic_message(&*e)))),
+            Err(e) => Outcome::Fail(e.to_string()),
+        };
+        results.push((name, outcome));
+    }
+
+    report(suite, &results);
+}
+
+/// Both background maps, with tile numbers read as ASCII like Blargg's
+/// console font.
+fn screen_text(device: &Device) -> String {
+    (0x9800..=0x9FFF)
+        .map(|a| match device.peek(a) {
+            c @ 0x20..=0x7E => c as char,
+            _ => ' ',
+        })
+        .collect()
+}
+
+/// Result block of the newer Blargg ROMs: a status byte at $A000, the
+/// signature DE B0 61 and a NUL-terminated message from $A004.
+fn blargg_memory(device: &Device) -> Option<Outcome> {
+    let signature = [
+        device.peek(0xA001),
+        device.peek(0xA002),
+        device.peek(0xA003),
+    ];
+    let status = device.peek(0xA000);
+    if signature != [0xDE, 0xB0, 0x61] || status == 0x80 {
+        return None;
+    }
+    let text: String = (0xA004..0xC000)
+        .map(|a| device.peek(a))
+        .take_while(|&c| c != 0)
+        .map(|c| c as char)
+        .collect();
+    Some(match status {
+        0 => Outcome::Pass,
+        _ => Outcome::Fail(format!("status {status}: {}", summary(&text))),
+    })
+}
+
+fn blargg(device: &mut Device, _rom: &Path) -> Outcome {
+    device.capture_serial();
+    let mut serial = String::new();
+    for frame in 1..=BLARGG_FRAMES {
+        device.run_until(frame * CYCLES_PER_FRAME);
+        let output = device.serial_output();
+        let tail = &output[output.len().saturating_sub(SERIAL_TAIL)..];
+        serial = tail.iter().map(|&c| c as char).collect();
+        if let Some(outcome) = blargg_memory(device) {
+            return outcome;
+        }
+        for text in [&serial, &screen_text(device)] {
+            if text.contains("Passed") {
+                return Outcome::Pass;
+            }
+            if text.contains("Failed") {
+                return Outcome::Fail(summary(text));
+            }
+        }
+    }
+    Outcome::Fail(format!("timeout: {}", summary(&serial)))
+}
+
+/// Runs until the PC reaches `ld b, b`, which the Mooneye and acid2 ROMs
+/// use as a software breakpoint when done.
+fn run_to_ld_b_b(device: &mut Device, frames: u64) -> bool {
+    let limit = frames * CYCLES_PER_FRAME;
+    while device.cycles() < limit {
+        if device.peek(device.registers().pc) == LD_B_B {
+            return true;
+        }
+        device.do_cycle();
+    }
+    false
+}
+
+fn mooneye(device: &mut Device, _rom: &Path) -> Outcome {
+    if !run_to_ld_b_b(device, MOONEYE_FRAMES) {
+        return Outcome::Fail("timeout".to_string());
+    }
+    let r = device.registers();
+    let registers = [r.b, r.c, r.d, r.e, r.h, r.l];
+    if registers == FIBONACCI {
+        Outcome::Pass
+    } else {
+        Outcome::Fail(format!(
+            "B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X}",
+            r.b, r.c, r.d, r.e, r.h, r.l
+        ))
+    }
+}
+
+/// Reference image as shades 0 (white) to 3 (black).
+fn reference_shades(path: &Path) -> Result<(u32, u32, Vec<u8>), String> {
+    let file = File::open(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+    let mut buf = vec![0; reader.output_buffer_size().unwrap_or(0)];
+    let info = reader.next_frame(&mut buf).map_err(|e| e.to_string())?;
+    let samples = info.color_type.samples();
+    let shades = buf[..info.line_size * info.height as usize]
+        .chunks(info.line_size)
+        .flat_map(|line| line.chunks(samples).take(info.width as usize))
+        .map(|pixel| {
+            let color = &pixel[..samples.min(3)];
+            let luma = color.iter().map(|&c| c as u32).sum::<u32>() / color.len() as u32;
+            ((255 - luma + 42) / 85) as u8
+        })
+        .collect();
+    Ok((info.width, info.height, shades))
+}
+
+fn acid2(device: &mut Device, rom: &Path) -> Outcome {
+    let (width, height, reference) = match reference_shades(&rom.with_extension("png")) {
+        Ok(image) => image,
+        Err(e) => return Outcome::Fail(format!("no reference image: {e}")),
+    };
+    if (width, height) != (160, 144) {
+        return Outcome::Fail(format!("reference image is {width}x{height}"));
+    }
+    if !run_to_ld_b_b(device, ACID2_FRAMES) {
+        return Outcome::Fail("timeout".to_string());
+    }
+    let frame = device.frame();
+    let wrong = frame.iter().zip(&reference).filter(|(a, b)| a != b).count();
+    match frame.iter().zip(&reference).position(|(a, b)| a != b) {
+        None => Outcome::Pass,
+        Some(first) => Outcome::Fail(format!(
+            "{wrong} pixels differ, first at ({}, {})",
+            first % 160,
+            first / 160
+        )),
+    }
+}
+
+#[test]
+fn blargg_suite() {
+    run_suite("blargg", blargg);
+}
+
+#[test]
+fn mooneye_suite() {
+    run_suite("mooneye", mooneye);
+}
+
+#[test]
+fn acid2_suite() {
+    run_suite("acid2", acid2);
+}
@@ -0,0 +1,122 @@
+//! Movies replay the recorded inputs at the same cycles, reaching the same
+//! states as the recording.
+
+mod common;
+
+use rekop_gbc::device::{Device, CYCLES_PER_FRAME};
+use rekop_gbc::joypad::KeypadKey;
+use rekop_gbc::movie::Movie;
+
+use common::device;
+
+fn run_frames(device: &mut Device, frames: u64) {
+    device.run_until(device.cycles() + frames * CYCLES_PER_FRAME);
+}
+
+/// Presses and releases buttons over 150 frames, returning the recorded
+/// movie and the hash of the final state.
+fn record(device: &mut Device, from_state: bool) -> (Movie, u64) {
+    device.record_movie(from_state);
+    for (i, key) in [KeypadKey::A, KeypadKey::Start, KeypadKey::B]
+        .into_iter()
+        .enumerate()
+    {
+        run_frames(device, 20 + i as u64);
+        device.press_button(key);
+        device.run_until(device.cycles() + 1000 * (i as u64 + 1));
+        device.release_button(key);
+        run_frames(device, 27);
+    }
+    run_frames(device, 10);
+    let movie = device.stop_movie().unwrap();
+    (movie, device.state_hash())
+}
+
+/// Plays `movie` on a device that was used differently before, returning
+/// the hash of the final state.
+fn replay(device: &mut Device, movie: Movie) -> u64 {
+    device.press_button(KeypadKey::Select);
+    run_frames(device, 7);
+    device.play_movie(movie).unwrap();
+    let end = device.playback().unwrap().movie().end;
+    device.run_until(end);
+    device.state_hash()
+}
+
+#[test]
+fn replays_from_power_on() {
+    let mut recorder = device("movie.gb", 0);
+    run_frames(&mut recorder, 10);
+    let (movie, hash) = record(&mut recorder, false);
+    assert_eq!(movie.start_state, None);
+    assert_eq!(movie.inputs.len(), 6);
+    assert_eq!(movie.checkpoints.len(), 2);
+
+    let mut player = device("movie.gb", 0);
+    assert_eq!(replay(&mut player, movie), hash);
+    let playback = player.playback().unwrap();
+    assert_eq!(playback.desync(), None);
+    assert_eq!(playback.verified(), 2);
+}
+
+#[test]
+fn replays_from_save_state() {
+    let mut recorder = device("movie-state.gb", 1);
+    recorder.press_button(KeypadKey::Right);
+    recorder.press_button(KeypadKey::A);
+    run_frames(&mut recorder, 30);
+    let (movie, hash) = record(&mut recorder, true);
+    assert!(movie.start_state.is_some());
+
+    let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+    let mut player = device("movie-state.gb", 1);
+    assert_eq!(replay(&mut player, movie), hash);
+    assert_eq!(player.playback().unwrap().desync(), None);
+}
+
+#[test]
+fn desync_is_detected() {
+    let mut recorder = device("movie-desync.gb", 2);
+    let (mut movie, _) = record(&mut recorder, false);
+    movie.inputs.remove(0);
+    let checkpoint = movie.checkpoints[0].0;
+
+    let mut player = device("movie-desync.gb", 2);
+    replay(&mut player, movie);
+    assert_eq!(player.playback().unwrap().desync(), Some(checkpoint));
+}
+
+#[test]
+fn bad_movies_are_rejected() {
+    let mut recorder = device("movie-bad.gb", 3);
+    let (movie, _) = record(&mut recorder, false);
+    let bytes = movie.to_bytes();
+    assert!(Movie::from_bytes(b"not a movie").is_err());
+    assert!(Movie::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+    let mut other = device("movie-other.gb", 4);
+    assert!(other.play_movie(movie).is_err());
+}
+
+#[test]
+fn loading_a_state_keeps_the_movie() {
+    let mut recorder = device("movie-load.gb", 5);
+    run_frames(&mut recorder, 5);
+    let state = recorder.save_state();
+    recorder.record_movie(false);
+    recorder.press_button(KeypadKey::A);
+    run_frames(&mut recorder, 5);
+    assert!(recorder.load_state(&state).is_err());
+    recorder.release_button(KeypadKey::A);
+    run_frames(&mut recorder, 5);
+    let hash = recorder.state_hash();
+    let movie = recorder.stop_movie().unwrap();
+    assert_eq!(movie.inputs.len(), 2);
+
+    let mut player = device("movie-load.gb", 5);
+    assert_eq!(replay(&mut player, movie), hash);
+    assert!(player.load_state(&state).is_err());
+    assert_eq!(player.playback().unwrap().desync(), None);
+    player.stop_movie();
+    player.load_state(&state).unwrap();
+}
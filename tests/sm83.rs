@@ -0,0 +1,252 @@
+//! Per-opcode CPU tests in the SingleStepTests sm83 JSON format: each test
+//! gives the registers and memory before and after one instruction, and the
+//! bus activity of each M-cycle. The vectors in `tests/sm83/` always run;
+//! point `REKOP_SM83_TESTS` at the `v1` directory of a SingleStepTests/sm83
+//! checkout to run the whole suite.
+
+mod common;
+
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use rekop_gbc::bus::Bus;
+use rekop_gbc::cpu::CPU;
+use serde::Deserialize;
+use serde_json::Value;
+
+use common::{panic_message, report, summary, Outcome};
+
+#[derive(Deserialize)]
+struct State {
+    pc: u16,
+    sp: u16,
+    a: u8,
+    b: u8,
+    c: u8,
+    d: u8,
+    e: u8,
+    f: u8,
+    h: u8,
+    l: u8,
+    ime: Option<u8>,
+    ie: Option<u8>,
+    ram: Vec<(u16, u8)>,
+}
+
+#[derive(Deserialize)]
+struct Test {
+    name: String,
+    initial: State,
+    #[serde(rename = "final")]
+    expected: State,
+    /// `[address, data, pins]` per M-cycle, where pins is `r-m` for a read,
+    /// `-wm` for a write and `---` for an idle cycle.
+    cycles: Vec<Value>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+/// Flat 64 KiB of RAM recording every access and M-cycle.
+struct FlatBus {
+    memory: Vec<u8>,
+    ie: u8,
+    accesses: Vec<Access>,
+    ticks: usize,
+}
+
+impl Bus for FlatBus {
+    fn rb(&mut self, a: u16) -> u8 {
+        let v = self.memory[a as usize];
+        self.accesses.push(Access::Read(a, v));
+        v
+    }
+
+    fn wb(&mut self, a: u16, v: u8) {
+        self.memory[a as usize] = v;
+        self.accesses.push(Access::Write(a, v));
+    }
+
+    fn tick(&mut self) {
+        self.ticks += 1;
+    }
+
+    fn peek(&self, a: u16) -> u8 {
+        self.memory[a as usize]
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.ie & self.memory[0xFF0F] & 0x1F
+    }
+
+    fn acknowledge_interrupt(&mut self, n: u32) {
+        self.memory[0xFF0F] &= !(1 << n);
+    }
+}
+
+/// Memory accesses of the `cycles` list, leaving out idle cycles.
+fn expected_accesses(cycles: &[Value]) -> Vec<Access> {
+    cycles
+        .iter()
+        .filter_map(|cycle| {
+            let cycle = cycle.as_array()?;
+            let address = cycle.first()?.as_u64()? as u16;
+            let value = cycle.get(1)?.as_u64()? as u8;
+            match cycle.get(2)?.as_str()?.as_bytes() {
+                [b'r', ..] => Some(Access::Read(address, value)),
+                [_, b'w', ..] => Some(Access::Write(address, value)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn run_test(test: &Test) -> Result<(), String> {
+    let initial = &test.initial;
+    let mut bus = FlatBus {
+        memory: vec![0; 0x10000],
+        ie: initial.ie.unwrap_or(0),
+        accesses: Vec::new(),
+        ticks: 0,
+    };
+    for &(a, v) in &initial.ram {
+        bus.memory[a as usize] = v;
+    }
+    let mut cpu = CPU::with_bus(bus);
+    let regs = &mut cpu.regs;
+    (regs.a, regs.f, regs.b, regs.c) = (initial.a, initial.f, initial.b, initial.c);
+    (regs.d, regs.e, regs.h, regs.l) = (initial.d, initial.e, initial.h, initial.l);
+    (regs.sp, regs.pc) = (initial.sp, initial.pc);
+    cpu.set_ime(initial.ime == Some(1));
+
+    let cycles = cpu.step();
+
+    let expected = &test.expected;
+    let r = &cpu.regs;
+    let mut errors = Vec::new();
+    for (name, actual, expected) in [
+        ("A", r.a, expected.a),
+        ("F", r.f, expected.f),
+        ("B", r.b, expected.b),
+        ("C", r.c, expected.c),
+        ("D", r.d, expected.d),
+        ("E", r.e, expected.e),
+        ("H", r.h, expected.h),
+        ("L", r.l, expected.l),
+    ] {
+        if actual != expected {
+            errors.push(format!("{name}:{actual:02X} (expected {expected:02X})"));
+        }
+    }
+    for (name, actual, expected) in [("SP", r.sp, expected.sp), ("PC", r.pc, expected.pc)] {
+        if actual != expected {
+            errors.push(format!("{name}:{actual:04X} (expected {expected:04X})"));
+        }
+    }
+    if let Some(ime) = expected.ime {
+        if cpu.ime() != (ime == 1) {
+            errors.push(format!("IME:{} (expected {ime})", cpu.ime() as u8));
+        }
+    }
+    for &(a, v) in &expected.ram {
+        let actual = cpu.bus.peek(a);
+        if actual != v {
+            errors.push(format!("[{a:04X}]:{actual:02X} (expected {v:02X})"));
+        }
+    }
+    let ticks = cpu.bus.ticks;
+    if cycles as usize != test.cycles.len() || ticks != test.cycles.len() {
+        errors.push(format!(
+            "{cycles} M-cycles, {ticks} ticks (expected {})",
+            test.cycles.len()
+        ));
+    }
+    let accesses = expected_accesses(&test.cycles);
+    if cpu.bus.accesses != accesses {
+        errors.push(format!(
+            "bus {:?} (expected {:?})",
+            cpu.bus.accesses, accesses
+        ));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+fn run_file(path: &Path) -> Outcome {
+    let tests: Vec<Test> = match fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()))
+    {
+        Ok(tests) => tests,
+        Err(e) => return Outcome::Fail(e),
+    };
+    let mut failed = 0;
+    let mut first = None;
+    for test in &tests {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| run_test(test)))
+            .unwrap_or_else(|e| Err(format!("panic: {}", panic_message(&*e))));
+        if let Err(e) = result {
+            failed += 1;
+            first.get_or_insert_with(|| format!("{}: {e}", test.name));
+        }
+    }
+    match first {
+        None => Outcome::Pass,
+        Some(first) => {
+            eprintln!("{}: {first}", path.display());
+            Outcome::Fail(format!(
+                "{failed}/{} failed, {}",
+                tests.len(),
+                summary(&first)
+            ))
+        }
+    }
+}
+
+fn run_dir(dir: &Path) -> Vec<(String, Outcome)> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|e| e == "json"))
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+        .iter()
+        .map(|path| {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            (name.into_owned(), run_file(path))
+        })
+        .collect()
+}
+
+#[test]
+fn sm83_vectors() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/sm83");
+    let results = run_dir(&dir);
+    report("sm83-local", &results);
+    assert!(
+        results.iter().all(|(_, o)| matches!(o, Outcome::Pass)),
+        "local sm83 vectors failed"
+    );
+}
+
+#[test]
+fn single_step_tests() {
+    let Some(dir) = std::env::var_os("REKOP_SM83_TESTS") else {
+        eprintln!("Skipping SingleStepTests: REKOP_SM83_TESTS is not set");
+        return;
+    };
+    report("sm83", &run_dir(Path::new(&dir)));
+}
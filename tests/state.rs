@@ -0,0 +1,63 @@
+//! Save states restore the machine exactly: running on from a loaded state
+//! gives the same results as the first time.
+
+mod common;
+
+use rekop_gbc::device::{Device, CYCLES_PER_FRAME};
+
+use common::device;
+
+/// Everything observable after running `frames` more frames.
+fn run(device: &mut Device, frames: u64) -> (u64, u16, Vec<u8>, Vec<u8>) {
+    device.run_until(device.cycles() + frames * CYCLES_PER_FRAME);
+    let ram = (0xC000..=0xC0FF).map(|a| device.peek(a)).collect();
+    (
+        device.cycles(),
+        device.registers().pc,
+        ram,
+        device.frame().to_vec(),
+    )
+}
+
+#[test]
+fn load_state_replays_identically() {
+    let mut device = device("state.gb", 0);
+    run(&mut device, 10);
+    let state = device.save_state();
+    let first = run(&mut device, 5);
+    device.load_state(&state).unwrap();
+    assert_eq!(device.save_state(), state);
+    assert_eq!(run(&mut device, 5), first);
+}
+
+#[test]
+fn bad_states_are_rejected() {
+    let mut device = device("state-bad.gb", 1);
+    run(&mut device, 1);
+    let state = device.save_state();
+    assert!(device.load_state(b"not a state").is_err());
+    assert!(device.load_state(&state[..state.len() - 1]).is_err());
+    assert_eq!(device.save_state(), state);
+
+    let mut other = common::device("state-other.gb", 2);
+    assert!(other.load_state(&state).is_err());
+}
+
+#[test]
+fn bad_banks_are_rejected() {
+    let mut device = device("state-banks.gb", 3);
+    run(&mut device, 1);
+    let state = device.save_state();
+    // Header, ROM checksum, cycles and CPU, then cartridge RAM and VRAM.
+    let vram_bank = 8 + 4 + 8 + 23 + 0x2000 + 0x4000;
+    // OAM, the PPU registers and counters, and the screen, then WRAM.
+    let wram_bank = vram_bank + 1 + 0xA0 + 13 + 4 + 2 + 160 * 144 + 0x8000;
+    assert_eq!((state[vram_bank], state[wram_bank]), (0, 1));
+
+    for (offset, bank) in [(vram_bank, 2), (wram_bank, 8)] {
+        let mut bad = state.clone();
+        bad[offset] = bank;
+        assert!(device.load_state(&bad).is_err());
+        assert_eq!(device.save_state(), state);
+    }
+}